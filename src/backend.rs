@@ -0,0 +1,280 @@
+use async_trait::async_trait;
+use btleplug::api::{
+    Central, CentralEvent, Characteristic, Manager, Peripheral, PeripheralProperties, ScanFilter, Service,
+    ValueNotification, WriteType,
+};
+use btleplug::platform::{Adapter, Manager as PlatformManager, Peripheral as PlatformPeripheral};
+use btleplug::Result;
+use futures::stream::{Stream, StreamExt};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::pin::Pin;
+use std::str::FromStr;
+
+use crate::error::{BluetrackerError, Result as BluetrackerResult};
+
+pub type EventStream = Pin<Box<dyn Stream<Item = AdapterEvent> + Send>>;
+pub type NotificationStream = Pin<Box<dyn Stream<Item = ValueNotification> + Send>>;
+
+/// Advertisement activity reported by an adapter while it is scanning.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdapterEvent {
+    DeviceDiscovered(String),
+    DeviceUpdated(String),
+    ManufacturerDataAdvertisement(String),
+}
+
+impl AdapterEvent {
+    pub fn address(&self) -> &str {
+        match self {
+            AdapterEvent::DeviceDiscovered(address)
+            | AdapterEvent::DeviceUpdated(address)
+            | AdapterEvent::ManufacturerDataAdvertisement(address) => address,
+        }
+    }
+}
+
+/// Source of Bluetooth adapters. The scanner and connect code only talk to
+/// this trait, so the radio can be swapped for the simulator in `simulator.rs`.
+#[async_trait]
+pub trait BluetoothBackend: Send + Sync {
+    async fn adapters(&self) -> Result<Vec<Box<dyn BluetoothAdapter>>>;
+}
+
+#[async_trait]
+pub trait BluetoothAdapter: Send + Sync {
+    async fn info(&self) -> Result<String>;
+    async fn start_scan(&self, filter: ScanFilter) -> Result<()>;
+    async fn stop_scan(&self) -> Result<()>;
+    async fn peripherals(&self) -> Result<Vec<Box<dyn BluetoothPeripheral>>>;
+    async fn events(&self) -> Result<EventStream>;
+
+    async fn peripheral(&self, address: &str) -> Result<Option<Box<dyn BluetoothPeripheral>>> {
+        Ok(self.peripherals().await?.into_iter().find(|p| p.address() == address))
+    }
+}
+
+#[async_trait]
+pub trait BluetoothPeripheral: Send + Sync {
+    fn address(&self) -> String;
+    async fn properties(&self) -> Result<Option<PeripheralProperties>>;
+    async fn connect(&self) -> Result<()>;
+    async fn disconnect(&self) -> Result<()>;
+    async fn discover_services(&self) -> Result<()>;
+    /// Services found by the last `discover_services`; empty before discovery.
+    fn services(&self) -> BTreeSet<Service>;
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>>;
+    async fn write(&self, characteristic: &Characteristic, data: &[u8], write_type: WriteType) -> Result<()>;
+    async fn subscribe(&self, characteristic: &Characteristic) -> Result<()>;
+    async fn unsubscribe(&self, characteristic: &Characteristic) -> Result<()>;
+    /// Values notified by every subscribed characteristic.
+    async fn notifications(&self) -> Result<NotificationStream>;
+}
+
+/// Picks an adapter by its position in the backend's list or by name, e.g. `1` or `hci1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdapterSelector {
+    Index(usize),
+    Name(String),
+}
+
+impl FromStr for AdapterSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("adapter must be an index or a name".to_string());
+        }
+        Ok(match s.parse() {
+            Ok(index) => AdapterSelector::Index(index),
+            Err(_) => AdapterSelector::Name(s.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for AdapterSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdapterSelector::Index(index) => write!(f, "index {}", index),
+            AdapterSelector::Name(name) => write!(f, "name {}", name),
+        }
+    }
+}
+
+/// An adapter as shown by `adapters list`.
+#[derive(Debug, Clone, Serialize)]
+pub struct AdapterInfo {
+    pub index: usize,
+    pub name: String,
+    pub info: String,
+}
+
+/// Short name of an adapter: the first word of its info string, e.g. `hci0`
+/// for `hci0 (usb:v1D6Bp0246d0537)`.
+pub fn adapter_name(info: &str) -> &str {
+    info.split_whitespace().next().unwrap_or(info)
+}
+
+/// Describes every adapter the backend reports.
+pub async fn list_adapters(backend: &dyn BluetoothBackend) -> BluetrackerResult<Vec<AdapterInfo>> {
+    let mut list = Vec::new();
+    for (index, adapter) in backend.adapters().await?.into_iter().enumerate() {
+        let info = adapter.info().await?;
+        list.push(AdapterInfo { index, name: adapter_name(&info).to_string(), info });
+    }
+    Ok(list)
+}
+
+/// Returns the selected adapter, or the first one when no selector is given.
+pub async fn select_adapter(
+    backend: &dyn BluetoothBackend,
+    selector: Option<&AdapterSelector>,
+) -> BluetrackerResult<Box<dyn BluetoothAdapter>> {
+    let adapters = backend.adapters().await?;
+    if adapters.is_empty() {
+        return Err(BluetrackerError::NoAdapter);
+    }
+
+    let Some(selector) = selector else {
+        return adapters.into_iter().next().ok_or(BluetrackerError::NoAdapter);
+    };
+    match selector {
+        AdapterSelector::Index(index) => adapters.into_iter().nth(*index),
+        AdapterSelector::Name(name) => {
+            let mut found = None;
+            for adapter in adapters {
+                let info = adapter.info().await?;
+                if info == *name || adapter_name(&info) == name {
+                    found = Some(adapter);
+                    break;
+                }
+            }
+            found
+        }
+    }
+    .ok_or_else(|| BluetrackerError::AdapterNotFound(selector.to_string()))
+}
+
+/// Backend driving the host's Bluetooth stack through btleplug.
+pub struct BtleplugBackend {
+    manager: PlatformManager,
+}
+
+impl BtleplugBackend {
+    pub async fn new() -> Result<Self> {
+        Ok(Self { manager: PlatformManager::new().await? })
+    }
+}
+
+#[async_trait]
+impl BluetoothBackend for BtleplugBackend {
+    async fn adapters(&self) -> Result<Vec<Box<dyn BluetoothAdapter>>> {
+        let adapters = self.manager.adapters().await?;
+        Ok(adapters
+            .into_iter()
+            .map(|adapter| Box::new(BtleplugAdapter { adapter }) as Box<dyn BluetoothAdapter>)
+            .collect())
+    }
+}
+
+struct BtleplugAdapter {
+    adapter: Adapter,
+}
+
+#[async_trait]
+impl BluetoothAdapter for BtleplugAdapter {
+    async fn info(&self) -> Result<String> {
+        self.adapter.adapter_info().await
+    }
+
+    async fn start_scan(&self, filter: ScanFilter) -> Result<()> {
+        self.adapter.start_scan(filter).await
+    }
+
+    async fn stop_scan(&self) -> Result<()> {
+        self.adapter.stop_scan().await
+    }
+
+    async fn peripherals(&self) -> Result<Vec<Box<dyn BluetoothPeripheral>>> {
+        let peripherals = self.adapter.peripherals().await?;
+        Ok(peripherals
+            .into_iter()
+            .map(|peripheral| Box::new(BtleplugPeripheral { peripheral }) as Box<dyn BluetoothPeripheral>)
+            .collect())
+    }
+
+    async fn events(&self) -> Result<EventStream> {
+        let adapter = self.adapter.clone();
+        let events = self.adapter.events().await?;
+        // btleplug identifies peripherals by a platform-specific id; resolve it to the address.
+        let events = events.filter_map(move |event| {
+            let adapter = adapter.clone();
+            async move {
+                let (id, wrap): (_, fn(String) -> AdapterEvent) = match event {
+                    CentralEvent::DeviceDiscovered(id) => (id, AdapterEvent::DeviceDiscovered),
+                    CentralEvent::DeviceUpdated(id) => (id, AdapterEvent::DeviceUpdated),
+                    CentralEvent::ManufacturerDataAdvertisement { id, .. } => {
+                        (id, AdapterEvent::ManufacturerDataAdvertisement)
+                    }
+                    _ => return None,
+                };
+                let peripheral = adapter.peripheral(&id).await.ok()?;
+                Some(wrap(peripheral.address().to_string()))
+            }
+        });
+        Ok(Box::pin(events))
+    }
+}
+
+struct BtleplugPeripheral {
+    peripheral: PlatformPeripheral,
+}
+
+#[async_trait]
+impl BluetoothPeripheral for BtleplugPeripheral {
+    fn address(&self) -> String {
+        self.peripheral.address().to_string()
+    }
+
+    async fn properties(&self) -> Result<Option<PeripheralProperties>> {
+        self.peripheral.properties().await
+    }
+
+    async fn connect(&self) -> Result<()> {
+        self.peripheral.connect().await
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.peripheral.disconnect().await
+    }
+
+    async fn discover_services(&self) -> Result<()> {
+        self.peripheral.discover_services().await
+    }
+
+    fn services(&self) -> BTreeSet<Service> {
+        self.peripheral.services()
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>> {
+        self.peripheral.read(characteristic).await
+    }
+
+    async fn write(&self, characteristic: &Characteristic, data: &[u8], write_type: WriteType) -> Result<()> {
+        self.peripheral.write(characteristic, data, write_type).await
+    }
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> Result<()> {
+        self.peripheral.subscribe(characteristic).await
+    }
+
+    async fn unsubscribe(&self, characteristic: &Characteristic) -> Result<()> {
+        self.peripheral.unsubscribe(characteristic).await
+    }
+
+    async fn notifications(&self) -> Result<NotificationStream> {
+        self.peripheral.notifications().await
+    }
+}
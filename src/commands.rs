@@ -0,0 +1,889 @@
+//! The commands of the `blet` CLI. Each one takes the options parsed from the
+//! command line, does its work and prints the result, as text or in the
+//! [`OutputFormat`] asked for.
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use futures::StreamExt;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::backend::{self, AdapterSelector, BluetoothBackend, BluetoothPeripheral};
+use crate::config::{self, LocationConfig};
+use crate::connect::{self, ConnectOptions};
+use crate::db::{self, BluetoothTracker};
+use crate::error::{BluetrackerError, Result};
+use crate::filter::DeviceFilter;
+use crate::output::{self, OutputFormat};
+use crate::schedule::Schedule;
+use crate::{estimate, export, gatt, gpx, location, migrations, profiles, scan, simulator, utils};
+
+/// Which Bluetooth stack the scan, connect, gatt and adapters commands use.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendKind {
+    /// The host Bluetooth stack via btleplug
+    Btleplug,
+    /// Scripted devices for testing without Bluetooth hardware
+    Sim,
+}
+
+/// The global options every command runs with.
+#[derive(Debug, Clone)]
+pub struct Context {
+    pub db_path: String,
+    pub config_path: String,
+    pub backend: BackendKind,
+    /// YAML script of the simulated devices, needed with [`BackendKind::Sim`].
+    pub sim_script: Option<String>,
+    pub format: OutputFormat,
+}
+
+impl Context {
+    pub async fn open_backend(&self) -> Result<Box<dyn BluetoothBackend>> {
+        match self.backend {
+            BackendKind::Btleplug => Ok(Box::new(backend::BtleplugBackend::new().await?)),
+            BackendKind::Sim => {
+                let script = self
+                    .sim_script
+                    .as_deref()
+                    .ok_or_else(|| BluetrackerError::Usage("--sim-script is required with --backend sim".into()))?;
+                Ok(Box::new(simulator::SimulatedBackend::from_file(script)?))
+            }
+        }
+    }
+
+    fn open_db(&self) -> Result<BluetoothTracker> {
+        BluetoothTracker::new(&self.db_path)
+    }
+
+    fn load_config(&self) -> Result<config::Config> {
+        config::load(&self.config_path)
+    }
+
+    fn is_text(&self) -> bool {
+        self.format == OutputFormat::Text
+    }
+}
+
+#[derive(Serialize)]
+struct LocationOutput<'a> {
+    address: &'a str,
+    #[serde(flatten)]
+    estimate: &'a estimate::LocationEstimate,
+}
+
+#[derive(Serialize)]
+struct SessionOutput {
+    #[serde(flatten)]
+    session: db::ScanSession,
+    strongest: Vec<db::SessionDevice>,
+}
+
+#[derive(Serialize)]
+struct BrandOutput {
+    id: u16,
+    name: Option<String>,
+}
+
+/// What `scan` was asked to do.
+#[derive(Debug, Default)]
+pub struct ScanRequest {
+    /// File to save the list of found devices to.
+    pub output: Option<String>,
+    pub use_db: bool,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// Keep scanning and record detections as they arrive until Ctrl-C.
+    pub continuous: bool,
+    pub flush_interval: Duration,
+    pub debounce: Duration,
+    pub adapter: Option<AdapterSelector>,
+    pub all_adapters: bool,
+    pub filter: DeviceFilter,
+    /// How long each scan listens (default: scan.duration from the config).
+    pub duration: Option<Duration>,
+    pub repeat: Option<u32>,
+    pub interval: Option<Duration>,
+    /// `Some(None)` takes the schedule from the config.
+    pub schedule: Option<Option<Schedule>>,
+    pub label: Option<String>,
+    /// `Some(None)` takes the gpsd address from the config, or the default one.
+    pub gpsd: Option<Option<String>>,
+    pub nmea: Option<String>,
+}
+
+/// Starts the live position sources asked for on the command line or in the
+/// config. With both gpsd and NMEA, gpsd's fix is preferred.
+fn open_location_provider(
+    config: &LocationConfig,
+    gpsd: Option<&Option<String>>,
+    nmea: Option<&str>,
+) -> Option<Arc<dyn location::LocationProvider>> {
+    let max_age = config.max_fix_age.unwrap_or(location::DEFAULT_MAX_FIX_AGE);
+    let mut providers: Vec<Box<dyn location::LocationProvider>> = Vec::new();
+
+    let gpsd = match gpsd {
+        Some(address) => {
+            Some(address.clone().or(config.gpsd.clone()).unwrap_or(location::DEFAULT_GPSD_ADDRESS.to_string()))
+        }
+        None => config.gpsd.clone(),
+    };
+    if let Some(address) = gpsd {
+        println!("Taking positions from gpsd at {}", address);
+        providers.push(Box::new(location::GpsdProvider::start(&address, max_age)));
+    }
+    if let Some(path) = nmea.or(config.nmea.as_deref()) {
+        println!("Taking positions from NMEA sentences in {}", path);
+        providers.push(Box::new(location::NmeaProvider::start(path, max_age)));
+    }
+
+    match providers.len() {
+        0 => None,
+        1 => providers.pop().map(Arc::from),
+        _ => Some(Arc::new(location::FirstFix(providers))),
+    }
+}
+
+/// Runs a one-shot, repeated, scheduled or continuous scan. Scan errors are
+/// reported rather than returned, like the summary of a successful scan.
+pub async fn scan(context: &Context, request: ScanRequest) -> Result<()> {
+    let config = context.load_config()?;
+    let repetition = match request.schedule {
+        Some(schedule) => {
+            let schedule = schedule.or(config.scan.schedule.clone()).ok_or_else(|| {
+                BluetrackerError::Usage("--schedule needs a cron expression or scan.schedule in the config".into())
+            })?;
+            Some(scan::Repetition::Schedule(schedule))
+        }
+        None if request.repeat.is_some() || request.interval.is_some() => Some(scan::Repetition::Every {
+            interval: request.interval.unwrap_or_default(),
+            count: request.repeat,
+        }),
+        None => None,
+    };
+
+    let scan_options = scan::ScanOptions {
+        outpath: request.output,
+        use_db: request.use_db,
+        db_path: Some(context.db_path.clone()),
+        latitude: request.latitude,
+        longitude: request.longitude,
+        adapter: request.adapter,
+        all_adapters: request.all_adapters,
+        filter: request.filter,
+        duration: request.duration.or(config.scan.duration),
+        label: request.label,
+        location: open_location_provider(&config.location, request.gpsd.as_ref(), request.nmea.as_deref()),
+        retention: Some(config.retention.policy()).filter(|policy| config.retention.after_scan && !policy.is_empty()),
+    };
+
+    let backend = context.open_backend().await?;
+    if request.continuous {
+        let continuous_options = scan::ContinuousOptions {
+            flush_interval: request.flush_interval,
+            debounce: request.debounce,
+        };
+        match scan::scan_continuous(backend.as_ref(), scan_options, continuous_options).await {
+            Ok(recorded) => println!("Scan stopped. Recorded {} detections.", recorded),
+            Err(e) => eprintln!("Error during scan: {}", e),
+        }
+    } else if let Some(repetition) = repetition {
+        match scan::scan_repeatedly(backend.as_ref(), scan_options, &repetition).await {
+            Ok(runs) => println!("Scans finished after {} runs.", runs),
+            Err(e) => eprintln!("Error during scan: {}", e),
+        }
+    } else {
+        match scan::scan_devices(backend.as_ref(), scan_options).await {
+            Ok(devices) => println!("Scan completed. Found {} devices.", devices.len()),
+            Err(e) => eprintln!("Error during scan: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Connects to a device, then lists its GATT table with `discover` (storing
+/// and comparing it with `save`) and reads its profiles with `info`.
+pub async fn connect(
+    context: &Context,
+    address: &str,
+    options: &ConnectOptions,
+    discover: bool,
+    save: bool,
+    info: bool,
+) -> Result<()> {
+    let mut db = context.open_db()?;
+    let backend = context.open_backend().await?;
+    let device = match connect::connect_to_device(backend.as_ref(), address, options).await {
+        Ok(device) => {
+            println!("Successfully connected to device: {}", address);
+            device
+        }
+        Err(e) => {
+            eprintln!("Error connecting to device {}: {}", address, e);
+            return Ok(());
+        }
+    };
+
+    let result = run_connect_session(context, &mut db, device.as_ref(), address, discover, save, info).await;
+    connect::disconnect(device.as_ref()).await;
+    result
+}
+
+/// Discovers services and reads profiles of a connected device for `connect`.
+async fn run_connect_session(
+    context: &Context,
+    db: &mut BluetoothTracker,
+    device: &dyn BluetoothPeripheral,
+    address: &str,
+    discover: bool,
+    save: bool,
+    info: bool,
+) -> Result<()> {
+    if discover {
+        let services = gatt::discover(device).await?;
+        if !context.is_text() {
+            output::print_records(context.format, &services)?;
+        } else {
+            print_gatt_table(&services);
+        }
+
+        if save {
+            match db.get_latest_gatt_table(address)? {
+                Some((discovered_at, previous)) => {
+                    let changes = gatt::compare(&previous, &services);
+                    if changes.is_empty() {
+                        eprintln!("GATT table unchanged since {}.", discovered_at);
+                    } else {
+                        eprintln!("GATT table changed since {}:", discovered_at);
+                        for change in changes {
+                            eprintln!("  {}", change);
+                        }
+                    }
+                }
+                None => eprintln!("First GATT table stored for {}.", address),
+            }
+            db.store_gatt_table(address, Utc::now(), &services)?;
+        }
+    }
+
+    if info {
+        let profile = profiles::read_profile(device).await?;
+        if !context.is_text() {
+            output::print_record(context.format, Some(&profile))?;
+        } else {
+            print_profile(&profile);
+        }
+        if db.store_profile_snapshot(address, Utc::now(), &profile)? {
+            eprintln!("Recorded device information snapshot for {}.", address);
+        }
+    }
+    Ok(())
+}
+
+fn print_profile(profile: &profiles::DeviceProfile) {
+    if profile.battery_level.is_none() && profile.device_information.is_none() && profile.heart_rate.is_none() {
+        println!("No Battery, Device Information or Heart Rate service found.");
+        return;
+    }
+    if let Some(level) = profile.battery_level {
+        println!("Battery: {}%", level);
+    }
+    if let Some(info) = &profile.device_information {
+        println!("Device Information:");
+        for (label, value) in [
+            ("Manufacturer", &info.manufacturer_name),
+            ("Model", &info.model_number),
+            ("Serial Number", &info.serial_number),
+            ("Hardware", &info.hardware_revision),
+            ("Firmware", &info.firmware_revision),
+            ("Software", &info.software_revision),
+        ] {
+            if let Some(value) = value {
+                println!("  {}: {}", label, value);
+            }
+        }
+    }
+    if let Some(heart_rate) = &profile.heart_rate {
+        println!("Heart Rate:");
+        if let Some(location) = heart_rate.body_sensor_location {
+            println!("  Sensor Location: {}", location);
+        }
+        match &heart_rate.measurement {
+            Some(measurement) => {
+                println!("  {} bpm, sensor contact: {}, energy expended: {} kJ",
+                    measurement.bpm,
+                    utils::format_optional(measurement.sensor_contact),
+                    utils::format_optional(measurement.energy_expended_kj)
+                );
+                if !measurement.rr_intervals.is_empty() {
+                    let intervals: Vec<String> = measurement.rr_intervals.iter().map(|rr| format!("{:.3}", rr)).collect();
+                    println!("  RR intervals: {} s", intervals.join(", "));
+                }
+            }
+            None => println!("  No measurement received"),
+        }
+    }
+}
+
+fn print_gatt_table(services: &[gatt::GattService]) {
+    if services.is_empty() {
+        println!("No GATT services found.");
+        return;
+    }
+    for service in services {
+        let kind = if service.primary { "primary" } else { "secondary" };
+        println!("Service {} [{}]", gatt::describe(&service.uuid), kind);
+        for characteristic in &service.characteristics {
+            println!(
+                "  Characteristic {} [{}]",
+                gatt::describe(&characteristic.uuid),
+                characteristic.properties.join(", ")
+            );
+            for descriptor in &characteristic.descriptors {
+                println!("    Descriptor {}", gatt::describe(&descriptor.uuid));
+            }
+        }
+    }
+}
+
+/// What `gatt` does with a characteristic once connected.
+#[derive(Debug, Clone)]
+pub enum GattOperation {
+    Read {
+        uuid: Uuid,
+        value_format: gatt::ValueFormat,
+    },
+    Write {
+        uuid: Uuid,
+        /// Hex digits, or text with `utf8`.
+        value: String,
+        utf8: bool,
+        without_response: bool,
+    },
+    /// Streams notifications as NDJSON until the duration runs out or Ctrl-C.
+    Notify {
+        uuid: Uuid,
+        duration: Option<Duration>,
+        value_format: gatt::ValueFormat,
+    },
+}
+
+/// Connects to a device, runs one GATT operation on it and disconnects.
+pub async fn gatt(context: &Context, address: &str, options: &ConnectOptions, operation: &GattOperation) -> Result<()> {
+    // Reject a malformed value before spending time on the connection.
+    let data = match operation {
+        GattOperation::Write { value, utf8, .. } => gatt::parse_value(value, *utf8)?,
+        _ => Vec::new(),
+    };
+
+    let backend = context.open_backend().await?;
+    let device = connect::connect_to_device(backend.as_ref(), address, options).await?;
+    let result = run_gatt_operation(context, address, operation, device.as_ref(), &data).await;
+    connect::disconnect(device.as_ref()).await;
+    result
+}
+
+async fn run_gatt_operation(
+    context: &Context,
+    address: &str,
+    operation: &GattOperation,
+    device: &dyn BluetoothPeripheral,
+    data: &[u8],
+) -> Result<()> {
+    match operation {
+        GattOperation::Read { uuid, value_format } => {
+            let data = gatt::read_characteristic(device, uuid).await?;
+            let value = gatt::GattValue::new(address, *uuid, &data, *value_format);
+            if !context.is_text() {
+                output::print_record(context.format, Some(&value))?;
+            } else {
+                match &value.value {
+                    Some(decoded) => println!("{}: {}", gatt::describe(uuid), decoded),
+                    None => println!("{}: {} (not a valid {:?} value)", gatt::describe(uuid), value.hex, value_format),
+                }
+            }
+        }
+
+        GattOperation::Write { uuid, without_response, .. } => {
+            gatt::write_characteristic(device, uuid, data, !*without_response).await?;
+            println!("Wrote {} byte(s) to {}", data.len(), gatt::describe(uuid));
+        }
+
+        GattOperation::Notify { uuid, duration, value_format } => {
+            let notifications = gatt::subscribe(device, uuid).await?;
+            tokio::pin!(notifications);
+            let deadline = async {
+                match duration {
+                    Some(duration) => tokio::time::sleep(*duration).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::pin!(deadline);
+            let ctrl_c = tokio::signal::ctrl_c();
+            tokio::pin!(ctrl_c);
+
+            loop {
+                tokio::select! {
+                    _ = &mut ctrl_c => break,
+                    _ = &mut deadline => break,
+                    data = notifications.next() => {
+                        let Some(data) = data else { break };
+                        let value = gatt::GattValue::new(address, *uuid, &data, *value_format);
+                        println!("{}", serde_json::to_string(&value)?);
+                    }
+                }
+            }
+            gatt::unsubscribe(device, uuid).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Lists every adapter of the backend with its index, name and info.
+pub async fn list_adapters(context: &Context) -> Result<()> {
+    let backend = context.open_backend().await?;
+    let adapters = backend::list_adapters(backend.as_ref()).await?;
+    if !context.is_text() {
+        output::print_records(context.format, &adapters)?;
+    } else if adapters.is_empty() {
+        println!("No Bluetooth adapters found.");
+    } else {
+        for adapter in adapters {
+            println!("{}: {} - {}", adapter.index, adapter.name, adapter.info);
+        }
+    }
+    Ok(())
+}
+
+/// Prints the estimated location of a device.
+pub fn location(context: &Context, address: &str, since: Option<DateTime<Utc>>, trilaterate: bool) -> Result<()> {
+    let db = context.open_db()?;
+    let options = estimate::EstimatorOptions {
+        trilaterate,
+        ..Default::default()
+    };
+    let estimate = db.estimate_device_location(address, since, &options)?;
+    if !context.is_text() {
+        let record = estimate.as_ref().map(|estimate| LocationOutput { address, estimate });
+        return output::print_record(context.format, record.as_ref());
+    }
+    match estimate {
+        Some(estimate) => println!(
+            "Estimated location: ({}, {}) ± {:.0} m from {} detections ({})",
+            estimate.latitude,
+            estimate.longitude,
+            estimate.uncertainty_m,
+            estimate.detections,
+            match estimate.method {
+                estimate::EstimateMethod::WeightedCentroid => "weighted centroid",
+                estimate::EstimateMethod::Trilateration => "trilateration",
+            }
+        ),
+        None => println!("No location data found for device."),
+    }
+    Ok(())
+}
+
+/// Lists the devices last seen within `radius` km of a position.
+pub fn nearby(
+    context: &Context,
+    latitude: f64,
+    longitude: f64,
+    radius: f64,
+    since: Option<DateTime<Utc>>,
+    limit: Option<usize>,
+) -> Result<()> {
+    let db = context.open_db()?;
+    let devices = db.find_devices_near(latitude, longitude, radius, since, limit)?;
+    if !context.is_text() {
+        output::print_records(context.format, &devices)?;
+    } else if devices.is_empty() {
+        println!("No devices found within {} km.", radius);
+    } else {
+        println!("Devices found within {} km:", radius);
+        for device in devices {
+            println!("- {} ({}): {:.3} km at ({}, {}), last seen {}",
+                device.address,
+                device.name,
+                device.distance_km,
+                device.latitude,
+                device.longitude,
+                device.last_seen
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Fills in the position of detections recorded without one from a GPX track.
+pub fn geotag(context: &Context, gpx: &str, max_gap: chrono::Duration) -> Result<()> {
+    let track = gpx::Track::load(gpx)?;
+    if track.is_empty() {
+        return Err(BluetrackerError::Parse(format!("{} has no timestamped trackpoints", gpx)));
+    }
+    let mut db = context.open_db()?;
+    let summary = db.geotag_detections(&track, max_gap)?;
+    if !context.is_text() {
+        output::print_record(context.format, Some(&summary))?;
+    } else {
+        println!("Tagged {} detections from {} trackpoints; {} fell outside the track.",
+            summary.tagged,
+            track.points().len(),
+            summary.outside_track
+        );
+    }
+    Ok(())
+}
+
+/// Writes geotagged detections as GeoJSON, KML or GPX to `output`, or to
+/// standard output. `window` is the time span behind each GPX trackpoint.
+pub fn export(context: &Context, query: &db::DetectionQuery, output: Option<&str>, window: chrono::Duration) -> Result<()> {
+    if !matches!(context.format, OutputFormat::Geojson | OutputFormat::Kml | OutputFormat::Gpx) {
+        return Err(BluetrackerError::Usage("export needs --format geojson, kml or gpx".into()));
+    }
+    let db = context.open_db()?;
+    let detections = db.get_geo_detections(query)?;
+
+    let out: Box<dyn std::io::Write> = match output {
+        Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path)?)),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    match context.format {
+        OutputFormat::Geojson => export::write_geojson(out, &detections)?,
+        OutputFormat::Kml => export::write_kml(out, &detections)?,
+        _ => export::write_gpx(out, &detections, window, &estimate::EstimatorOptions::default())?,
+    }
+
+    if let Some(path) = output {
+        let devices = detections.chunk_by(|a, b| a.address == b.address).count();
+        println!("Exported {} detections of {} devices to {}", detections.len(), devices, path);
+    }
+    Ok(())
+}
+
+/// Prints the detections of a device and the names it went by.
+pub fn history(context: &Context, address: &str, filters: db::FilterOptions) -> Result<()> {
+    let mut db = context.open_db()?;
+    let history = db.get_device_history(address, filters)?;
+    if !context.is_text() {
+        return output::print_records(context.format, &history);
+    }
+    if history.is_empty() {
+        println!("No history found for device.");
+        return Ok(());
+    }
+
+    println!("Detection history for {}:", address);
+    let names = db.get_name_history(address)?;
+    if !names.is_empty() {
+        println!("Names seen: {}", names
+            .iter()
+            .map(|n| format!("{} ({} - {})", n.name, n.first_seen, n.last_seen))
+            .collect::<Vec<_>>()
+            .join(", "));
+    }
+    for detection in history {
+        println!("- Time: {}, Location: ({:?}, {:?}), Adapter: {}, RSSI: {}, Tx Power: {}, Address Type: {}, Class: {}, Manufacturer Data: {}, Services: {}, Service Data: {}",
+            detection.timestamp,
+            detection.latitude,
+            detection.longitude,
+            utils::format_optional(detection.adapter.as_deref()),
+            utils::format_optional(detection.rssi),
+            utils::format_optional(detection.tx_power),
+            utils::format_optional(detection.address_type.as_deref()),
+            utils::format_optional(detection.class),
+            utils::format_manufacturer_data(&detection.manufacturer_data),
+            detection.services.iter().map(|uuid| uuid.to_string()).collect::<Vec<_>>().join(", "),
+            utils::format_service_data(&detection.service_data)
+        );
+    }
+    Ok(())
+}
+
+/// Lists the stored devices matching `query`.
+pub fn devices(context: &Context, query: &db::DeviceQuery) -> Result<()> {
+    let db = context.open_db()?;
+    let devices = db.get_devices(query)?;
+    if !context.is_text() {
+        output::print_records(context.format, &devices)?;
+    } else if devices.is_empty() {
+        println!("No devices found.");
+    } else {
+        println!("Stored devices for {}:",  devices.len());
+        for device in devices {
+            println!("- Address: {}, Name: {}, Manufacturer Id: {:?}, First Seen: {}, Last Seen: {}, Detections: {}, Strongest RSSI: {}",
+                device.address,
+                device.name,
+                device.manufacturer_id,
+                utils::format_optional(device.first_seen),
+                utils::format_optional(device.last_seen),
+                device.detection_count,
+                utils::format_optional(device.strongest_rssi)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// How long a session ran, or a note that it never finished.
+fn format_session_length(session: &db::ScanSession) -> String {
+    match session.ended_at {
+        Some(ended_at) => format!("{}s", (ended_at - session.started_at).num_seconds()),
+        None => "interrupted or running".to_string(),
+    }
+}
+
+/// Lists the most recent scan sessions.
+pub fn list_sessions(context: &Context, limit: usize) -> Result<()> {
+    let db = context.open_db()?;
+    let sessions = db.get_scan_sessions(Some(limit))?;
+    if !context.is_text() {
+        output::print_records(context.format, &sessions)?;
+    } else if sessions.is_empty() {
+        println!("No scan sessions found.");
+    } else {
+        for session in sessions {
+            println!("#{} {} ({}){}: {} devices ({} new), {} detections on {}",
+                session.id,
+                session.started_at.format("%Y-%m-%d %H:%M:%S"),
+                format_session_length(&session),
+                session.label.as_deref().map(|label| format!(" [{}]", label)).unwrap_or_default(),
+                session.device_count,
+                session.new_device_count,
+                session.detection_count,
+                utils::format_optional(session.adapter.as_deref())
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Shows one scan session and the `top` strongest devices it heard.
+pub fn show_session(context: &Context, id: i64, top: usize) -> Result<()> {
+    let db = context.open_db()?;
+    let Some(session) = db.get_scan_session(id)? else {
+        if !context.is_text() {
+            output::print_record::<SessionOutput>(context.format, None)?;
+        } else {
+            println!("Scan session {} not found.", id);
+        }
+        return Ok(());
+    };
+    let strongest = db.get_session_devices(id, Some(top))?;
+    if !context.is_text() {
+        return output::print_record(context.format, Some(&SessionOutput { session, strongest }));
+    }
+
+    println!("Scan session #{}", session.id);
+    println!("  Started: {}", session.started_at);
+    println!("  Ended: {}", session.ended_at.map_or("not finished".to_string(), |ended| ended.to_string()));
+    println!("  Length: {}", format_session_length(&session));
+    if let Some(duration) = session.duration_secs {
+        println!("  Configured duration: {}s", duration);
+    }
+    println!("  Adapter: {}", utils::format_optional(session.adapter.as_deref()));
+    println!("  Host: {}", utils::format_optional(session.host.as_deref()));
+    if let Some(label) = &session.label {
+        println!("  Label: {}", label);
+    }
+    if let (Some(latitude), Some(longitude)) = (session.latitude, session.longitude) {
+        println!("  Location: ({}, {})", latitude, longitude);
+    }
+    println!("  Filters: {}", session.filters.as_deref().unwrap_or("none"));
+    println!("  Devices: {} ({} new), detections: {}",
+        session.device_count, session.new_device_count, session.detection_count);
+    if !strongest.is_empty() {
+        println!("Strongest signals:");
+        for device in strongest {
+            println!("- {} {}: {} dBm, {} detections{}",
+                device.address,
+                device.name.as_deref().unwrap_or("Unknown"),
+                utils::format_optional(device.strongest_rssi),
+                device.detection_count,
+                if device.new { " (new)" } else { "" }
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Looks up the manufacturer name of a company ID.
+pub fn brand(context: &Context, id: u16) -> Result<()> {
+    let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    let path = format!("{}/.bluetracker/assets/company_identifiers.yaml", home);
+    let manufacturer_map = utils::load_manufacturer_map_from_yaml(&path)?;
+    let name = utils::get_manufacturer_name(&id, &manufacturer_map);
+    if !context.is_text() {
+        return output::print_record(context.format, Some(&BrandOutput { id, name }));
+    }
+    match name {
+        Some(name) => println!("Manufacturer Name: {}", name),
+        None => println!("Manufacturer Name not found."),
+    }
+    Ok(())
+}
+
+/// Applies the pending schema migrations, or only lists them with `dry_run`.
+pub fn migrate(context: &Context, dry_run: bool) -> Result<()> {
+    let mut conn = rusqlite::Connection::open(&context.db_path)?;
+    let version = migrations::current_version(&conn)?;
+
+    if dry_run {
+        let pending = migrations::pending(&conn)?;
+        if pending.is_empty() {
+            println!("Database is up to date (version {}).", version);
+        } else {
+            println!("Database is at version {}. Pending migrations:", version);
+            for migration in pending {
+                println!("- v{}: {}{}",
+                    migration.version,
+                    migration.description,
+                    if migration.destructive { " (destructive, backup will be made)" } else { "" }
+                );
+            }
+        }
+        return Ok(());
+    }
+
+    let report = migrations::migrate(&mut conn, &context.db_path)?;
+    if let Some(backup) = &report.backup {
+        println!("Database backed up to {}", backup);
+    }
+    if report.applied.is_empty() {
+        println!("Database is up to date (version {}).", version);
+    } else {
+        for migration in &report.applied {
+            println!("Applied v{}: {}", migration.version, migration.description);
+        }
+    }
+    Ok(())
+}
+
+/// A table `db export` can write.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportTable {
+    Devices,
+    Detections,
+    /// Hourly aggregates left by `db prune --downsample`
+    Aggregates,
+}
+
+/// Writes a whole table as CSV or NDJSON to `output`, or to standard output.
+pub fn export_table(context: &Context, table: ExportTable, output: Option<&str>) -> Result<()> {
+    let format = match context.format {
+        OutputFormat::Text | OutputFormat::Csv => OutputFormat::Csv,
+        OutputFormat::Ndjson => OutputFormat::Ndjson,
+        _ => return Err(BluetrackerError::Usage("db export needs --format csv (the default) or ndjson".into())),
+    };
+    let db = context.open_db()?;
+    let out: Box<dyn std::io::Write> = match output {
+        Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path)?)),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let count = match table {
+        ExportTable::Devices => {
+            let devices = db.get_device_records()?;
+            output::write_records(out, format, &devices)?;
+            devices.len()
+        }
+        ExportTable::Detections => {
+            let detections = db.get_detection_records()?;
+            output::write_records(out, format, &detections)?;
+            detections.len()
+        }
+        ExportTable::Aggregates => {
+            let aggregates = db.get_aggregate_records()?;
+            output::write_records(out, format, &aggregates)?;
+            aggregates.len()
+        }
+    };
+    if let Some(path) = output {
+        println!("Exported {} rows to {}", count, path);
+    }
+    Ok(())
+}
+
+/// What `db prune` removes, on top of the retention settings in the config.
+#[derive(Debug, Default)]
+pub struct PruneRequest {
+    pub older_than: Option<chrono::Duration>,
+    pub keep_per_device: Option<u32>,
+    pub downsample: bool,
+    pub dry_run: bool,
+}
+
+/// Removes old detections and hands the freed space back to the file system.
+pub fn prune(context: &Context, request: &PruneRequest) -> Result<()> {
+    let config = context.load_config()?;
+    let mut policy = config.retention.policy();
+    if let Some(older_than) = request.older_than {
+        policy.older_than = Some(
+            older_than
+                .to_std()
+                .map_err(|_| BluetrackerError::Usage("--older-than has to be positive".into()))?,
+        );
+    }
+    if request.keep_per_device.is_some() {
+        policy.keep_per_device = request.keep_per_device;
+    }
+    policy.downsample |= request.downsample;
+    if policy.is_empty() {
+        return Err(BluetrackerError::Usage(
+            "nothing to prune: give --older-than or --keep-per-device, or set them under retention in the config".into(),
+        ));
+    }
+
+    let dry_run = request.dry_run;
+    let mut db = context.open_db()?;
+    let summary = db.prune(&policy, dry_run)?;
+    let freed = if dry_run || summary.detections_removed == 0 { 0 } else { db.vacuum()? };
+    if !context.is_text() {
+        return output::print_record(context.format, Some(&summary));
+    }
+    println!("{} {} detections of {} devices{}.",
+        if dry_run { "Would remove" } else { "Removed" },
+        summary.detections_removed,
+        summary.devices_affected,
+        if policy.downsample {
+            format!(", folded into {} hourly aggregates", summary.aggregates_written)
+        } else {
+            String::new()
+        }
+    );
+    if freed > 0 {
+        println!("Freed {} KiB.", freed / 1024);
+    } else if !dry_run && summary.detections_removed > 0 && !db.is_incremental_vacuum()? {
+        println!("Run `blet db vacuum` once to hand the space back to the file system.");
+    }
+    if !policy.downsample && summary.detections_removed > 0 {
+        println!("Devices keep their records and lifetime counts, but without --downsample \
+                  their location and RSSI history is gone once all their detections are removed.");
+    }
+    Ok(())
+}
+
+/// Rewrites the database with a full VACUUM.
+pub fn vacuum(context: &Context) -> Result<()> {
+    let db = context.open_db()?;
+    let freed = db.compact()?;
+    println!("Vacuumed {}, freed {} KiB. Prunes now reclaim space incrementally.", context.db_path, freed / 1024);
+    Ok(())
+}
+
+/// Merges another bluetracker database into this one.
+pub fn merge(context: &Context, other: &str) -> Result<()> {
+    let mut db = context.open_db()?;
+    let summary = db.merge_database(other)?;
+    if !context.is_text() {
+        return output::print_record(context.format, Some(&summary));
+    }
+    println!("Merged {} into {}:", other, context.db_path);
+    println!("  Devices: {} added, {} updated", summary.devices_added, summary.devices_updated);
+    println!("  Detections: {} added, {} duplicates skipped",
+        summary.detections_added, summary.duplicate_detections);
+    println!("  Scan sessions: {} added", summary.sessions_added);
+    println!("  Snapshots: {} added", summary.snapshots_added);
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use serde::{Deserialize, Deserializer};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::db::RetentionPolicy;
+use crate::error::{BluetrackerError, Result};
+use crate::schedule::Schedule;
+use crate::utils::parse_duration;
+
+/// Settings read from the YAML config file. Command-line flags take precedence.
+///
+/// ```yaml
+/// scan:
+///   duration: 30s
+///   schedule: "*/5 9-17 * * mon-fri"
+/// location:
+///   gpsd: localhost:2947
+///   nmea: /dev/ttyACM0
+///   max_fix_age: 10s
+/// retention:
+///   older_than: 90d
+///   keep_per_device: 10000
+///   downsample: true
+///   after_scan: true
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub scan: ScanConfig,
+    pub location: LocationConfig,
+    pub retention: RetentionConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScanConfig {
+    /// How long each scan listens unless `--duration` is given.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub duration: Option<Duration>,
+    /// When `scan --schedule` runs.
+    #[serde(deserialize_with = "deserialize_schedule")]
+    pub schedule: Option<Schedule>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocationConfig {
+    /// gpsd address (host:port) scans take their position from.
+    pub gpsd: Option<String>,
+    /// Serial GPS device or recorded `.nmea` file, used when gpsd has no fix.
+    pub nmea: Option<String>,
+    /// Fixes older than this are ignored (default 10s).
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub max_fix_age: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// Detections older than this are pruned.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub older_than: Option<Duration>,
+    /// Only this many of each device's most recent detections are kept.
+    pub keep_per_device: Option<u32>,
+    /// Fold pruned detections into hourly aggregates instead of dropping them.
+    pub downsample: bool,
+    /// Prune after every stored scan.
+    pub after_scan: bool,
+}
+
+impl RetentionConfig {
+    pub fn policy(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            older_than: self.older_than,
+            keep_per_device: self.keep_per_device,
+            downsample: self.downsample,
+        }
+    }
+}
+
+pub fn get_config_path(provided_path: Option<String>) -> String {
+    provided_path.unwrap_or_else(|| {
+        let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+        format!("{}/.bluetracker/config.yaml", home)
+    })
+}
+
+/// Loads the config file, falling back to defaults when it does not exist.
+pub fn load(path: &str) -> Result<Config> {
+    if !Path::new(path).exists() {
+        return Ok(Config::default());
+    }
+    let content = std::fs::read_to_string(path)?;
+    serde_yaml::from_str(&content).map_err(|e| BluetrackerError::Parse(format!("{}: {}", path, e)))
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<Duration>, D::Error> {
+    let Some(value) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    parse_duration(&value)
+        .and_then(|duration| duration.to_std().ok())
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid duration '{}', expected e.g. 30s or 5m", value)))
+}
+
+fn deserialize_schedule<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<Schedule>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| value.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
@@ -1,64 +1,240 @@
-use btleplug::api::{Peripheral, Central, Manager};
-use btleplug::platform::Manager as PlatformManager;
-use std::error::Error;
-use std::thread;
+use btleplug::api::ScanFilter;
+use futures::StreamExt;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::time::Duration;
+use tokio::time::{sleep, sleep_until, Instant};
 
-pub async fn connect_to_device(address: &str) -> Result<(), Box<dyn Error>> {
-    let manager = PlatformManager::new().await?;
-    let adapters = manager.adapters().await?;
-    let adapter = match adapters.first() {
-        Some(adapter) => adapter.clone(),
-        None => {
-            eprintln!("No Bluetooth adapters found.");
-            return Err("No Bluetooth adapters found.".into());
+use crate::backend::{
+    select_adapter, AdapterSelector, BluetoothAdapter, BluetoothBackend, BluetoothPeripheral, EventStream,
+};
+use crate::error::{BluetrackerError, Result};
+
+/// How often the adapter's peripheral list is checked while scanning for a device,
+/// for stacks that do not report already known devices as events.
+const SCAN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    /// Adapter to connect through; the first one when unset.
+    pub adapter: Option<AdapterSelector>,
+    /// How long to scan for the device before giving up.
+    pub scan_timeout: Duration,
+    pub max_attempts: u32,
+    /// Delay before the first retry; doubled after every failed attempt.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Fraction by which each delay is randomly lengthened or shortened.
+    pub jitter: f64,
+    /// Limit on the whole operation, scanning included.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            adapter: None,
+            scan_timeout: Duration::from_secs(10),
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(8),
+            jitter: 0.2,
+            timeout: None,
+        }
+    }
+}
+
+/// Connects to the device with the given address and returns the connected
+/// peripheral. Unless the adapter already knows the device, a scan runs until it
+/// is seen; failed attempts are retried with exponential backoff. When the
+/// timeout fires first, the scan is stopped, or the device disconnected in
+/// case the stack went on to connect it.
+pub async fn connect_to_device(
+    backend: &dyn BluetoothBackend,
+    address: &str,
+    options: &ConnectOptions,
+) -> Result<Box<dyn BluetoothPeripheral>> {
+    let adapter = select_adapter(backend, options.adapter.as_ref()).await?;
+
+    // Kept outside the attempt, so they can still be cleaned up once the attempt is dropped.
+    let mut found: Option<Box<dyn BluetoothPeripheral>> = None;
+    let mut scanning = false;
+    let attempt = async {
+        let device = found.insert(find_device(adapter.as_ref(), address, options.scan_timeout, &mut scanning).await?);
+        connect_with_retries(device.as_ref(), options).await
+    };
+    let deadline = async {
+        match options.timeout {
+            Some(limit) => sleep(limit).await,
+            None => std::future::pending().await,
         }
     };
 
-    // Find the peripheral with the given address
+    tokio::select! {
+        result = attempt => {
+            result?;
+            return Ok(found.expect("a connected device was found"));
+        }
+        _ = deadline => {}
+    }
+
+    if scanning {
+        if let Err(e) = adapter.stop_scan().await {
+            eprintln!("Failed to stop scanning for {}: {}", address, e);
+        }
+    }
+    if let Some(device) = found {
+        // Fails harmlessly when the device never connected.
+        let _ = device.disconnect().await;
+    }
+    Err(BluetrackerError::TimedOut { address: address.to_string(), after: options.timeout.unwrap_or_default() })
+}
+
+/// Disconnects from a device, reporting rather than failing on errors so it can
+/// be used while cleaning up.
+pub async fn disconnect(device: &dyn BluetoothPeripheral) {
+    match device.disconnect().await {
+        Ok(_) => eprintln!("Disconnected from {}", device.address()),
+        Err(e) => eprintln!("Failed to disconnect from {}: {}", device.address(), e),
+    }
+}
+
+/// Looks the device up among the peripherals the adapter knows, scanning for it
+/// if needed. `scanning` is true while a scan this started is running, so it can
+/// be stopped if this is abandoned.
+async fn find_device(
+    adapter: &dyn BluetoothAdapter,
+    address: &str,
+    scan_timeout: Duration,
+    scanning: &mut bool,
+) -> Result<Box<dyn BluetoothPeripheral>> {
+    if let Some(device) = lookup(adapter, address).await? {
+        return Ok(device);
+    }
+
+    eprintln!("Scanning for {} (up to {}s)...", address, scan_timeout.as_secs());
+    let events = adapter.events().await?;
+    adapter.start_scan(ScanFilter::default()).await?;
+    *scanning = true;
+    let found = scan_for(adapter, address, scan_timeout, events).await;
+    let stopped = adapter.stop_scan().await;
+    *scanning = false;
+
+    let found = found?;
+    stopped?;
+    found.ok_or_else(|| BluetrackerError::DeviceNotFound(address.to_string()))
+}
+
+/// Waits for the running scan to find the device, checking whenever an event
+/// arrives and at least every [`SCAN_POLL_INTERVAL`].
+async fn scan_for(
+    adapter: &dyn BluetoothAdapter,
+    address: &str,
+    scan_timeout: Duration,
+    mut events: EventStream,
+) -> Result<Option<Box<dyn BluetoothPeripheral>>> {
+    let deadline = Instant::now() + scan_timeout;
+    // A closed event stream leaves polling as the only way to find the device.
+    let mut events_open = true;
+
+    loop {
+        if let Some(device) = lookup(adapter, address).await? {
+            return Ok(Some(device));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        tokio::select! {
+            _ = sleep(SCAN_POLL_INTERVAL) => {}
+            _ = sleep_until(deadline) => {}
+            event = events.next(), if events_open => events_open = event.is_some(),
+        }
+    }
+}
+
+async fn lookup(adapter: &dyn BluetoothAdapter, address: &str) -> Result<Option<Box<dyn BluetoothPeripheral>>> {
     let peripherals = adapter.peripherals().await?;
-    let peripheral = peripherals.into_iter().find(|p| p.address().to_string() == address);
-
-    match peripheral {
-        Some(device) => {
-            println!("Attempting to connect to device: {}", device.address());
-
-            // Retry mechanism with a delay for transient errors
-            let mut attempts = 0;
-            let max_attempts = 3;
-            let retry_delay = Duration::from_secs(2); // 2-second delay between retries
-
-            while attempts < max_attempts {
-                match device.connect().await {
-                    Ok(_) => {
-                        println!("Successfully connected to {}", device.address());
-                        return Ok(());
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to connect to {}: {}", device.address(), e);
-
-                        // Check if the error is a connection refused error (e.g., br-connection-refused)
-                        if e.to_string().contains("br-connection-refused") {
-                            eprintln!("Connection refused. The device may require manual pairing or authorization.");
-                        } else {
-                            eprintln!("Connection attempt failed for another reason.");
-                        }
-
-                        attempts += 1;
-                        if attempts < max_attempts {
-                            eprintln!("Retrying connection attempt {} of {}...", attempts, max_attempts);
-                            thread::sleep(retry_delay); // Wait before retrying
-                        }
-                    }
-                }
+    Ok(peripherals.into_iter().find(|p| p.address().eq_ignore_ascii_case(address)))
+}
+
+async fn connect_with_retries(device: &dyn BluetoothPeripheral, options: &ConnectOptions) -> Result<()> {
+    eprintln!("Attempting to connect to device: {}", device.address());
+
+    let max_attempts = options.max_attempts.max(1);
+    let mut attempts = 0;
+    loop {
+        match device.connect().await {
+            Ok(_) => {
+                eprintln!("Successfully connected to {}", device.address());
+                return Ok(());
             }
+            Err(e) => {
+                eprintln!("Failed to connect to {}: {}", device.address(), e);
 
-            eprintln!("Failed to connect to device {} after {} attempts", device.address(), max_attempts);
-            Err("Max connection attempts reached.".into())
+                // Check if the error is a connection refused error (e.g., br-connection-refused)
+                if e.to_string().contains("br-connection-refused") {
+                    eprintln!("Connection refused. The device may require manual pairing or authorization.");
+                }
+
+                attempts += 1;
+                if attempts >= max_attempts {
+                    return Err(BluetrackerError::ConnectFailed {
+                        address: device.address(),
+                        attempts,
+                        source: e,
+                    });
+                }
+                let delay = backoff_delay(options, attempts);
+                eprintln!(
+                    "Retrying in {:.1}s (attempt {} of {})...",
+                    delay.as_secs_f64(),
+                    attempts + 1,
+                    max_attempts
+                );
+                sleep(delay).await;
+            }
         }
-        None => {
-            eprintln!("Device with address {} not found.", address);
-            Err("Device not found.".into())
+    }
+}
+
+/// Delay before retry number `retry` (starting at 1): the initial backoff doubled
+/// for every earlier retry, capped, then jittered.
+fn backoff_delay(options: &ConnectOptions, retry: u32) -> Duration {
+    let exponent = retry.saturating_sub(1).min(16);
+    let delay = options.initial_backoff.saturating_mul(1 << exponent).min(options.max_backoff);
+    let jitter = if options.jitter.is_finite() { options.jitter.clamp(0.0, 1.0) } else { 0.0 };
+    delay.mul_f64(1.0 + jitter * (2.0 * random_unit() - 1.0))
+}
+
+/// Parses `--jitter`, a fraction between 0 and 1.
+pub fn parse_jitter(value: &str) -> std::result::Result<f64, String> {
+    let jitter: f64 = value.trim().parse().map_err(|_| format!("invalid jitter '{}'", value))?;
+    if !(0.0..=1.0).contains(&jitter) {
+        return Err(format!("jitter has to be between 0 and 1, not {}", value));
+    }
+    Ok(jitter)
+}
+
+/// A random number in [0, 1], drawn from the per-instance keys of the standard
+/// library's hasher so no RNG dependency is needed for jitter.
+fn random_unit() -> f64 {
+    let value = RandomState::new().build_hasher().finish();
+    value as f64 / u64::MAX as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitter_has_to_be_a_fraction() {
+        assert_eq!(parse_jitter("0.3"), Ok(0.3));
+        assert_eq!(parse_jitter("1"), Ok(1.0));
+        for value in ["NaN", "inf", "-0.1", "1.5", "lots"] {
+            assert!(parse_jitter(value).is_err(), "{} was accepted", value);
         }
+
+        let options = ConnectOptions { jitter: f64::NAN, ..Default::default() };
+        assert_eq!(backoff_delay(&options, 2), Duration::from_secs(1));
     }
 }
@@ -1,39 +1,286 @@
-use rusqlite::{params, Connection, Result, OptionalExtension};
+use rusqlite::functions::FunctionFlags;
+use rusqlite::{params, Connection, DatabaseName, OptionalExtension, Transaction};
 use chrono::{Utc, DateTime};
+use regex::Regex;
 use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use uuid::Uuid;
 
+use crate::estimate::{estimate_location, EstimatorOptions, LocationEstimate, Observation};
+use crate::error::{BluetrackerError, Result};
+use crate::gatt::{self, GattCharacteristic, GattDescriptor, GattService};
+use crate::gpx::Track;
+use crate::profiles::DeviceProfile;
+use crate::migrations;
 use crate::utils::{haversine_distance, get_manufacturer_id};
 
 #[derive(Debug, Clone, Serialize)]
 pub struct DeviceScanData {
+    pub timestamp: DateTime<Utc>,
     pub name: Option<String>,
     pub address: String,
-    pub rssi: i32,
-    pub tx_power: i32,
-    pub manufacturer_data: String,
+    pub address_type: Option<String>,
+    pub rssi: Option<i32>,
+    pub tx_power: Option<i32>,
+    pub manufacturer_data: HashMap<u16, Vec<u8>>,
+    pub services: Vec<Uuid>,
+    pub service_data: HashMap<Uuid, Vec<u8>>,
+    pub class: Option<u32>,
     pub latitude: Option<f64>,
     pub longitude: Option<f64>,
+    /// Meters above mean sea level, when a 3D GPS fix was available.
+    pub altitude: Option<f64>,
+    /// Estimated horizontal error of the position in meters.
+    pub accuracy: Option<f64>,
+    /// Horizontal dilution of precision of the GPS fix.
+    pub hdop: Option<f64>,
+    /// Name of the adapter that heard the advertisement.
+    pub adapter: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct DeviceDetection {
+    pub address: String,
     pub timestamp: DateTime<Utc>,
     pub latitude: Option<f64>,
     pub longitude: Option<f64>,
-    pub rssi: i32,
-    pub tx_power: i32,
-    pub manufacturer_data: String,
-    
+    pub altitude: Option<f64>,
+    pub accuracy: Option<f64>,
+    pub hdop: Option<f64>,
+    pub address_type: Option<String>,
+    pub rssi: Option<i32>,
+    pub tx_power: Option<i32>,
+    pub manufacturer_data: HashMap<u16, Vec<u8>>,
+    pub services: Vec<Uuid>,
+    pub service_data: HashMap<Uuid, Vec<u8>>,
+    pub class: Option<u32>,
+    pub adapter: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct DeviceEntry {
     pub address: String,
     pub name: String, // Device name (default: "Unknown")
     pub manufacturer_id: Option<u16>,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub detection_count: u32,
+    /// Strongest RSSI among the detections matched by the query.
+    pub strongest_rssi: Option<i32>,
+    #[serde(skip)]
     pub detections: Vec<DeviceDetection>,
 }
 
+/// A row of the devices table, as written by `db export`.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceRecord {
+    pub address: String,
+    pub name: Option<String>,
+    pub manufacturer_id: Option<u16>,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub detection_count: u32,
+}
+
+/// A stored detection with its row and session IDs, as written by `db export`.
+#[derive(Debug, Clone, Serialize)]
+pub struct DetectionRecord {
+    pub id: i64,
+    pub session_id: Option<i64>,
+    #[serde(flatten)]
+    pub detection: DeviceDetection,
+}
+
+/// What [`BluetoothTracker::merge_database`] added.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MergeSummary {
+    pub devices_added: u32,
+    /// Devices both databases knew, whose first/last seen and name were reconciled.
+    pub devices_updated: u32,
+    pub detections_added: u32,
+    /// Detections already present with the same device, time, RSSI, position and
+    /// adapter, or already summarised by an hourly aggregate of their session.
+    pub duplicate_detections: u32,
+    pub sessions_added: u32,
+    /// GATT tables and device information snapshots.
+    pub snapshots_added: u32,
+}
+
+/// Which detections [`BluetoothTracker::prune`] removes: those older than
+/// `older_than` and those beyond the newest `keep_per_device` of their device.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    pub older_than: Option<std::time::Duration>,
+    pub keep_per_device: Option<u32>,
+    /// Fold the removed detections into hourly per-device aggregates.
+    pub downsample: bool,
+}
+
+impl RetentionPolicy {
+    /// Whether the policy removes nothing.
+    pub fn is_empty(&self) -> bool {
+        self.older_than.is_none() && self.keep_per_device.is_none()
+    }
+}
+
+/// What [`BluetoothTracker::prune`] removed.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PruneSummary {
+    pub detections_removed: u32,
+    pub devices_affected: u32,
+    /// Hourly aggregates created or extended by downsampling.
+    pub aggregates_written: u32,
+}
+
+/// Downsampled detections of a device during one hour (UTC).
+#[derive(Debug, Clone, Serialize)]
+pub struct DetectionAggregate {
+    pub address: String,
+    pub hour: DateTime<Utc>,
+    pub session_id: Option<i64>,
+    pub detection_count: u32,
+    pub min_rssi: Option<i32>,
+    pub max_rssi: Option<i32>,
+    pub mean_rssi: Option<f64>,
+    /// Centroid of the detections that had a position.
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+/// Folds rows selected as (device_address, hour, session_id, detection_count,
+/// rssi_count, min_rssi, max_rssi, mean_rssi, located_count, latitude, longitude)
+/// into the hourly aggregates, weighting means by their sample counts.
+const AGGREGATE_UPSERT: &str = "ON CONFLICT(device_address, hour, session_id) DO UPDATE SET
+    detection_count = detection_aggregates.detection_count + excluded.detection_count,
+    rssi_count = detection_aggregates.rssi_count + excluded.rssi_count,
+    min_rssi = MIN(COALESCE(detection_aggregates.min_rssi, excluded.min_rssi),
+                   COALESCE(excluded.min_rssi, detection_aggregates.min_rssi)),
+    max_rssi = MAX(COALESCE(detection_aggregates.max_rssi, excluded.max_rssi),
+                   COALESCE(excluded.max_rssi, detection_aggregates.max_rssi)),
+    mean_rssi = (COALESCE(detection_aggregates.mean_rssi * detection_aggregates.rssi_count, 0)
+                 + COALESCE(excluded.mean_rssi * excluded.rssi_count, 0))
+                / NULLIF(detection_aggregates.rssi_count + excluded.rssi_count, 0),
+    located_count = detection_aggregates.located_count + excluded.located_count,
+    latitude = (COALESCE(detection_aggregates.latitude * detection_aggregates.located_count, 0)
+                + COALESCE(excluded.latitude * excluded.located_count, 0))
+               / NULLIF(detection_aggregates.located_count + excluded.located_count, 0),
+    longitude = (COALESCE(detection_aggregates.longitude * detection_aggregates.located_count, 0)
+                 + COALESCE(excluded.longitude * excluded.located_count, 0))
+                / NULLIF(detection_aggregates.located_count + excluded.located_count, 0)";
+
+/// A device detected near a point, described by its closest detection.
+#[derive(Debug, Clone, Serialize)]
+pub struct NearbyDevice {
+    pub address: String,
+    pub name: String,
+    pub distance_km: f64,
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Latest detection within the search radius.
+    pub last_seen: DateTime<Utc>,
+}
+
+/// A distinct name a device has advertised, with when it was first and last heard.
+#[derive(Debug, Clone, Serialize)]
+pub struct NameObservation {
+    pub name: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// What is known about a scan run when it starts.
+#[derive(Debug, Clone)]
+pub struct NewScanSession {
+    pub started_at: DateTime<Utc>,
+    /// Names of the adapters scanned on, comma separated.
+    pub adapter: Option<String>,
+    /// How long the run was set to listen; `None` for open-ended scans.
+    pub duration_secs: Option<f64>,
+    /// The filters in effect, as JSON.
+    pub filters: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub label: Option<String>,
+    pub host: Option<String>,
+}
+
+/// A scan run with a summary of what it heard.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanSession {
+    pub id: i64,
+    pub started_at: DateTime<Utc>,
+    /// `None` while the run is going on or when it was interrupted.
+    pub ended_at: Option<DateTime<Utc>>,
+    pub adapter: Option<String>,
+    pub duration_secs: Option<f64>,
+    pub filters: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub label: Option<String>,
+    pub host: Option<String>,
+    pub detection_count: u32,
+    pub device_count: u32,
+    /// Devices never detected before this run started.
+    pub new_device_count: u32,
+}
+
+/// A device heard during a scan session.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionDevice {
+    pub address: String,
+    pub name: Option<String>,
+    pub strongest_rssi: Option<i32>,
+    pub detection_count: u32,
+    /// Whether the session was the first to detect the device.
+    pub new: bool,
+}
+
+/// A detection with a position, as exported for mapping.
+#[derive(Debug, Clone, Serialize)]
+pub struct GeoDetection {
+    pub address: String,
+    pub name: String,
+    pub timestamp: DateTime<Utc>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f64>,
+    pub rssi: Option<i32>,
+    pub tx_power: Option<i32>,
+    pub hdop: Option<f64>,
+}
+
+/// Which detections [`BluetoothTracker::get_geo_detections`] returns.
+#[derive(Debug, Clone, Default)]
+pub struct DetectionQuery {
+    /// Only these devices; all when empty.
+    pub addresses: Vec<String>,
+    /// Regular expression the device name has to match.
+    pub name_pattern: Option<String>,
+    pub start_time: Option<DateTime<Utc>>,
+    pub end_time: Option<DateTime<Utc>>,
+}
+
+/// Outcome of [`BluetoothTracker::geotag_detections`].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GeotagSummary {
+    pub tagged: u32,
+    /// Untagged detections before or after the track, or in a gap longer than allowed.
+    pub outside_track: u32,
+}
+
+/// Session columns plus detection, device and new device counts.
+const SCAN_SESSION_QUERY: &str =
+    "SELECT s.id, s.started_at, s.ended_at, s.adapter, s.duration_secs, s.filters, s.latitude, s.longitude,
+            s.label, s.host,
+            (SELECT COUNT(*) FROM detections d WHERE d.session_id = s.id),
+            (SELECT COUNT(DISTINCT d.device_address) FROM detections d WHERE d.session_id = s.id),
+            (SELECT COUNT(DISTINCT d.device_address) FROM detections d
+             WHERE d.session_id = s.id
+               AND NOT EXISTS (SELECT 1 FROM detections e
+                               WHERE e.device_address = d.device_address AND e.timestamp < s.started_at))
+     FROM scan_sessions s";
+
 #[derive(Debug, Clone)]
 pub struct FilterOptions {
     pub start_time: Option<DateTime<Utc>>,
@@ -41,141 +288,168 @@ pub struct FilterOptions {
     pub limit: Option<usize>,
 }
 
+/// Order of the devices returned by [`BluetoothTracker::get_devices`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeviceSort {
+    /// Most recently seen first
+    #[default]
+    LastSeen,
+    /// Most detections first
+    Count,
+    /// Strongest RSSI first
+    Rssi,
+}
+
+/// Device-level search criteria. Detection based criteria (count, RSSI,
+/// services, locations) only consider detections inside the time window.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceQuery {
+    pub start_time: Option<DateTime<Utc>>,
+    pub end_time: Option<DateTime<Utc>>,
+    pub manufacturer_id: Option<u16>,
+    /// Regular expression the device name has to match.
+    pub name_pattern: Option<String>,
+    pub min_detections: Option<u32>,
+    /// Bounds on the strongest RSSI the device was heard with.
+    pub min_rssi: Option<i32>,
+    pub max_rssi: Option<i32>,
+    /// Service UUID the device has advertised.
+    pub service: Option<Uuid>,
+    /// Minimum number of distinct places the device was heard at.
+    pub min_locations: Option<u32>,
+    pub sort: DeviceSort,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+/// Coordinates are rounded to this many decimals (about 11 m) when counting
+/// the distinct places a device was heard at.
+const LOCATION_PRECISION: i32 = 4;
+
+/// Upper bound on the detections fed into a location estimate.
+const MAX_ESTIMATE_DETECTIONS: i64 = 500;
+
 pub struct BluetoothTracker {
     conn: Connection,
 }
 
 impl BluetoothTracker {
     pub fn new(db_path: &str) -> Result<Self> {
-        let conn = Connection::open(db_path)?;
-
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS devices (
-                address TEXT PRIMARY KEY,
-                name TEXT,
-                manufacturer_id TEXT
-            )",
-            [],
-        )?;
+        let mut conn = Connection::open(db_path)?;
+        add_regexp_function(&conn)?;
 
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS detections (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                device_address TEXT,
-                timestamp TEXT,
-                latitude REAL,
-                longitude REAL,
-                rssi INTEGER,
-                tx_power INTEGER,
-                manufacturer_data TEXT,
-                FOREIGN KEY(device_address) REFERENCES devices(address)
-            )",
-            [],
-        )?;
+        let report = migrations::migrate(&mut conn, db_path)?;
+        if let Some(backup) = report.backup {
+            println!("Database backed up to {} before migrating.", backup);
+        }
 
         Ok(Self { conn })
     }
-    
 
     pub fn store_scan_data(&self, scan_data: DeviceScanData) -> Result<()> {
-        let now = Utc::now();
-        let detection = DeviceDetection {
-            timestamp: now,
-            latitude: scan_data.latitude,
-            longitude: scan_data.longitude,
-            rssi: scan_data.rssi,
-            tx_power: scan_data.tx_power,
-            manufacturer_data: scan_data.manufacturer_data.clone(),
-        };
+        Ok(insert_scan_data(&self.conn, &scan_data, None)?)
+    }
 
-        let mut stmt = self.conn.prepare("SELECT name FROM devices WHERE address = ?")?;
-        let device_name: Option<String> = stmt
-            .query_row(params![scan_data.address], |row| row.get(0))
-            .optional()?;
+    pub fn store_scan_data_batch(&mut self, scan_data_list: &[DeviceScanData]) -> Result<()> {
+        self.insert_batch(scan_data_list, None)
+    }
 
-        if device_name.is_none() {
-            self.conn.execute(
-                "INSERT INTO devices (address, name, manufacturer_id) VALUES (?1, ?2, ?3)",
-                params![
-                    scan_data.address,
-                    scan_data.name.clone().unwrap_or_else(|| "Unknown".to_string()),
-                    get_manufacturer_id(&scan_data.manufacturer_data)
-                ],
-            )?;
+    /// Stores detections made during the given scan session.
+    pub fn store_session_scan_data(&mut self, session_id: i64, scan_data_list: &[DeviceScanData]) -> Result<()> {
+        self.insert_batch(scan_data_list, Some(session_id))
+    }
+
+    fn insert_batch(&mut self, scan_data_list: &[DeviceScanData], session_id: Option<i64>) -> Result<()> {
+        let transaction = self.conn.transaction()?;
+
+        for scan_data in scan_data_list {
+            insert_scan_data(&transaction, scan_data, session_id)?;
         }
 
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Records the start of a scan run and returns its session ID.
+    pub fn start_scan_session(&self, session: &NewScanSession) -> Result<i64> {
         self.conn.execute(
-            "INSERT INTO detections (device_address, timestamp, latitude, longitude, rssi, tx_power, manufacturer_data) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            "INSERT INTO scan_sessions (started_at, adapter, duration_secs, filters, latitude, longitude, label, host)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
             params![
-                scan_data.address,
-                detection.timestamp.to_rfc3339(),
-                detection.latitude,
-                detection.longitude,
-                detection.rssi,
-                detection.tx_power,
-                detection.manufacturer_data
+                session.started_at.to_rfc3339(),
+                session.adapter,
+                session.duration_secs,
+                session.filters,
+                session.latitude,
+                session.longitude,
+                session.label,
+                session.host
             ],
         )?;
+        Ok(self.conn.last_insert_rowid())
+    }
 
+    pub fn finish_scan_session(&self, session_id: i64, ended_at: DateTime<Utc>) -> Result<()> {
+        self.conn.execute(
+            "UPDATE scan_sessions SET ended_at = ?1 WHERE id = ?2",
+            params![ended_at.to_rfc3339(), session_id],
+        )?;
         Ok(())
     }
 
-    // Changed &self to &mut self to allow mutable access for the transaction.
-    pub fn store_scan_data_batch(&mut self, scan_data_list: &Vec<DeviceScanData>) -> Result<()> {
-        let transaction = self.conn.transaction()?;
-        
-        for scan_data in scan_data_list {
-            let now = Utc::now();
-            let detection = DeviceDetection {
-                timestamp: now,
-                latitude: scan_data.latitude,
-                longitude: scan_data.longitude,
-                rssi: scan_data.rssi,
-                tx_power: scan_data.tx_power,
-                manufacturer_data: scan_data.manufacturer_data.clone(),
-            };
-    
-            // Check if the device exists
-            let mut stmt = transaction.prepare("SELECT name FROM devices WHERE address = ?")?;
-            let device_name: Option<String> = stmt
-                .query_row(params![scan_data.address], |row| row.get(0))
-                .optional()?;
-    
-            // Insert device if it doesn't exist
-            if device_name.is_none() {
-                transaction.execute(
-                    "INSERT INTO devices (address, name, manufacturer_id) VALUES (?1, ?2, ?3)",
-                    params![
-                        scan_data.address,
-                        scan_data.name.clone().unwrap_or_else(|| "Unknown".to_string()),
-                        get_manufacturer_id(&scan_data.manufacturer_data)
-                    ],
-                )?;
-            }
-    
-            // Insert detection
-            transaction.execute(
-                "INSERT INTO detections (device_address, timestamp, latitude, longitude, rssi, tx_power, manufacturer_data) 
-                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
-                params![
-                    scan_data.address,
-                    detection.timestamp.to_rfc3339(),
-                    detection.latitude,
-                    detection.longitude,
-                    detection.rssi,
-                    detection.tx_power,
-                    detection.manufacturer_data
-                ],
-            )?;
-        }
-    
-        transaction.commit()?;
-        Ok(())
+    /// Deletes a session without detections, such as one whose scan failed
+    /// before hearing anything. Returns whether it was deleted.
+    pub fn discard_empty_scan_session(&self, session_id: i64) -> Result<bool> {
+        let deleted = self.conn.execute(
+            "DELETE FROM scan_sessions WHERE id = ?1 AND NOT EXISTS (SELECT 1 FROM detections WHERE session_id = ?1)",
+            params![session_id],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    /// Lists scan sessions, most recent first.
+    pub fn get_scan_sessions(&self, limit: Option<usize>) -> Result<Vec<ScanSession>> {
+        let mut stmt = self.conn.prepare(&format!("{} ORDER BY s.started_at DESC, s.id DESC LIMIT ?", SCAN_SESSION_QUERY))?;
+        let limit = limit.map_or(-1, |limit| limit as i64);
+        let rows = stmt.query_map(params![limit], read_scan_session)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn get_scan_session(&self, session_id: i64) -> Result<Option<ScanSession>> {
+        let mut stmt = self.conn.prepare(&format!("{} WHERE s.id = ?", SCAN_SESSION_QUERY))?;
+        Ok(stmt.query_row(params![session_id], read_scan_session).optional()?)
+    }
+
+    /// The devices heard during a session, strongest signal first.
+    pub fn get_session_devices(&self, session_id: i64, limit: Option<usize>) -> Result<Vec<SessionDevice>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT d.device_address, dev.name, MAX(d.rssi), COUNT(*),
+                    NOT EXISTS (SELECT 1 FROM detections e
+                                WHERE e.device_address = d.device_address AND e.timestamp < s.started_at)
+             FROM detections d
+             JOIN scan_sessions s ON s.id = d.session_id
+             LEFT JOIN devices dev ON dev.address = d.device_address
+             WHERE d.session_id = ?1
+             GROUP BY d.device_address
+             ORDER BY MAX(d.rssi) IS NULL, MAX(d.rssi) DESC, d.device_address
+             LIMIT ?2",
+        )?;
+        let limit = limit.map_or(-1, |limit| limit as i64);
+        let rows = stmt.query_map(params![session_id, limit], |row| {
+            Ok(SessionDevice {
+                address: row.get(0)?,
+                name: row.get(1)?,
+                strongest_rssi: row.get(2)?,
+                detection_count: row.get(3)?,
+                new: row.get(4)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
     }
-    
+
     pub fn get_device_history(&mut self, address: &str, filters: FilterOptions) -> Result<Vec<DeviceDetection>> {
         let mut query = String::from(
-            "SELECT timestamp, latitude, longitude, rssi, tx_power, manufacturer_data 
+            "SELECT id, timestamp, latitude, longitude, rssi, tx_power, address_type, class, adapter, altitude, accuracy, hdop
              FROM detections 
              WHERE device_address = ?"
         );
@@ -200,92 +474,1159 @@ impl BluetoothTracker {
         
         let mut stmt = self.conn.prepare(&query)?;
         let rows = stmt.query_map(rusqlite::params_from_iter(params.iter()), |row| {
-            Ok(DeviceDetection {
-                timestamp: row.get(0)?,
-                latitude: row.get(1)?,
-                longitude: row.get(2)?,
-                rssi: row.get(3)?,
-                tx_power: row.get(4)?,
-                manufacturer_data: row.get(5)?,
-            })
+            let detection_id: i64 = row.get(0)?;
+            Ok((detection_id, DeviceDetection {
+                address: address.to_string(),
+                timestamp: row.get(1)?,
+                latitude: row.get(2)?,
+                longitude: row.get(3)?,
+                rssi: row.get(4)?,
+                tx_power: row.get(5)?,
+                address_type: row.get(6)?,
+                class: row.get(7)?,
+                adapter: row.get(8)?,
+                altitude: row.get(9)?,
+                accuracy: row.get(10)?,
+                hdop: row.get(11)?,
+                manufacturer_data: HashMap::new(),
+                services: Vec::new(),
+                service_data: HashMap::new(),
+            }))
         })?;
 
-        let history: Result<Vec<_>> = rows.collect();
-        history
+        let mut history = Vec::new();
+        for row in rows {
+            let (detection_id, mut detection) = row?;
+            detection.manufacturer_data = load_manufacturer_data(&self.conn, detection_id)?;
+            detection.services = load_services(&self.conn, detection_id)?;
+            detection.service_data = load_service_data(&self.conn, detection_id)?;
+            history.push(detection);
+        }
+        Ok(history)
     }
 
-    pub fn get_devices(&mut self, filters: FilterOptions, manufacturer_id: Option<u16>) -> Result<Vec<DeviceEntry>> {
-        let mut query = String::from("SELECT address, name, manufacturer_id FROM devices WHERE 1=1");
-        let mut params: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
-    
-        if let Some(id) = manufacturer_id {
-            query.push_str(" AND manufacturer_id = ?");
+    pub fn get_devices(&self, query: &DeviceQuery) -> Result<Vec<DeviceEntry>> {
+        if let Some(pattern) = &query.name_pattern {
+            Regex::new(pattern).map_err(|e| BluetrackerError::Parse(format!("invalid name pattern: {}", e)))?;
+        }
+
+        // Aggregate the detections inside the time window per device, then
+        // apply the device-level criteria to the aggregates.
+        let mut window = String::from("1=1");
+        let mut params: Vec<Box<dyn rusqlite::ToSql>> = vec![Box::new(LOCATION_PRECISION)];
+
+        if let Some(start) = query.start_time {
+            window.push_str(" AND timestamp >= ?");
+            params.push(Box::new(start.to_rfc3339()));
+        }
+        if let Some(end) = query.end_time {
+            window.push_str(" AND timestamp <= ?");
+            params.push(Box::new(end.to_rfc3339()));
+        }
+
+        // Without a time window every device is listed, including those whose
+        // detections were pruned: their hourly aggregates stand in for the
+        // removed detections, and the device row keeps the lifetime count.
+        let windowed = query.start_time.is_some() || query.end_time.is_some();
+        let (source, join, hits, latest) = if windowed {
+            (
+                format!("SELECT device_address, rssi, timestamp, latitude, longitude FROM detections WHERE {}", window),
+                "JOIN",
+                "w.hits",
+                "w.latest",
+            )
+        } else {
+            (
+                "SELECT device_address, rssi, timestamp, latitude, longitude FROM detections
+                 UNION ALL
+                 SELECT device_address, max_rssi, hour, latitude, longitude FROM detection_aggregates"
+                    .to_string(),
+                "LEFT JOIN",
+                "d.detection_count",
+                "d.last_seen",
+            )
+        };
+
+        let mut sql = format!(
+            "SELECT d.address, d.name, CAST(d.manufacturer_id AS INTEGER), d.first_seen, d.last_seen,
+                    d.detection_count, w.strongest_rssi
+             FROM devices d
+             {} (
+                SELECT device_address,
+                       COUNT(*) AS hits,
+                       MAX(rssi) AS strongest_rssi,
+                       MAX(timestamp) AS latest,
+                       COUNT(DISTINCT ROUND(latitude, ?1) || ',' || ROUND(longitude, ?1)) AS locations
+                FROM ({})
+                GROUP BY device_address
+             ) w ON w.device_address = d.address
+             WHERE 1=1",
+            join, source
+        );
+
+        if let Some(id) = query.manufacturer_id {
+            sql.push_str(" AND CAST(d.manufacturer_id AS INTEGER) = ?");
             params.push(Box::new(id));
         }
-    
-        let lim = filters.limit.unwrap_or(50);
-        query.push_str(" LIMIT ?");
-        params.push(Box::new(lim as i64));
-    
-        let mut stmt = self.conn.prepare(&query)?;
+        if let Some(pattern) = &query.name_pattern {
+            sql.push_str(" AND d.name REGEXP ?");
+            params.push(Box::new(pattern.clone()));
+        }
+        if let Some(count) = query.min_detections {
+            sql.push_str(&format!(" AND {} >= ?", hits));
+            params.push(Box::new(count));
+        }
+        if let Some(rssi) = query.min_rssi {
+            sql.push_str(" AND w.strongest_rssi >= ?");
+            params.push(Box::new(rssi));
+        }
+        if let Some(rssi) = query.max_rssi {
+            sql.push_str(" AND w.strongest_rssi <= ?");
+            params.push(Box::new(rssi));
+        }
+        if let Some(service) = query.service {
+            sql.push_str(&format!(
+                " AND EXISTS (
+                    SELECT 1 FROM detection_services s JOIN detections t ON t.id = s.detection_id
+                    WHERE s.uuid = ? AND t.device_address = d.address AND {}
+                 )",
+                window
+            ));
+            params.push(Box::new(service.to_string()));
+            if let Some(start) = query.start_time {
+                params.push(Box::new(start.to_rfc3339()));
+            }
+            if let Some(end) = query.end_time {
+                params.push(Box::new(end.to_rfc3339()));
+            }
+        }
+        if let Some(locations) = query.min_locations {
+            sql.push_str(" AND w.locations >= ?");
+            params.push(Box::new(locations));
+        }
+
+        sql.push_str(&match query.sort {
+            DeviceSort::LastSeen => format!(" ORDER BY {} DESC", latest),
+            DeviceSort::Count => format!(" ORDER BY {} DESC, {} DESC", hits, latest),
+            DeviceSort::Rssi => format!(" ORDER BY w.strongest_rssi IS NULL, w.strongest_rssi DESC, {} DESC", latest),
+        });
+
+        sql.push_str(" LIMIT ? OFFSET ?");
+        params.push(Box::new(query.limit.unwrap_or(50) as i64));
+        params.push(Box::new(query.offset.unwrap_or(0) as i64));
+
+        let mut stmt = self.conn.prepare(&sql)?;
         let rows = stmt.query_map(rusqlite::params_from_iter(params.iter()), |row| {
             Ok(DeviceEntry {
                 address: row.get(0)?,
                 name: row.get(1)?,
                 manufacturer_id: row.get(2)?,
+                first_seen: row.get(3)?,
+                last_seen: row.get(4)?,
+                detection_count: row.get(5)?,
+                strongest_rssi: row.get(6)?,
                 detections: Vec::new(),
             })
         })?;
-    
-        rows.collect()
+
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
     }
 
-    pub fn estimate_device_location(&self, address: &str) -> Result<Option<(f64, f64)>> {
+    pub fn get_name_history(&self, address: &str) -> Result<Vec<NameObservation>> {
         let mut stmt = self.conn.prepare(
-            "SELECT latitude, longitude 
-             FROM detections 
-             WHERE device_address = ? 
-             ORDER BY timestamp DESC 
-             LIMIT 1",
+            "SELECT name, first_seen, last_seen FROM device_name_history
+             WHERE address = ? ORDER BY first_seen",
         )?;
+        let rows = stmt.query_map(params![address], |row| {
+            Ok(NameObservation {
+                name: row.get(0)?,
+                first_seen: row.get(1)?,
+                last_seen: row.get(2)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
 
-        let mut rows = stmt.query(params![address])?;
-        if let Some(row) = rows.next()? {
-            let latitude: f64 = row.get(0)?;
-            let longitude: f64 = row.get(1)?;
-            Ok(Some((latitude, longitude)))
-        } else {
-            Ok(None)
+    /// Estimates a device's location from its most recent geotagged detections,
+    /// optionally restricted to those heard at or after `since`. When pruning
+    /// removed them all, the centroids of its hourly aggregates are used instead.
+    pub fn estimate_device_location(
+        &self,
+        address: &str,
+        since: Option<DateTime<Utc>>,
+        options: &EstimatorOptions,
+    ) -> Result<Option<LocationEstimate>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT timestamp, latitude, longitude, rssi, tx_power, hdop
+             FROM detections
+             WHERE device_address = ?1
+             AND latitude IS NOT NULL AND longitude IS NOT NULL
+             AND (?2 IS NULL OR timestamp >= ?2)
+             ORDER BY timestamp DESC
+             LIMIT ?3",
+        )?;
+
+        let now = Utc::now();
+        let rows = stmt.query_map(
+            params![address, since.map(|t| t.to_rfc3339()), MAX_ESTIMATE_DETECTIONS],
+            |row| {
+                let timestamp: DateTime<Utc> = row.get(0)?;
+                Ok(Observation {
+                    latitude: row.get(1)?,
+                    longitude: row.get(2)?,
+                    rssi: row.get(3)?,
+                    tx_power: row.get(4)?,
+                    hdop: row.get(5)?,
+                    age_secs: (now - timestamp).num_milliseconds() as f64 / 1000.0,
+                })
+            },
+        )?;
+        let mut observations = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+
+        if observations.is_empty() {
+            let mut stmt = self.conn.prepare(
+                "SELECT hour, latitude, longitude, mean_rssi
+                 FROM detection_aggregates
+                 WHERE device_address = ?1
+                 AND latitude IS NOT NULL AND longitude IS NOT NULL
+                 AND (?2 IS NULL OR hour >= strftime('%Y-%m-%dT%H:00:00Z', ?2))
+                 ORDER BY hour DESC
+                 LIMIT ?3",
+            )?;
+            let rows = stmt.query_map(
+                params![address, since.map(|t| t.to_rfc3339()), MAX_ESTIMATE_DETECTIONS],
+                |row| {
+                    let hour: DateTime<Utc> = row.get(0)?;
+                    let mean_rssi: Option<f64> = row.get(3)?;
+                    Ok(Observation {
+                        latitude: row.get(1)?,
+                        longitude: row.get(2)?,
+                        rssi: mean_rssi.map(|rssi| rssi.round() as i32),
+                        tx_power: None,
+                        hdop: None,
+                        age_secs: (now - hour).num_milliseconds() as f64 / 1000.0,
+                    })
+                },
+            )?;
+            observations = rows.collect::<rusqlite::Result<Vec<_>>>()?;
         }
+
+        Ok(estimate_location(&observations, options))
     }
 
-    pub fn find_devices_near(&self, latitude: f64, longitude: f64, radius_km: f64) -> Result<Vec<String>> {
-        // Bounding box filter for rough pre-selection (1-degree lat/lon ≈ 111 km)
-        let lat_range = radius_km / 111.0;
+    /// Finds devices detected within `radius_km` of a point, one entry per device
+    /// for its closest detection, sorted by distance.
+    pub fn find_devices_near(
+        &self,
+        latitude: f64,
+        longitude: f64,
+        radius_km: f64,
+        since: Option<DateTime<Utc>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<NearbyDevice>> {
         let mut stmt = self.conn.prepare(
-            "SELECT DISTINCT device_address, latitude, longitude 
-             FROM detections 
-             WHERE latitude BETWEEN ?1 - ?3 AND ?1 + ?3 
-             AND longitude BETWEEN ?2 - ?3 AND ?2 + ?3",
+            "SELECT d.device_address, d.latitude, d.longitude, d.timestamp
+             FROM detections_rtree r
+             JOIN detections d ON d.id = r.id
+             WHERE r.max_lat >= ?1 AND r.min_lat <= ?2
+             AND r.max_lon >= ?3 AND r.min_lon <= ?4
+             AND (?5 IS NULL OR d.timestamp >= ?5)",
         )?;
 
-        let rows = stmt.query_map(params![latitude, longitude, lat_range], |row| {
-            let address: String = row.get(0)?;
-            let lat: f64 = row.get(1)?;
-            let lon: f64 = row.get(2)?;
-            Ok((address, lat, lon))
+        let since = since.map(|t| t.to_rfc3339());
+        let (min_lat, max_lat, lon_ranges) = bounding_box(latitude, longitude, radius_km);
+        let mut closest: HashMap<String, NearbyDevice> = HashMap::new();
+
+        for (min_lon, max_lon) in lon_ranges {
+            let rows = stmt.query_map(params![min_lat, max_lat, min_lon, max_lon, since], |row| {
+                let address: String = row.get(0)?;
+                let lat: f64 = row.get(1)?;
+                let lon: f64 = row.get(2)?;
+                let timestamp: DateTime<Utc> = row.get(3)?;
+                Ok((address, lat, lon, timestamp))
+            })?;
+
+            for row in rows {
+                let (address, lat, lon, timestamp) = row?;
+                let distance_km = haversine_distance(latitude, longitude, lat, lon);
+                if distance_km > radius_km {
+                    continue;
+                }
+
+                let entry = closest.entry(address.clone()).or_insert_with(|| NearbyDevice {
+                    address,
+                    name: String::new(),
+                    distance_km,
+                    latitude: lat,
+                    longitude: lon,
+                    last_seen: timestamp,
+                });
+                if distance_km < entry.distance_km {
+                    entry.distance_km = distance_km;
+                    entry.latitude = lat;
+                    entry.longitude = lon;
+                }
+                entry.last_seen = entry.last_seen.max(timestamp);
+            }
+        }
+
+        let mut devices: Vec<NearbyDevice> = closest.into_values().collect();
+        devices.sort_by(|a, b| a.distance_km.total_cmp(&b.distance_km));
+        if let Some(limit) = limit {
+            devices.truncate(limit);
+        }
+
+        let mut name_stmt = self.conn.prepare("SELECT name FROM devices WHERE address = ?")?;
+        for device in &mut devices {
+            device.name = name_stmt
+                .query_row(params![device.address], |row| row.get::<_, Option<String>>(0))
+                .optional()?
+                .flatten()
+                .unwrap_or_else(|| "Unknown".to_string());
+        }
+
+        Ok(devices)
+    }
+
+    /// Geotagged detections matching the query, grouped by device and in time order.
+    pub fn get_geo_detections(&self, query: &DetectionQuery) -> Result<Vec<GeoDetection>> {
+        if let Some(pattern) = &query.name_pattern {
+            Regex::new(pattern).map_err(|e| BluetrackerError::Parse(format!("invalid name pattern: {}", e)))?;
+        }
+
+        let mut sql = String::from(
+            "SELECT d.device_address, COALESCE(v.name, 'Unknown'), d.timestamp, d.latitude, d.longitude, d.altitude,
+                    d.rssi, d.tx_power, d.hdop
+             FROM detections d
+             JOIN devices v ON v.address = d.device_address
+             WHERE d.latitude IS NOT NULL AND d.longitude IS NOT NULL",
+        );
+        let mut params: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
+
+        if !query.addresses.is_empty() {
+            let placeholders = vec!["?"; query.addresses.len()].join(", ");
+            sql.push_str(&format!(" AND d.device_address COLLATE NOCASE IN ({})", placeholders));
+            for address in &query.addresses {
+                params.push(Box::new(address.clone()));
+            }
+        }
+        if let Some(pattern) = &query.name_pattern {
+            sql.push_str(" AND v.name REGEXP ?");
+            params.push(Box::new(pattern.clone()));
+        }
+        if let Some(start) = query.start_time {
+            sql.push_str(" AND d.timestamp >= ?");
+            params.push(Box::new(start.to_rfc3339()));
+        }
+        if let Some(end) = query.end_time {
+            sql.push_str(" AND d.timestamp <= ?");
+            params.push(Box::new(end.to_rfc3339()));
+        }
+        sql.push_str(" ORDER BY d.device_address, d.timestamp");
+
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(rusqlite::params_from_iter(params.iter()), |row| {
+            Ok(GeoDetection {
+                address: row.get(0)?,
+                name: row.get(1)?,
+                timestamp: row.get(2)?,
+                latitude: row.get(3)?,
+                longitude: row.get(4)?,
+                altitude: row.get(5)?,
+                rssi: row.get(6)?,
+                tx_power: row.get(7)?,
+                hdop: row.get(8)?,
+            })
         })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
 
-        let mut devices = Vec::new();
+    /// Fills in the position of detections recorded without one from a GPS
+    /// track, matching them by timestamp. Altitude is only set where missing.
+    pub fn geotag_detections(&mut self, track: &Track, max_gap: chrono::Duration) -> Result<GeotagSummary> {
+        let transaction = self.conn.transaction()?;
+        let mut summary = GeotagSummary::default();
+        {
+            let untagged = transaction
+                .prepare("SELECT id, timestamp FROM detections WHERE latitude IS NULL OR longitude IS NULL")?
+                .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, DateTime<Utc>>(1)?)))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            let mut update = transaction.prepare(
+                "UPDATE detections SET latitude = ?2, longitude = ?3, altitude = COALESCE(altitude, ?4) WHERE id = ?1",
+            )?;
+            for (id, timestamp) in untagged {
+                match track.position_at(timestamp, max_gap) {
+                    Some(point) => {
+                        update.execute(params![id, point.latitude, point.longitude, point.elevation])?;
+                        summary.tagged += 1;
+                    }
+                    None => summary.outside_track += 1,
+                }
+            }
+        }
+        transaction.commit()?;
+        Ok(summary)
+    }
+
+    /// Every stored device, ordered by address.
+    pub fn get_device_records(&self) -> Result<Vec<DeviceRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT address, name, CAST(manufacturer_id AS INTEGER), first_seen, last_seen, detection_count
+             FROM devices
+             ORDER BY address",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(DeviceRecord {
+                address: row.get(0)?,
+                name: row.get(1)?,
+                manufacturer_id: row.get(2)?,
+                first_seen: row.get(3)?,
+                last_seen: row.get(4)?,
+                detection_count: row.get(5)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Every stored detection with its advertisement data, oldest first.
+    pub fn get_detection_records(&self) -> Result<Vec<DetectionRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, session_id, device_address, timestamp, latitude, longitude, altitude, accuracy, hdop,
+                    address_type, rssi, tx_power, class, adapter
+             FROM detections
+             ORDER BY timestamp, id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(DetectionRecord {
+                id: row.get(0)?,
+                session_id: row.get(1)?,
+                detection: DeviceDetection {
+                    address: row.get(2)?,
+                    timestamp: row.get(3)?,
+                    latitude: row.get(4)?,
+                    longitude: row.get(5)?,
+                    altitude: row.get(6)?,
+                    accuracy: row.get(7)?,
+                    hdop: row.get(8)?,
+                    address_type: row.get(9)?,
+                    rssi: row.get(10)?,
+                    tx_power: row.get(11)?,
+                    class: row.get(12)?,
+                    adapter: row.get(13)?,
+                    manufacturer_data: HashMap::new(),
+                    services: Vec::new(),
+                    service_data: HashMap::new(),
+                },
+            })
+        })?;
+
+        let mut records = Vec::new();
         for row in rows {
-            let (address, lat, lon) = row?;
-            if haversine_distance(latitude, longitude, lat, lon) <= radius_km {
-                devices.push(address);
+            let mut record = row?;
+            record.detection.manufacturer_data = load_manufacturer_data(&self.conn, record.id)?;
+            record.detection.services = load_services(&self.conn, record.id)?;
+            record.detection.service_data = load_service_data(&self.conn, record.id)?;
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    /// Downsampled hourly aggregates, ordered by device, hour and session.
+    pub fn get_aggregate_records(&self) -> Result<Vec<DetectionAggregate>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT device_address, hour, NULLIF(session_id, 0), detection_count, min_rssi, max_rssi, mean_rssi,
+                    latitude, longitude
+             FROM detection_aggregates
+             ORDER BY device_address, hour, session_id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(DetectionAggregate {
+                address: row.get(0)?,
+                hour: row.get(1)?,
+                session_id: row.get(2)?,
+                detection_count: row.get(3)?,
+                min_rssi: row.get(4)?,
+                max_rssi: row.get(5)?,
+                mean_rssi: row.get(6)?,
+                latitude: row.get(7)?,
+                longitude: row.get(8)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Removes the detections the policy selects, with their advertisement data.
+    /// Devices and their lifetime detection counts are kept. With `dry_run`
+    /// nothing is changed, but the summary tells what would have been.
+    pub fn prune(&mut self, policy: &RetentionPolicy, dry_run: bool) -> Result<PruneSummary> {
+        let transaction = self.conn.transaction()?;
+        transaction.execute("CREATE TEMP TABLE prune_ids (id INTEGER PRIMARY KEY)", [])?;
+
+        if let Some(older_than) = policy.older_than {
+            let older_than = chrono::Duration::from_std(older_than)
+                .map_err(|_| BluetrackerError::Parse(format!("retention period {:?} is too long", older_than)))?;
+            let cutoff = Utc::now().checked_sub_signed(older_than).unwrap_or(DateTime::<Utc>::MIN_UTC);
+            transaction.execute(
+                "INSERT OR IGNORE INTO temp.prune_ids SELECT id FROM detections WHERE timestamp < ?1",
+                params![cutoff.to_rfc3339()],
+            )?;
+        }
+        if let Some(keep) = policy.keep_per_device {
+            transaction.execute(
+                "INSERT OR IGNORE INTO temp.prune_ids
+                 SELECT id FROM (
+                    SELECT id, ROW_NUMBER() OVER (PARTITION BY device_address ORDER BY timestamp DESC, id DESC) AS rank
+                    FROM detections
+                 )
+                 WHERE rank > ?1",
+                params![keep],
+            )?;
+        }
+
+        let mut summary = PruneSummary::default();
+        (summary.detections_removed, summary.devices_affected) = transaction.query_row(
+            "SELECT COUNT(*), COUNT(DISTINCT device_address) FROM detections WHERE id IN (SELECT id FROM temp.prune_ids)",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+
+        if policy.downsample {
+            // Hours are bucketed in UTC, whatever offset the timestamps were stored with.
+            summary.aggregates_written = transaction.execute(
+                &format!(
+                    "INSERT INTO detection_aggregates
+                        (device_address, hour, session_id, detection_count, rssi_count, min_rssi, max_rssi,
+                         mean_rssi, located_count, latitude, longitude)
+                     SELECT device_address, strftime('%Y-%m-%dT%H:00:00Z', timestamp), COALESCE(session_id, 0),
+                            COUNT(*), COUNT(rssi), MIN(rssi), MAX(rssi), AVG(rssi),
+                            SUM(latitude IS NOT NULL AND longitude IS NOT NULL),
+                            AVG(CASE WHEN longitude IS NOT NULL THEN latitude END),
+                            AVG(CASE WHEN latitude IS NOT NULL THEN longitude END)
+                     FROM detections
+                     WHERE id IN (SELECT id FROM temp.prune_ids)
+                     GROUP BY 1, 2, 3
+                     {}",
+                    AGGREGATE_UPSERT
+                ),
+                [],
+            )? as u32;
+        }
+
+        transaction.execute_batch(
+            "DELETE FROM manufacturer_data WHERE detection_id IN (SELECT id FROM temp.prune_ids);
+             DELETE FROM detection_services WHERE detection_id IN (SELECT id FROM temp.prune_ids);
+             DELETE FROM service_data WHERE detection_id IN (SELECT id FROM temp.prune_ids);
+             DELETE FROM detections WHERE id IN (SELECT id FROM temp.prune_ids);
+             DROP TABLE temp.prune_ids;",
+        )?;
+
+        if dry_run {
+            transaction.rollback()?;
+        } else {
+            transaction.commit()?;
+        }
+        Ok(summary)
+    }
+
+    /// Returns free pages to the file system and reports how many bytes the file
+    /// shrank by. This is incremental and cheap, but only does anything once
+    /// [`compact`](Self::compact) has switched the database to incremental auto-vacuum.
+    pub fn vacuum(&self) -> Result<u64> {
+        let before = file_size(&self.conn)?;
+        self.conn.execute_batch("PRAGMA incremental_vacuum;")?;
+        Ok(before.saturating_sub(file_size(&self.conn)?))
+    }
+
+    /// Whether [`vacuum`](Self::vacuum) can reclaim space.
+    pub fn is_incremental_vacuum(&self) -> Result<bool> {
+        // 2 is INCREMENTAL.
+        let auto_vacuum: i64 = self.conn.pragma_query_value(None, "auto_vacuum", |row| row.get(0))?;
+        Ok(auto_vacuum == 2)
+    }
+
+    /// Rewrites the whole database with a full VACUUM, switching it to
+    /// incremental auto-vacuum on the way, and reports how many bytes the file
+    /// shrank by. This takes as long as copying the file.
+    pub fn compact(&self) -> Result<u64> {
+        let before = file_size(&self.conn)?;
+        self.conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;")?;
+        Ok(before.saturating_sub(file_size(&self.conn)?))
+    }
+
+    /// Merges another bluetracker database, which has to be fully migrated, into
+    /// this one. Devices are matched by address, keeping the earliest first seen,
+    /// the latest last seen and the most recently heard name. Detections this
+    /// database already holds are skipped, so merging the same file twice adds nothing.
+    pub fn merge_database(&mut self, other_path: &str) -> Result<MergeSummary> {
+        // ATTACH would quietly create a missing file.
+        let other = std::fs::canonicalize(other_path)
+            .map_err(|e| BluetrackerError::Io(std::io::Error::new(e.kind(), format!("{}: {}", other_path, e))))?;
+        let main_path: String =
+            self.conn.query_row("SELECT file FROM pragma_database_list WHERE name = 'main'", [], |row| row.get(0))?;
+        if std::fs::canonicalize(&main_path).is_ok_and(|main| main == other) {
+            return Err(BluetrackerError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "cannot merge a database into itself",
+            )));
+        }
+
+        self.conn.execute("ATTACH DATABASE ?1 AS other", params![other_path])?;
+        let result = self.merge_attached(other_path);
+        self.conn.execute("DETACH DATABASE other", [])?;
+        result
+    }
+
+    fn merge_attached(&mut self, other_path: &str) -> Result<MergeSummary> {
+        let found: u32 =
+            self.conn.pragma_query_value(Some(DatabaseName::Attached("other")), "user_version", |row| row.get(0))?;
+        let expected = migrations::latest_version();
+        if found != expected {
+            return Err(BluetrackerError::SchemaVersion { path: other_path.to_string(), found, expected });
+        }
+
+        let transaction = self.conn.transaction()?;
+        let summary = merge_from_attached(&transaction)?;
+        transaction.commit()?;
+        Ok(summary)
+    }
+
+    /// Stores a discovered GATT table as a new snapshot of the device.
+    pub fn store_gatt_table(&mut self, address: &str, discovered_at: DateTime<Utc>, services: &[GattService]) -> Result<()> {
+        let transaction = self.conn.transaction()?;
+
+        transaction.execute(
+            "INSERT INTO gatt_snapshots (device_address, discovered_at) VALUES (?1, ?2)",
+            params![address, discovered_at.to_rfc3339()],
+        )?;
+        let snapshot_id = transaction.last_insert_rowid();
+
+        for service in services {
+            transaction.execute(
+                "INSERT INTO gatt_services (snapshot_id, uuid, is_primary) VALUES (?1, ?2, ?3)",
+                params![snapshot_id, service.uuid.to_string(), service.primary],
+            )?;
+            let service_id = transaction.last_insert_rowid();
+
+            for characteristic in &service.characteristics {
+                transaction.execute(
+                    "INSERT INTO gatt_characteristics (service_id, uuid, properties) VALUES (?1, ?2, ?3)",
+                    params![service_id, characteristic.uuid.to_string(), characteristic.properties.join(",")],
+                )?;
+                let characteristic_id = transaction.last_insert_rowid();
+
+                for descriptor in &characteristic.descriptors {
+                    transaction.execute(
+                        "INSERT INTO gatt_descriptors (characteristic_id, uuid) VALUES (?1, ?2)",
+                        params![characteristic_id, descriptor.uuid.to_string()],
+                    )?;
+                }
             }
         }
 
-        Ok(devices)
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// The most recently stored GATT table of a device and when it was discovered.
+    pub fn get_latest_gatt_table(&self, address: &str) -> Result<Option<(DateTime<Utc>, Vec<GattService>)>> {
+        let snapshot = self
+            .conn
+            .query_row(
+                "SELECT id, discovered_at FROM gatt_snapshots
+                 WHERE device_address = ? ORDER BY discovered_at DESC, id DESC LIMIT 1",
+                params![address],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, DateTime<Utc>>(1)?)),
+            )
+            .optional()?;
+        let Some((snapshot_id, discovered_at)) = snapshot else {
+            return Ok(None);
+        };
+
+        let mut service_stmt = self
+            .conn
+            .prepare("SELECT id, uuid, is_primary FROM gatt_services WHERE snapshot_id = ? ORDER BY id")?;
+        let mut characteristic_stmt = self
+            .conn
+            .prepare("SELECT id, uuid, properties FROM gatt_characteristics WHERE service_id = ? ORDER BY id")?;
+        let mut descriptor_stmt = self
+            .conn
+            .prepare("SELECT uuid FROM gatt_descriptors WHERE characteristic_id = ? ORDER BY rowid")?;
+
+        let rows = service_stmt.query_map(params![snapshot_id], |row| {
+            Ok((row.get::<_, i64>(0)?, parse_uuid(row, 1)?, row.get::<_, bool>(2)?))
+        })?;
+        let mut services = Vec::new();
+        for row in rows {
+            let (service_id, uuid, primary) = row?;
+            let rows = characteristic_stmt.query_map(params![service_id], |row| {
+                Ok((row.get::<_, i64>(0)?, parse_uuid(row, 1)?, row.get::<_, String>(2)?))
+            })?;
+
+            let mut characteristics = Vec::new();
+            for row in rows {
+                let (characteristic_id, uuid, properties) = row?;
+                let descriptors = descriptor_stmt
+                    .query_map(params![characteristic_id], |row| parse_uuid(row, 0))?
+                    .map(|uuid| uuid.map(|uuid| GattDescriptor { uuid, name: gatt::uuid_name(&uuid) }))
+                    .collect::<rusqlite::Result<_>>()?;
+                characteristics.push(GattCharacteristic {
+                    uuid,
+                    name: gatt::uuid_name(&uuid),
+                    properties: properties
+                        .split(',')
+                        .filter_map(gatt::parse_property)
+                        .map(|(_, name)| name)
+                        .collect(),
+                    descriptors,
+                });
+            }
+
+            services.push(GattService { uuid, name: gatt::uuid_name(&uuid), primary, characteristics });
+        }
+
+        Ok(Some((discovered_at, services)))
     }
+
+    /// Records the battery level and device information read from a device.
+    /// Profiles without either are not stored.
+    pub fn store_profile_snapshot(&self, address: &str, recorded_at: DateTime<Utc>, profile: &DeviceProfile) -> Result<bool> {
+        if profile.battery_level.is_none() && profile.device_information.is_none() {
+            return Ok(false);
+        }
+        let info = profile.device_information.clone().unwrap_or_default();
+        self.conn.execute(
+            "INSERT INTO device_info_snapshots (
+                device_address, recorded_at, battery_level, manufacturer_name, model_number,
+                serial_number, hardware_revision, firmware_revision, software_revision
+             ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                address,
+                recorded_at.to_rfc3339(),
+                profile.battery_level,
+                info.manufacturer_name,
+                info.model_number,
+                info.serial_number,
+                info.hardware_revision,
+                info.firmware_revision,
+                info.software_revision
+            ],
+        )?;
+        Ok(true)
+    }
+}
+
+/// Latitude bounds and longitude ranges of the box enclosing a circle. Longitude
+/// degrees shrink with cos(latitude); ranges crossing the antimeridian are split.
+fn bounding_box(latitude: f64, longitude: f64, radius_km: f64) -> (f64, f64, Vec<(f64, f64)>) {
+    const KM_PER_DEGREE: f64 = 111.32;
+
+    let lat_delta = radius_km / KM_PER_DEGREE;
+    let min_lat = (latitude - lat_delta).max(-90.0);
+    let max_lat = (latitude + lat_delta).min(90.0);
+
+    // Near the poles the circle can cover every longitude.
+    let widest_lat = min_lat.abs().max(max_lat.abs());
+    let cos_lat = widest_lat.to_radians().cos();
+    if max_lat >= 90.0 || min_lat <= -90.0 || cos_lat < 1e-6 {
+        return (min_lat, max_lat, vec![(-180.0, 180.0)]);
+    }
+    let lon_delta = radius_km / (KM_PER_DEGREE * cos_lat);
+    if lon_delta >= 180.0 {
+        return (min_lat, max_lat, vec![(-180.0, 180.0)]);
+    }
+
+    let (min_lon, max_lon) = (longitude - lon_delta, longitude + lon_delta);
+    let ranges = if min_lon < -180.0 {
+        vec![(min_lon + 360.0, 180.0), (-180.0, max_lon)]
+    } else if max_lon > 180.0 {
+        vec![(min_lon, 180.0), (-180.0, max_lon - 360.0)]
+    } else {
+        vec![(min_lon, max_lon)]
+    };
+    (min_lat, max_lat, ranges)
+}
+
+fn read_scan_session(row: &rusqlite::Row) -> rusqlite::Result<ScanSession> {
+    Ok(ScanSession {
+        id: row.get(0)?,
+        started_at: row.get(1)?,
+        ended_at: row.get(2)?,
+        adapter: row.get(3)?,
+        duration_secs: row.get(4)?,
+        filters: row.get(5)?,
+        latitude: row.get(6)?,
+        longitude: row.get(7)?,
+        label: row.get(8)?,
+        host: row.get(9)?,
+        detection_count: row.get(10)?,
+        device_count: row.get(11)?,
+        new_device_count: row.get(12)?,
+    })
+}
+
+fn insert_scan_data(conn: &Connection, scan_data: &DeviceScanData, session_id: Option<i64>) -> rusqlite::Result<()> {
+    let timestamp = scan_data.timestamp.to_rfc3339();
+
+    // Insert the device or fold this detection into it. A newer advertised name
+    // replaces the stored one, and a known manufacturer ID is never cleared.
+    conn.execute(
+        "INSERT INTO devices (address, name, manufacturer_id, first_seen, last_seen, detection_count)
+         VALUES (?1, COALESCE(?2, 'Unknown'), ?3, ?4, ?4, 1)
+         ON CONFLICT(address) DO UPDATE SET
+            name = CASE
+                WHEN ?2 IS NOT NULL AND (devices.last_seen IS NULL OR excluded.last_seen >= devices.last_seen)
+                THEN ?2 ELSE devices.name END,
+            manufacturer_id = COALESCE(excluded.manufacturer_id, devices.manufacturer_id),
+            first_seen = MIN(COALESCE(devices.first_seen, excluded.first_seen), excluded.first_seen),
+            last_seen = MAX(COALESCE(devices.last_seen, excluded.last_seen), excluded.last_seen),
+            detection_count = devices.detection_count + 1",
+        params![
+            scan_data.address,
+            scan_data.name,
+            get_manufacturer_id(&scan_data.manufacturer_data),
+            timestamp
+        ],
+    )?;
+
+    if let Some(name) = &scan_data.name {
+        conn.execute(
+            "INSERT INTO device_name_history (address, name, first_seen, last_seen)
+             VALUES (?1, ?2, ?3, ?3)
+             ON CONFLICT(address, name) DO UPDATE SET
+                first_seen = MIN(device_name_history.first_seen, excluded.first_seen),
+                last_seen = MAX(device_name_history.last_seen, excluded.last_seen)",
+            params![scan_data.address, name, timestamp],
+        )?;
+    }
+
+    // Insert detection
+    conn.execute(
+        "INSERT INTO detections (device_address, timestamp, latitude, longitude, rssi, tx_power, address_type, class, adapter, session_id, altitude, accuracy, hdop)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+        params![
+            scan_data.address,
+            timestamp,
+            scan_data.latitude,
+            scan_data.longitude,
+            scan_data.rssi,
+            scan_data.tx_power,
+            scan_data.address_type,
+            scan_data.class,
+            scan_data.adapter,
+            session_id,
+            scan_data.altitude,
+            scan_data.accuracy,
+            scan_data.hdop
+        ],
+    )?;
+    let detection_id = conn.last_insert_rowid();
+
+    insert_manufacturer_data(conn, detection_id, &scan_data.manufacturer_data)?;
+
+    let mut stmt = conn.prepare_cached("INSERT INTO detection_services (detection_id, uuid) VALUES (?1, ?2)")?;
+    for uuid in &scan_data.services {
+        stmt.execute(params![detection_id, uuid.to_string()])?;
+    }
+
+    let mut stmt = conn.prepare_cached("INSERT INTO service_data (detection_id, uuid, payload) VALUES (?1, ?2, ?3)")?;
+    for (uuid, payload) in &scan_data.service_data {
+        stmt.execute(params![detection_id, uuid.to_string(), payload])?;
+    }
+
+    Ok(())
+}
+
+/// Copies everything from the database attached as `other` that this one lacks.
+fn merge_from_attached(tx: &Transaction) -> rusqlite::Result<MergeSummary> {
+    let mut summary = MergeSummary::default();
+
+    // Sessions are the same run when they started at the same time on the same host and adapters.
+    let mut session_ids = HashMap::new();
+    let other_sessions: Vec<i64> = tx
+        .prepare("SELECT id FROM other.scan_sessions ORDER BY id")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    for other_id in other_sessions {
+        let existing: Option<i64> = tx
+            .query_row(
+                "SELECT s.id FROM main.scan_sessions s, other.scan_sessions o
+                 WHERE o.id = ?1 AND s.started_at = o.started_at AND s.host IS o.host AND s.adapter IS o.adapter",
+                params![other_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let session_id = match existing {
+            Some(id) => id,
+            None => {
+                tx.execute(
+                    "INSERT INTO main.scan_sessions
+                        (started_at, ended_at, adapter, duration_secs, filters, latitude, longitude, label, host)
+                     SELECT started_at, ended_at, adapter, duration_secs, filters, latitude, longitude, label, host
+                     FROM other.scan_sessions WHERE id = ?1",
+                    params![other_id],
+                )?;
+                summary.sessions_added += 1;
+                tx.last_insert_rowid()
+            }
+        };
+        session_ids.insert(other_id, session_id);
+    }
+
+    let new_devices: HashSet<String> = tx
+        .prepare("SELECT address FROM other.devices o WHERE NOT EXISTS (SELECT 1 FROM main.devices d WHERE d.address = o.address)")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    summary.devices_added = new_devices.len() as u32;
+
+    // Shared devices are remembered as they were, to tell which ones the merge changed.
+    tx.execute_batch(
+        "CREATE TEMP TABLE merge_devices AS
+         SELECT address, name, manufacturer_id, first_seen, last_seen, detection_count
+         FROM main.devices WHERE address IN (SELECT address FROM other.devices)",
+    )?;
+
+    // New devices bring their lifetime count, which may cover detections the
+    // other collector has pruned; shared devices are credited as detections are copied.
+    tx.execute(
+        "INSERT INTO main.devices (address, name, manufacturer_id, first_seen, last_seen, detection_count)
+         SELECT address, name, manufacturer_id, first_seen, last_seen, detection_count FROM other.devices WHERE true
+         ON CONFLICT(address) DO UPDATE SET
+            name = CASE
+                WHEN excluded.name IS NOT NULL AND excluded.name != 'Unknown'
+                     AND (devices.name IS NULL OR devices.name = 'Unknown' OR excluded.last_seen > devices.last_seen)
+                THEN excluded.name ELSE devices.name END,
+            manufacturer_id = COALESCE(devices.manufacturer_id, excluded.manufacturer_id),
+            first_seen = MIN(COALESCE(devices.first_seen, excluded.first_seen),
+                             COALESCE(excluded.first_seen, devices.first_seen)),
+            last_seen = MAX(COALESCE(devices.last_seen, excluded.last_seen),
+                            COALESCE(excluded.last_seen, devices.last_seen))",
+        [],
+    )?;
+
+    tx.execute(
+        "INSERT INTO main.device_name_history (address, name, first_seen, last_seen)
+         SELECT address, name, first_seen, last_seen FROM other.device_name_history WHERE true
+         ON CONFLICT(address, name) DO UPDATE SET
+            first_seen = MIN(device_name_history.first_seen, excluded.first_seen),
+            last_seen = MAX(device_name_history.last_seen, excluded.last_seen)",
+        [],
+    )?;
+
+    // Aggregates only stand in for detections this database held before the merge.
+    let last_detection_id: i64 = tx.query_row("SELECT COALESCE(MAX(id), 0) FROM main.detections", [], |row| row.get(0))?;
+
+    let other_detections: Vec<(i64, String, Option<i64>)> = tx
+        .prepare("SELECT id, device_address, session_id FROM other.detections ORDER BY id")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    let mut is_duplicate = tx.prepare(
+        "SELECT EXISTS(
+            SELECT 1 FROM main.detections d, other.detections o
+            WHERE o.id = ?1 AND d.device_address = o.device_address AND d.timestamp = o.timestamp
+              AND d.rssi IS o.rssi AND d.latitude IS o.latitude AND d.longitude IS o.longitude
+              AND d.adapter IS o.adapter
+         )",
+    )?;
+    // A detection this database has already downsampled would be counted twice.
+    let mut is_summarised = tx.prepare(
+        "SELECT EXISTS(
+            SELECT 1 FROM main.detection_aggregates a, other.detections o
+            WHERE o.id = ?1 AND a.device_address = o.device_address
+              AND a.hour = strftime('%Y-%m-%dT%H:00:00Z', o.timestamp) AND a.session_id = COALESCE(?2, 0)
+         )",
+    )?;
+    let mut insert_detection = tx.prepare(
+        "INSERT INTO main.detections (device_address, timestamp, latitude, longitude, rssi, tx_power, manufacturer_data,
+                                      address_type, class, adapter, session_id, altitude, accuracy, hdop)
+         SELECT device_address, timestamp, latitude, longitude, rssi, tx_power, manufacturer_data,
+                address_type, class, adapter, ?2, altitude, accuracy, hdop
+         FROM other.detections WHERE id = ?1",
+    )?;
+    let mut copy_manufacturer_data = tx.prepare(
+        "INSERT INTO main.manufacturer_data (detection_id, company_id, payload)
+         SELECT ?1, company_id, payload FROM other.manufacturer_data WHERE detection_id = ?2",
+    )?;
+    let mut copy_services = tx.prepare(
+        "INSERT INTO main.detection_services (detection_id, uuid)
+         SELECT ?1, uuid FROM other.detection_services WHERE detection_id = ?2",
+    )?;
+    let mut copy_service_data = tx.prepare(
+        "INSERT INTO main.service_data (detection_id, uuid, payload)
+         SELECT ?1, uuid, payload FROM other.service_data WHERE detection_id = ?2",
+    )?;
+
+    let mut added_per_device: HashMap<String, u32> = HashMap::new();
+    for (other_id, address, session_id) in other_detections {
+        let session_id = session_id.and_then(|id| session_ids.get(&id).copied());
+        if is_duplicate.query_row(params![other_id], |row| row.get(0))?
+            || is_summarised.query_row(params![other_id, session_id], |row| row.get(0))?
+        {
+            summary.duplicate_detections += 1;
+            continue;
+        }
+        insert_detection.execute(params![other_id, session_id])?;
+        let detection_id = tx.last_insert_rowid();
+        copy_manufacturer_data.execute(params![detection_id, other_id])?;
+        copy_services.execute(params![detection_id, other_id])?;
+        copy_service_data.execute(params![detection_id, other_id])?;
+
+        summary.detections_added += 1;
+        *added_per_device.entry(address).or_default() += 1;
+    }
+
+    let mut add_count = tx.prepare("UPDATE main.devices SET detection_count = detection_count + ?2 WHERE address = ?1")?;
+    let mut cover_count =
+        tx.prepare("UPDATE main.devices SET detection_count = MAX(detection_count, ?2) WHERE address = ?1")?;
+    for (address, added) in added_per_device {
+        if new_devices.contains(&address) {
+            cover_count.execute(params![address, added])?;
+        } else {
+            add_count.execute(params![address, added])?;
+        }
+    }
+
+    summary.devices_updated = tx.query_row(
+        "SELECT COUNT(*) FROM temp.merge_devices b JOIN main.devices d ON d.address = b.address
+         WHERE d.name IS NOT b.name OR d.manufacturer_id IS NOT b.manufacturer_id
+            OR d.first_seen IS NOT b.first_seen OR d.last_seen IS NOT b.last_seen
+            OR d.detection_count IS NOT b.detection_count",
+        [],
+        |row| row.get(0),
+    )?;
+    tx.execute("DROP TABLE temp.merge_devices", [])?;
+
+    summary.snapshots_added += tx.execute(
+        "INSERT INTO main.device_info_snapshots (device_address, recorded_at, battery_level, manufacturer_name,
+                                                 model_number, serial_number, hardware_revision,
+                                                 firmware_revision, software_revision)
+         SELECT device_address, recorded_at, battery_level, manufacturer_name, model_number, serial_number,
+                hardware_revision, firmware_revision, software_revision
+         FROM other.device_info_snapshots o
+         WHERE NOT EXISTS (SELECT 1 FROM main.device_info_snapshots s
+                           WHERE s.device_address = o.device_address AND s.recorded_at = o.recorded_at)",
+        [],
+    )? as u32;
+
+    // Aggregates are keyed by device, hour and session. One that is already
+    // stored summarises the same detections, e.g. when both collectors
+    // downsampled a session they had exchanged before, so it is skipped, as is
+    // one whose detections this database still holds.
+    tx.execute("CREATE TEMP TABLE merge_sessions (other_id INTEGER PRIMARY KEY, main_id INTEGER NOT NULL)", [])?;
+    for (other_id, main_id) in &session_ids {
+        tx.prepare_cached("INSERT INTO temp.merge_sessions (other_id, main_id) VALUES (?1, ?2)")?
+            .execute(params![other_id, main_id])?;
+    }
+    tx.execute(
+        "INSERT INTO main.detection_aggregates
+            (device_address, hour, session_id, detection_count, rssi_count, min_rssi, max_rssi, mean_rssi,
+             located_count, latitude, longitude)
+         SELECT o.device_address, o.hour, COALESCE(m.main_id, 0), o.detection_count, o.rssi_count, o.min_rssi,
+                o.max_rssi, o.mean_rssi, o.located_count, o.latitude, o.longitude
+         FROM other.detection_aggregates o LEFT JOIN temp.merge_sessions m ON m.other_id = o.session_id
+         WHERE NOT EXISTS (
+            SELECT 1 FROM main.detections d
+            WHERE d.id <= ?1 AND d.device_address = o.device_address
+              AND strftime('%Y-%m-%dT%H:00:00Z', d.timestamp) = o.hour
+              AND COALESCE(d.session_id, 0) = COALESCE(m.main_id, 0)
+         )
+         ON CONFLICT(device_address, hour, session_id) DO NOTHING",
+        params![last_detection_id],
+    )?;
+    tx.execute("DROP TABLE temp.merge_sessions", [])?;
+
+    let other_gatt_snapshots: Vec<i64> = tx
+        .prepare(
+            "SELECT id FROM other.gatt_snapshots o
+             WHERE NOT EXISTS (SELECT 1 FROM main.gatt_snapshots s
+                               WHERE s.device_address = o.device_address AND s.discovered_at = o.discovered_at)
+             ORDER BY id",
+        )?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    for other_id in other_gatt_snapshots {
+        copy_gatt_snapshot(tx, other_id)?;
+        summary.snapshots_added += 1;
+    }
+
+    Ok(summary)
+}
+
+/// Copies a GATT snapshot from the attached `other` database with its services,
+/// characteristics and descriptors.
+fn copy_gatt_snapshot(tx: &Transaction, other_id: i64) -> rusqlite::Result<()> {
+    let child_ids = |sql: &str, parent_id: i64| -> rusqlite::Result<Vec<i64>> {
+        tx.prepare_cached(sql)?.query_map(params![parent_id], |row| row.get(0))?.collect()
+    };
+
+    tx.execute(
+        "INSERT INTO main.gatt_snapshots (device_address, discovered_at)
+         SELECT device_address, discovered_at FROM other.gatt_snapshots WHERE id = ?1",
+        params![other_id],
+    )?;
+    let snapshot_id = tx.last_insert_rowid();
+
+    for service_id in child_ids("SELECT id FROM other.gatt_services WHERE snapshot_id = ?1 ORDER BY id", other_id)? {
+        tx.execute(
+            "INSERT INTO main.gatt_services (snapshot_id, uuid, is_primary)
+             SELECT ?1, uuid, is_primary FROM other.gatt_services WHERE id = ?2",
+            params![snapshot_id, service_id],
+        )?;
+        let new_service_id = tx.last_insert_rowid();
+
+        let characteristics =
+            child_ids("SELECT id FROM other.gatt_characteristics WHERE service_id = ?1 ORDER BY id", service_id)?;
+        for characteristic_id in characteristics {
+            tx.execute(
+                "INSERT INTO main.gatt_characteristics (service_id, uuid, properties)
+                 SELECT ?1, uuid, properties FROM other.gatt_characteristics WHERE id = ?2",
+                params![new_service_id, characteristic_id],
+            )?;
+            tx.execute(
+                "INSERT INTO main.gatt_descriptors (characteristic_id, uuid)
+                 SELECT ?1, uuid FROM other.gatt_descriptors WHERE characteristic_id = ?2",
+                params![tx.last_insert_rowid(), characteristic_id],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn insert_manufacturer_data(conn: &Connection, detection_id: i64, manufacturer_data: &HashMap<u16, Vec<u8>>) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(
+        "INSERT INTO manufacturer_data (detection_id, company_id, payload) VALUES (?1, ?2, ?3)",
+    )?;
+    for (company_id, payload) in manufacturer_data {
+        stmt.execute(params![detection_id, company_id, payload])?;
+    }
+    Ok(())
+}
+
+fn load_manufacturer_data(conn: &Connection, detection_id: i64) -> rusqlite::Result<HashMap<u16, Vec<u8>>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT company_id, payload FROM manufacturer_data WHERE detection_id = ?",
+    )?;
+    let rows = stmt.query_map(params![detection_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+fn load_services(conn: &Connection, detection_id: i64) -> rusqlite::Result<Vec<Uuid>> {
+    let mut stmt = conn.prepare_cached("SELECT uuid FROM detection_services WHERE detection_id = ?")?;
+    let rows = stmt.query_map(params![detection_id], |row| parse_uuid(row, 0))?;
+    rows.collect()
+}
+
+fn load_service_data(conn: &Connection, detection_id: i64) -> rusqlite::Result<HashMap<Uuid, Vec<u8>>> {
+    let mut stmt = conn.prepare_cached("SELECT uuid, payload FROM service_data WHERE detection_id = ?")?;
+    let rows = stmt.query_map(params![detection_id], |row| Ok((parse_uuid(row, 0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+fn parse_uuid(row: &rusqlite::Row, index: usize) -> rusqlite::Result<Uuid> {
+    let value: String = row.get(index)?;
+    Uuid::parse_str(&value).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e))
+    })
 }
 
 pub fn get_db_path(provided_path: Option<String>) -> String {
@@ -294,3 +1635,295 @@ pub fn get_db_path(provided_path: Option<String>) -> String {
         format!("{}/.bluetracker/bluetooth_devices.db", home)
     })
 }
+
+fn file_size(conn: &Connection) -> rusqlite::Result<u64> {
+    let pages: u64 = conn.pragma_query_value(None, "page_count", |row| row.get(0))?;
+    let page_size: u64 = conn.pragma_query_value(None, "page_size", |row| row.get(0))?;
+    Ok(pages * page_size)
+}
+
+/// Registers `REGEXP` so queries can match names with `name REGEXP ?`. The
+/// compiled pattern is cached by SQLite for the duration of a statement.
+fn add_regexp_function(conn: &Connection) -> rusqlite::Result<()> {
+    conn.create_scalar_function(
+        "regexp",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let regex: Arc<Regex> = ctx.get_or_create_aux(0, |pattern| {
+                Regex::new(pattern.as_str()?).map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+            })?;
+            let is_match = match ctx.get_raw(1).as_str_or_null() {
+                Ok(Some(text)) => regex.is_match(text),
+                _ => false,
+            };
+            Ok(is_match)
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use tempfile::NamedTempFile;
+
+    fn open(file: &NamedTempFile) -> BluetoothTracker {
+        BluetoothTracker::new(&path(file)).unwrap()
+    }
+
+    fn path(file: &NamedTempFile) -> String {
+        file.path().to_string_lossy().into_owned()
+    }
+
+    fn detection(address: &str, timestamp: DateTime<Utc>, rssi: i32, latitude: f64) -> DeviceScanData {
+        DeviceScanData {
+            timestamp,
+            name: Some(format!("Device {}", &address[15..])),
+            address: address.to_string(),
+            address_type: None,
+            rssi: Some(rssi),
+            tx_power: None,
+            manufacturer_data: HashMap::from([(76, vec![2, 21])]),
+            services: Vec::new(),
+            service_data: HashMap::new(),
+            class: None,
+            latitude: Some(latitude),
+            longitude: Some(4.0),
+            altitude: None,
+            accuracy: None,
+            hdop: None,
+            adapter: Some("hci0".to_string()),
+        }
+    }
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, hour, minute, 0).unwrap()
+    }
+
+    /// Stores the detections under a new session started at `started_at`.
+    fn store_session(tracker: &mut BluetoothTracker, started_at: DateTime<Utc>, detections: &[DeviceScanData]) {
+        let session_id = tracker
+            .start_scan_session(&NewScanSession {
+                started_at,
+                adapter: Some("hci0".to_string()),
+                duration_secs: None,
+                filters: None,
+                latitude: None,
+                longitude: None,
+                label: None,
+                host: Some("collector".to_string()),
+            })
+            .unwrap();
+        tracker.store_session_scan_data(session_id, detections).unwrap();
+        tracker.finish_scan_session(session_id, started_at).unwrap();
+    }
+
+    fn downsample_all() -> RetentionPolicy {
+        RetentionPolicy { older_than: None, keep_per_device: Some(0), downsample: true }
+    }
+
+    #[test]
+    fn bounding_box_splits_at_the_antimeridian() {
+        let (min_lat, max_lat, ranges) = bounding_box(0.0, 179.9, 50.0);
+        assert!((max_lat - 50.0 / 111.32).abs() < 1e-9 && (min_lat + max_lat).abs() < 1e-9);
+        assert_eq!(ranges.len(), 2);
+        assert!(ranges[0].0 < 179.9 && ranges[0].1 == 180.0);
+        assert!(ranges[1].0 == -180.0 && ranges[1].1 > -180.0 && ranges[1].1 < -179.0);
+
+        let (_, _, ranges) = bounding_box(0.0, -179.9, 50.0);
+        assert_eq!(ranges.len(), 2);
+        assert!(ranges[0].0 > 179.0 && ranges[0].1 == 180.0);
+        assert!(ranges[1].0 == -180.0 && ranges[1].1 > -179.9);
+
+        assert_eq!(bounding_box(10.0, 20.0, 50.0).2.len(), 1);
+        assert_eq!(bounding_box(89.9, 20.0, 50.0).2, vec![(-180.0, 180.0)]);
+    }
+
+    #[test]
+    fn prune_downsamples_into_hourly_aggregates() {
+        let db = NamedTempFile::new().unwrap();
+        let mut tracker = open(&db);
+        let address = "AA:BB:CC:DD:EE:01";
+        tracker
+            .store_scan_data_batch(&[
+                detection(address, at(10, 5), -60, 51.0),
+                detection(address, at(10, 20), -70, 52.0),
+                detection(address, at(10, 40), -80, 53.0),
+                detection(address, at(11, 5), -50, 51.0),
+            ])
+            .unwrap();
+        let policy = RetentionPolicy { older_than: None, keep_per_device: Some(1), downsample: true };
+
+        let dry_run = tracker.prune(&policy, true).unwrap();
+        assert_eq!((dry_run.detections_removed, dry_run.devices_affected), (3, 1));
+        assert_eq!(tracker.get_detection_records().unwrap().len(), 4);
+        assert!(tracker.get_aggregate_records().unwrap().is_empty());
+
+        let summary = tracker.prune(&policy, false).unwrap();
+        assert_eq!((summary.detections_removed, summary.aggregates_written), (3, 1));
+        let remaining = tracker.get_detection_records().unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].detection.timestamp, at(11, 5));
+
+        let aggregates = tracker.get_aggregate_records().unwrap();
+        assert_eq!(aggregates.len(), 1);
+        assert_eq!(aggregates[0].hour, at(10, 0));
+        assert_eq!(aggregates[0].session_id, None);
+        assert_eq!(aggregates[0].detection_count, 3);
+        assert_eq!((aggregates[0].min_rssi, aggregates[0].max_rssi), (Some(-80), Some(-60)));
+        assert_eq!(aggregates[0].mean_rssi, Some(-70.0));
+        assert_eq!(aggregates[0].latitude, Some(52.0));
+
+        // The device keeps its lifetime count and stays listed.
+        let devices = tracker.get_devices(&DeviceQuery::default()).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].detection_count, 4);
+    }
+
+    #[test]
+    fn pruned_device_is_still_listed_and_located() {
+        let db = NamedTempFile::new().unwrap();
+        let mut tracker = open(&db);
+        let address = "AA:BB:CC:DD:EE:01";
+        tracker.store_scan_data_batch(&[detection(address, at(10, 5), -60, 51.0)]).unwrap();
+        tracker.prune(&downsample_all(), false).unwrap();
+
+        assert!(tracker.get_detection_records().unwrap().is_empty());
+        let devices = tracker.get_devices(&DeviceQuery::default()).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].strongest_rssi, Some(-60));
+        let estimate = tracker.estimate_device_location(address, None, &EstimatorOptions::default()).unwrap();
+        assert_eq!(estimate.map(|estimate| estimate.latitude), Some(51.0));
+    }
+
+    #[test]
+    fn vacuum_only_reclaims_space_after_compacting() {
+        let db = NamedTempFile::new().unwrap();
+        let mut tracker = open(&db);
+        let detections: Vec<_> =
+            (0..2000).map(|i| detection("AA:BB:CC:DD:EE:01", at(10, 0) + chrono::Duration::seconds(i), -60, 51.0)).collect();
+        tracker.store_scan_data_batch(&detections).unwrap();
+        assert!(!tracker.is_incremental_vacuum().unwrap());
+
+        tracker.prune(&RetentionPolicy { downsample: false, ..downsample_all() }, false).unwrap();
+        assert_eq!(tracker.vacuum().unwrap(), 0);
+        assert!(tracker.compact().unwrap() > 0);
+        assert!(tracker.is_incremental_vacuum().unwrap());
+
+        tracker.store_scan_data_batch(&detections).unwrap();
+        tracker.prune(&RetentionPolicy { downsample: false, ..downsample_all() }, false).unwrap();
+        assert!(tracker.vacuum().unwrap() > 0);
+    }
+
+    #[test]
+    fn merge_copies_and_deduplicates() {
+        let (source, target) = (NamedTempFile::new().unwrap(), NamedTempFile::new().unwrap());
+        let mut other = open(&source);
+        store_session(
+            &mut other,
+            at(10, 0),
+            &[detection("AA:BB:CC:DD:EE:01", at(10, 5), -60, 51.0), detection("AA:BB:CC:DD:EE:02", at(10, 6), -70, 51.0)],
+        );
+        drop(other);
+
+        let mut tracker = open(&target);
+        tracker.store_scan_data_batch(&[detection("AA:BB:CC:DD:EE:01", at(9, 0), -65, 51.0)]).unwrap();
+
+        let summary = tracker.merge_database(&path(&source)).unwrap();
+        assert_eq!((summary.devices_added, summary.devices_updated), (1, 1));
+        assert_eq!((summary.detections_added, summary.duplicate_detections), (2, 0));
+        assert_eq!(summary.sessions_added, 1);
+
+        let devices = tracker.get_device_records().unwrap();
+        let shared = devices.iter().find(|device| device.address == "AA:BB:CC:DD:EE:01").unwrap();
+        assert_eq!(shared.detection_count, 2);
+        assert_eq!((shared.first_seen, shared.last_seen), (Some(at(9, 0)), Some(at(10, 5))));
+        let copied = tracker.get_detection_records().unwrap();
+        assert!(copied.iter().all(|record| record.detection.manufacturer_data.get(&76) == Some(&vec![2, 21])));
+
+        // Merging the same file again changes nothing.
+        let again = tracker.merge_database(&path(&source)).unwrap();
+        assert_eq!((again.devices_added, again.devices_updated, again.detections_added), (0, 0, 0));
+        assert_eq!((again.duplicate_detections, again.sessions_added), (2, 0));
+
+        assert!(tracker.merge_database(&path(&target)).is_err());
+    }
+
+    #[test]
+    fn merge_skips_aggregates_of_sessions_downsampled_on_both_sides() {
+        let (source, target) = (NamedTempFile::new().unwrap(), NamedTempFile::new().unwrap());
+        let mut other = open(&source);
+        store_session(
+            &mut other,
+            at(10, 0),
+            &[detection("AA:BB:CC:DD:EE:01", at(10, 5), -60, 51.0), detection("AA:BB:CC:DD:EE:01", at(10, 6), -70, 51.0)],
+        );
+
+        // Both collectors downsample the same session before merging again.
+        let mut tracker = open(&target);
+        tracker.merge_database(&path(&source)).unwrap();
+        other.prune(&downsample_all(), false).unwrap();
+        tracker.prune(&downsample_all(), false).unwrap();
+        drop(other);
+        tracker.merge_database(&path(&source)).unwrap();
+
+        let aggregates = tracker.get_aggregate_records().unwrap();
+        assert_eq!(aggregates.len(), 1);
+        assert_eq!(aggregates[0].detection_count, 2);
+        assert!(aggregates[0].session_id.is_some());
+    }
+
+    #[test]
+    fn merge_counts_detections_once_when_only_one_side_downsampled_them() {
+        let (source, target) = (NamedTempFile::new().unwrap(), NamedTempFile::new().unwrap());
+        let address = "AA:BB:CC:DD:EE:01";
+        let mut other = open(&source);
+        store_session(
+            &mut other,
+            at(10, 0),
+            &[detection(address, at(10, 5), -60, 51.0), detection(address, at(10, 6), -70, 51.2)],
+        );
+        let mut tracker = open(&target);
+        tracker.merge_database(&path(&source)).unwrap();
+        let located_at = |tracker: &BluetoothTracker, locations: u32| {
+            let query = DeviceQuery { min_locations: Some(locations), ..DeviceQuery::default() };
+            tracker.get_devices(&query).unwrap().len()
+        };
+
+        // The other collector downsampled the session this one still holds.
+        other.prune(&downsample_all(), false).unwrap();
+        tracker.merge_database(&path(&source)).unwrap();
+        assert!(tracker.get_aggregate_records().unwrap().is_empty());
+        let devices = tracker.get_devices(&DeviceQuery::default()).unwrap();
+        assert_eq!((devices[0].detection_count, devices[0].strongest_rssi), (2, Some(-60)));
+        assert_eq!((located_at(&tracker, 2), located_at(&tracker, 3)), (1, 0));
+
+        // And the other way round: the downsampled collector merges the raw detections.
+        let summary = other.merge_database(&path(&target)).unwrap();
+        assert_eq!((summary.detections_added, summary.duplicate_detections), (0, 2));
+        assert!(other.get_detection_records().unwrap().is_empty());
+        let devices = other.get_devices(&DeviceQuery::default()).unwrap();
+        assert_eq!((devices[0].detection_count, devices[0].strongest_rssi), (2, Some(-60)));
+        assert_eq!((located_at(&other, 1), located_at(&other, 2)), (1, 0));
+    }
+
+    #[test]
+    fn merge_keeps_the_lifetime_count_of_pruned_devices() {
+        let (source, target) = (NamedTempFile::new().unwrap(), NamedTempFile::new().unwrap());
+        let mut other = open(&source);
+        store_session(
+            &mut other,
+            at(10, 0),
+            &[detection("AA:BB:CC:DD:EE:01", at(10, 5), -60, 51.0), detection("AA:BB:CC:DD:EE:01", at(10, 6), -70, 51.0)],
+        );
+        other.prune(&RetentionPolicy { downsample: false, ..downsample_all() }, false).unwrap();
+        drop(other);
+
+        // A new device brings its lifetime count although its detections are gone.
+        let mut tracker = open(&target);
+        let summary = tracker.merge_database(&path(&source)).unwrap();
+        assert_eq!((summary.devices_added, summary.detections_added), (1, 0));
+        assert_eq!(tracker.get_device_records().unwrap()[0].detection_count, 2);
+    }
+}
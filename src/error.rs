@@ -0,0 +1,60 @@
+use thiserror::Error;
+
+/// Errors returned by the bluetracker library.
+#[derive(Debug, Error)]
+pub enum BluetrackerError {
+    #[error("No Bluetooth adapters found")]
+    NoAdapter,
+
+    #[error("No Bluetooth adapter matches {0}")]
+    AdapterNotFound(String),
+
+    #[error("Device {0} not found")]
+    DeviceNotFound(String),
+
+    #[error("Characteristic {uuid} not found on {address}")]
+    CharacteristicNotFound { address: String, uuid: String },
+
+    #[error("Failed to connect to {address} after {attempts} attempts: {source}")]
+    ConnectFailed {
+        address: String,
+        attempts: u32,
+        #[source]
+        source: btleplug::Error,
+    },
+
+    #[error("Gave up on {address} after {after:?}")]
+    TimedOut { address: String, after: std::time::Duration },
+
+    #[error("Bluetooth error: {0}")]
+    Bluetooth(#[from] btleplug::Error),
+
+    #[error("Database error: {0}")]
+    Db(#[from] rusqlite::Error),
+
+    #[error("{path} is at schema version {found} but version {expected} is needed; run `db migrate` on it first")]
+    SchemaVersion { path: String, found: u32, expected: u32 },
+
+    #[error("{0}")]
+    Usage(String),
+
+    #[error("Parse error: {0}")]
+    Parse(String),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("YAML error: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+
+    #[error("XML error: {0}")]
+    Xml(#[from] quick_xml::Error),
+}
+
+pub type Result<T> = std::result::Result<T, BluetrackerError>;
@@ -0,0 +1,244 @@
+use serde::Serialize;
+use std::f64::consts::PI;
+
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+/// RSSI assumed for detections that did not report one.
+const WEAKEST_RSSI: f64 = -100.0;
+/// Typical received power at 1 m for BLE devices that do not advertise a TX power.
+const DEFAULT_MEASURED_POWER: f64 = -59.0;
+/// Free-space loss at 1 m for 2.4 GHz, used to turn an advertised TX power into the power at 1 m.
+const LOSS_AT_ONE_METER: f64 = 41.0;
+
+/// A geotagged detection contributing to an estimate.
+#[derive(Debug, Clone)]
+pub struct Observation {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub rssi: Option<i32>,
+    pub tx_power: Option<i32>,
+    /// HDOP of the GPS fix the position came from, when known.
+    pub hdop: Option<f64>,
+    /// Seconds between the detection and the time of the estimate.
+    pub age_secs: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EstimateMethod {
+    WeightedCentroid,
+    Trilateration,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LocationEstimate {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Radius in meters the device is likely to be within.
+    pub uncertainty_m: f64,
+    /// Number of detections that contributed to the estimate.
+    pub detections: usize,
+    pub method: EstimateMethod,
+}
+
+#[derive(Debug, Clone)]
+pub struct EstimatorOptions {
+    /// Age in seconds after which a detection counts half as much.
+    pub recency_half_life_secs: f64,
+    /// Log-distance path-loss exponent (2.0 is free space, 2.5-4 indoors).
+    pub path_loss_exponent: f64,
+    /// Refine the centroid by trilaterating path-loss distances.
+    pub trilaterate: bool,
+}
+
+impl Default for EstimatorOptions {
+    fn default() -> Self {
+        Self {
+            recency_half_life_secs: 24.0 * 3600.0,
+            path_loss_exponent: 2.5,
+            trilaterate: false,
+        }
+    }
+}
+
+/// Estimates where a device is from the places it was heard. Stronger and more
+/// recent detections weigh more. With `trilaterate`, the weighted centroid is used
+/// as the starting point of a least-squares fit of path-loss distances, falling
+/// back to the centroid when the geometry does not allow a fix.
+pub fn estimate_location(observations: &[Observation], options: &EstimatorOptions) -> Option<LocationEstimate> {
+    if observations.is_empty() {
+        return None;
+    }
+
+    // Work in a local east/north plane in meters around the first observation.
+    let origin = (observations[0].latitude, observations[0].longitude);
+    let points: Vec<(f64, f64)> = observations.iter().map(|o| to_local(origin, o.latitude, o.longitude)).collect();
+    // Weights span many orders of magnitude (weak or months-old detections would
+    // underflow to zero), so they are computed in log space relative to the largest.
+    let log_weights: Vec<f64> = observations.iter().map(|o| log_weight(o, options)).collect();
+    let max_log_weight = log_weights.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let weights: Vec<f64> = log_weights.iter().map(|w| (w - max_log_weight).exp()).collect();
+    let ranges: Vec<f64> = observations
+        .iter()
+        .map(|o| path_loss_distance(o, options.path_loss_exponent))
+        .collect();
+    let total_weight: f64 = weights.iter().sum();
+    if !total_weight.is_finite() || total_weight <= 0.0 {
+        return None;
+    }
+
+    let centroid = (
+        points.iter().zip(&weights).map(|(p, w)| p.0 * w).sum::<f64>() / total_weight,
+        points.iter().zip(&weights).map(|(p, w)| p.1 * w).sum::<f64>() / total_weight,
+    );
+
+    if options.trilaterate {
+        if let Some((position, residual)) = trilaterate(&points, &ranges, &weights, centroid) {
+            let (latitude, longitude) = from_local(origin, position);
+            return Some(LocationEstimate {
+                latitude,
+                longitude,
+                uncertainty_m: residual,
+                detections: observations.len(),
+                method: EstimateMethod::Trilateration,
+            });
+        }
+    }
+
+    // The device was within roughly `range` of each collector position, so the
+    // spread of positions and the ranges both widen the uncertainty.
+    let spread = points
+        .iter()
+        .zip(&ranges)
+        .zip(&weights)
+        .map(|((p, r), w)| w * (distance(*p, centroid).powi(2) + r.powi(2)))
+        .sum::<f64>()
+        / total_weight;
+
+    let (latitude, longitude) = from_local(origin, centroid);
+    Some(LocationEstimate {
+        latitude,
+        longitude,
+        uncertainty_m: spread.sqrt(),
+        detections: observations.len(),
+        method: EstimateMethod::WeightedCentroid,
+    })
+}
+
+/// Natural log of `10^(rssi/20) * 0.5^(age/half_life) / hdop^2`.
+fn log_weight(observation: &Observation, options: &EstimatorOptions) -> f64 {
+    let rssi = observation.rssi.map_or(WEAKEST_RSSI, f64::from);
+    let signal = rssi / 20.0 * 10f64.ln();
+    let recency = observation.age_secs.max(0.0) / options.recency_half_life_secs * 0.5f64.ln();
+    // Position error grows with HDOP, so poor fixes count by the inverse of its square.
+    let precision = observation.hdop.map_or(0.0, |hdop| -2.0 * hdop.max(1.0).ln());
+    signal + recency + precision
+}
+
+/// Distance in meters implied by the log-distance path-loss model.
+pub fn path_loss_distance(observation: &Observation, exponent: f64) -> f64 {
+    let measured_power = observation
+        .tx_power
+        .map_or(DEFAULT_MEASURED_POWER, |tx| f64::from(tx) - LOSS_AT_ONE_METER);
+    let rssi = observation.rssi.map_or(WEAKEST_RSSI, f64::from);
+    10f64.powf((measured_power - rssi) / (10.0 * exponent))
+}
+
+/// Gauss-Newton fit of the point whose distances to `points` best match `ranges`.
+/// Returns the position and the weighted RMS range residual, or `None` when there
+/// are too few points or they are collinear.
+fn trilaterate(points: &[(f64, f64)], ranges: &[f64], weights: &[f64], start: (f64, f64)) -> Option<((f64, f64), f64)> {
+    if points.len() < 3 {
+        return None;
+    }
+
+    let mut position = start;
+    for _ in 0..50 {
+        let (mut a11, mut a12, mut a22, mut b1, mut b2) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for ((p, r), w) in points.iter().zip(ranges).zip(weights) {
+            let d = distance(position, *p).max(1e-6);
+            let jx = (position.0 - p.0) / d;
+            let jy = (position.1 - p.1) / d;
+            let residual = d - r;
+            a11 += w * jx * jx;
+            a12 += w * jx * jy;
+            a22 += w * jy * jy;
+            b1 += w * jx * residual;
+            b2 += w * jy * residual;
+        }
+
+        let det = a11 * a22 - a12 * a12;
+        if det.abs() < 1e-12 * (a11 * a22).max(1e-300) {
+            return None;
+        }
+        let dx = (a22 * b1 - a12 * b2) / det;
+        let dy = (a11 * b2 - a12 * b1) / det;
+        position = (position.0 - dx, position.1 - dy);
+        if dx.hypot(dy) < 0.01 {
+            break;
+        }
+    }
+
+    if !position.0.is_finite() || !position.1.is_finite() {
+        return None;
+    }
+
+    let total_weight: f64 = weights.iter().sum();
+    let residual = points
+        .iter()
+        .zip(ranges)
+        .zip(weights)
+        .map(|((p, r), w)| w * (distance(position, *p) - r).powi(2))
+        .sum::<f64>()
+        / total_weight;
+    Some((position, residual.sqrt()))
+}
+
+fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    (a.0 - b.0).hypot(a.1 - b.1)
+}
+
+fn to_local(origin: (f64, f64), latitude: f64, longitude: f64) -> (f64, f64) {
+    let meters_per_degree = EARTH_RADIUS_M * PI / 180.0;
+    let east = normalize_longitude(longitude - origin.1) * meters_per_degree * (origin.0 * PI / 180.0).cos();
+    let north = (latitude - origin.0) * meters_per_degree;
+    (east, north)
+}
+
+fn from_local(origin: (f64, f64), point: (f64, f64)) -> (f64, f64) {
+    let meters_per_degree = EARTH_RADIUS_M * PI / 180.0;
+    let latitude = origin.0 + point.1 / meters_per_degree;
+    let longitude = origin.1 + point.0 / (meters_per_degree * (origin.0 * PI / 180.0).cos());
+    (latitude, normalize_longitude(longitude))
+}
+
+/// Wraps a longitude or longitude difference into [-180, 180], so points on
+/// both sides of the antimeridian stay close together.
+fn normalize_longitude(longitude: f64) -> f64 {
+    (longitude + 180.0).rem_euclid(360.0) - 180.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observation(latitude: f64, longitude: f64, rssi: i32, age_secs: f64) -> Observation {
+        Observation { latitude, longitude, rssi: Some(rssi), tx_power: None, hdop: None, age_secs }
+    }
+
+    #[test]
+    fn old_weak_detections_still_give_an_estimate() {
+        let options = EstimatorOptions::default();
+        let observations = [observation(51.0, 4.0, -100, 1e9), observation(51.001, 4.0, -100, 1e9)];
+        let estimate = estimate_location(&observations, &options).unwrap();
+        assert!(estimate.latitude.is_finite() && estimate.longitude.is_finite());
+        assert!((estimate.latitude - 51.0005).abs() < 1e-3);
+    }
+
+    #[test]
+    fn estimate_across_the_antimeridian_stays_near_it() {
+        let options = EstimatorOptions { trilaterate: false, ..EstimatorOptions::default() };
+        let observations = [observation(10.0, 179.9999, -60, 0.0), observation(10.0, -179.9999, -60, 0.0)];
+        let estimate = estimate_location(&observations, &options).unwrap();
+        assert!(estimate.longitude.abs() > 179.99, "longitude {}", estimate.longitude);
+    }
+}
@@ -0,0 +1,335 @@
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use quick_xml::events::{BytesDecl, BytesText, Event};
+use quick_xml::Writer;
+use serde::Serialize;
+use serde_json::json;
+use std::io::Write;
+
+use crate::db::GeoDetection;
+use crate::error::Result;
+use crate::estimate::{estimate_location, EstimatorOptions, Observation};
+
+/// A point on a device's estimated trajectory.
+#[derive(Debug, Clone, Serialize)]
+pub struct TrajectoryPoint {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Radius in meters the device is likely to have been within.
+    pub uncertainty_m: f64,
+    /// Midpoint of the detections the estimate was made from.
+    pub time: DateTime<Utc>,
+    pub detections: usize,
+}
+
+/// Writes the detections as a GeoJSON FeatureCollection of points.
+pub fn write_geojson<W: Write>(mut out: W, detections: &[GeoDetection]) -> Result<()> {
+    let features: Vec<_> = detections
+        .iter()
+        .map(|detection| {
+            let mut coordinates = vec![detection.longitude, detection.latitude];
+            coordinates.extend(detection.altitude);
+            json!({
+                "type": "Feature",
+                "geometry": { "type": "Point", "coordinates": coordinates },
+                "properties": {
+                    "address": detection.address,
+                    "name": detection.name,
+                    "rssi": detection.rssi,
+                    "time": detection.timestamp,
+                },
+            })
+        })
+        .collect();
+
+    let collection = json!({ "type": "FeatureCollection", "features": features });
+    serde_json::to_writer_pretty(&mut out, &collection)?;
+    writeln!(out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Writes the detections as KML: a folder per device spanning the time it was
+/// heard, with a timestamped placemark per detection.
+pub fn write_kml<W: Write>(out: W, detections: &[GeoDetection]) -> Result<()> {
+    let mut writer = Writer::new_with_indent(out, b' ', 2);
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    writer
+        .create_element("kml")
+        .with_attribute(("xmlns", "http://www.opengis.net/kml/2.2"))
+        .write_inner_content::<_, quick_xml::Error>(|writer| {
+            writer.create_element("Document").write_inner_content::<_, quick_xml::Error>(|writer| {
+                text_element(writer, "name", "Bluetooth detections")?;
+                for device in detections.chunk_by(|a, b| a.address == b.address) {
+                    write_kml_folder(writer, device)?;
+                }
+                Ok(())
+            })?;
+            Ok(())
+        })?;
+    writeln!(writer.get_mut())?;
+    writer.get_mut().flush()?;
+    Ok(())
+}
+
+fn write_kml_folder<W: Write>(writer: &mut Writer<W>, device: &[GeoDetection]) -> quick_xml::Result<()> {
+    let (first, last) = (&device[0], &device[device.len() - 1]);
+    writer.create_element("Folder").write_inner_content::<_, quick_xml::Error>(|writer| {
+        text_element(writer, "name", &device_label(first))?;
+        writer.create_element("TimeSpan").write_inner_content::<_, quick_xml::Error>(|writer| {
+            text_element(writer, "begin", &format_time(first.timestamp))?;
+            text_element(writer, "end", &format_time(last.timestamp))?;
+            Ok(())
+        })?;
+        for detection in device {
+            writer.create_element("Placemark").write_inner_content::<_, quick_xml::Error>(|writer| {
+                let rssi = detection.rssi.map_or("unknown".to_string(), |rssi| format!("{} dBm", rssi));
+                text_element(writer, "description", &format!("RSSI: {}", rssi))?;
+                writer.create_element("TimeStamp").write_inner_content::<_, quick_xml::Error>(|writer| {
+                    text_element(writer, "when", &format_time(detection.timestamp))
+                })?;
+                writer.create_element("Point").write_inner_content::<_, quick_xml::Error>(|writer| {
+                    let coordinates = match detection.altitude {
+                        Some(altitude) => format!("{},{},{}", detection.longitude, detection.latitude, altitude),
+                        None => format!("{},{}", detection.longitude, detection.latitude),
+                    };
+                    text_element(writer, "coordinates", &coordinates)
+                })?;
+                Ok(())
+            })?;
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+/// Writes a GPX track per device following its estimated trajectory.
+pub fn write_gpx<W: Write>(
+    out: W,
+    detections: &[GeoDetection],
+    window: Duration,
+    options: &EstimatorOptions,
+) -> Result<()> {
+    let mut writer = Writer::new_with_indent(out, b' ', 2);
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    writer
+        .create_element("gpx")
+        .with_attributes([
+            ("version", "1.1"),
+            ("creator", "bluetracker"),
+            ("xmlns", "http://www.topografix.com/GPX/1/1"),
+        ])
+        .write_inner_content::<_, quick_xml::Error>(|writer| {
+            for device in detections.chunk_by(|a, b| a.address == b.address) {
+                let points = trajectory(device, window, options);
+                writer.create_element("trk").write_inner_content::<_, quick_xml::Error>(|writer| {
+                    text_element(writer, "name", &device_label(&device[0]))?;
+                    writer.create_element("trkseg").write_inner_content::<_, quick_xml::Error>(|writer| {
+                        for point in &points {
+                            writer
+                                .create_element("trkpt")
+                                .with_attributes([
+                                    ("lat", point.latitude.to_string().as_str()),
+                                    ("lon", point.longitude.to_string().as_str()),
+                                ])
+                                .write_inner_content::<_, quick_xml::Error>(|writer| {
+                                    text_element(writer, "time", &format_time(point.time))?;
+                                    let description = format!(
+                                        "±{:.0} m from {} detections",
+                                        point.uncertainty_m, point.detections
+                                    );
+                                    text_element(writer, "desc", &description)
+                                })?;
+                        }
+                        Ok(())
+                    })?;
+                    Ok(())
+                })?;
+            }
+            Ok(())
+        })?;
+    writeln!(writer.get_mut())?;
+    writer.get_mut().flush()?;
+    Ok(())
+}
+
+/// Estimates where one device was over time: its detections, in time order,
+/// are split into consecutive windows and each window gets its own estimate.
+pub fn trajectory(detections: &[GeoDetection], window: Duration, options: &EstimatorOptions) -> Vec<TrajectoryPoint> {
+    let mut points = Vec::new();
+    let mut rest = detections;
+    while let Some(first) = rest.first() {
+        // Every window holds at least its first detection, even when `window` is zero.
+        let end = rest
+            .iter()
+            .skip(1)
+            .position(|detection| detection.timestamp - first.timestamp >= window)
+            .map_or(rest.len(), |index| index + 1);
+        let (group, remaining) = rest.split_at(end);
+        rest = remaining;
+
+        let last = group[group.len() - 1].timestamp;
+        let observations: Vec<Observation> = group
+            .iter()
+            .map(|detection| Observation {
+                latitude: detection.latitude,
+                longitude: detection.longitude,
+                rssi: detection.rssi,
+                tx_power: detection.tx_power,
+                hdop: detection.hdop,
+                age_secs: (last - detection.timestamp).num_milliseconds() as f64 / 1000.0,
+            })
+            .collect();
+        // A window whose weights are unusable gives no estimate, or one that is not a position.
+        let estimate = estimate_location(&observations, options)
+            .filter(|estimate| estimate.latitude.is_finite() && estimate.longitude.is_finite());
+        if let Some(estimate) = estimate {
+            points.push(TrajectoryPoint {
+                latitude: estimate.latitude,
+                longitude: estimate.longitude,
+                uncertainty_m: estimate.uncertainty_m,
+                time: first.timestamp + (last - first.timestamp) / 2,
+                detections: estimate.detections,
+            });
+        }
+    }
+    points
+}
+
+fn device_label(detection: &GeoDetection) -> String {
+    format!("{} ({})", detection.name, detection.address)
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn text_element<W: Write>(writer: &mut Writer<W>, name: &str, text: &str) -> quick_xml::Result<()> {
+    writer.create_element(name).write_text_content(BytesText::new(text))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn detection(address: &str, name: &str, second: i64, latitude: f64) -> GeoDetection {
+        GeoDetection {
+            address: address.to_string(),
+            name: name.to_string(),
+            timestamp: Utc.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap() + Duration::seconds(second),
+            latitude,
+            longitude: 4.0,
+            altitude: None,
+            rssi: Some(-60),
+            tx_power: None,
+            hdop: None,
+        }
+    }
+
+    /// Detections ordered by device and time, as `get_geo_detections` returns them.
+    fn detections() -> Vec<GeoDetection> {
+        let tag = GeoDetection { altitude: Some(12.5), rssi: None, ..detection("AA:BB:CC:DD:EE:02", "Tag", 10, 52.0) };
+        vec![
+            detection("AA:BB:CC:DD:EE:01", "Phone", 0, 51.0),
+            detection("AA:BB:CC:DD:EE:01", "Phone", 30, 51.0),
+            detection("AA:BB:CC:DD:EE:01", "Phone", 90, 53.0),
+            tag,
+        ]
+    }
+
+    fn time(second: i64) -> DateTime<Utc> {
+        detection("", "", second, 0.0).timestamp
+    }
+
+    #[test]
+    fn geojson_has_a_point_feature_per_detection() {
+        let mut out = Vec::new();
+        write_geojson(&mut out, &detections()).unwrap();
+        let collection: serde_json::Value = serde_json::from_slice(&out).unwrap();
+
+        assert_eq!(collection["type"], "FeatureCollection");
+        let features = collection["features"].as_array().unwrap();
+        assert_eq!(features.len(), 4);
+        assert_eq!(features[0]["type"], "Feature");
+        assert_eq!(features[0]["geometry"], json!({ "type": "Point", "coordinates": [4.0, 51.0] }));
+        assert_eq!(
+            features[0]["properties"],
+            json!({ "address": "AA:BB:CC:DD:EE:01", "name": "Phone", "rssi": -60, "time": "2024-05-01T10:00:00Z" })
+        );
+        // The altitude is a third coordinate; a missing RSSI is null.
+        assert_eq!(features[3]["geometry"]["coordinates"], json!([4.0, 52.0, 12.5]));
+        assert_eq!(features[3]["properties"]["rssi"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn kml_has_a_folder_per_device_spanning_its_detections() {
+        let mut out = Vec::new();
+        write_kml(&mut out, &detections()).unwrap();
+        let kml = String::from_utf8(out).unwrap();
+
+        let folders: Vec<&str> = kml.split("<Folder>").skip(1).collect();
+        assert_eq!(folders.len(), 2);
+        assert!(folders[0].contains("<name>Phone (AA:BB:CC:DD:EE:01)</name>"));
+        assert!(folders[0].contains("<begin>2024-05-01T10:00:00Z</begin>"));
+        assert!(folders[0].contains("<end>2024-05-01T10:01:30Z</end>"));
+        assert_eq!(folders[0].matches("<Placemark>").count(), 3);
+        assert!(folders[0].contains("<when>2024-05-01T10:00:30Z</when>"));
+        assert!(folders[0].contains("<description>RSSI: -60 dBm</description>"));
+
+        assert!(folders[1].contains("<begin>2024-05-01T10:00:10Z</begin>"));
+        assert!(folders[1].contains("<end>2024-05-01T10:00:10Z</end>"));
+        assert!(folders[1].contains("<coordinates>4,52,12.5</coordinates>"));
+        assert!(folders[1].contains("<description>RSSI: unknown</description>"));
+    }
+
+    #[test]
+    fn gpx_has_a_track_per_device() {
+        let mut out = Vec::new();
+        write_gpx(&mut out, &detections(), Duration::seconds(60), &EstimatorOptions::default()).unwrap();
+        let gpx = String::from_utf8(out).unwrap();
+
+        assert!(gpx.contains(r#"<gpx version="1.1" creator="bluetracker" xmlns="http://www.topografix.com/GPX/1/1">"#));
+        let tracks: Vec<&str> = gpx.split("<trk>").skip(1).collect();
+        assert_eq!(tracks.len(), 2);
+        assert!(tracks[0].contains("<name>Phone (AA:BB:CC:DD:EE:01)</name>"));
+        assert_eq!(tracks[0].matches("<trkseg>").count(), 1);
+        assert_eq!(tracks[0].matches("<trkpt ").count(), 2);
+        assert!(tracks[0].contains("<time>2024-05-01T10:00:15Z</time>"));
+        assert!(tracks[0].contains("from 2 detections</desc>"));
+        assert_eq!(tracks[1].matches("<trkpt ").count(), 1);
+    }
+
+    #[test]
+    fn trajectory_splits_detections_into_windows() {
+        let options = EstimatorOptions::default();
+        let phone = &detections()[..3];
+
+        let points = trajectory(phone, Duration::seconds(60), &options);
+        assert_eq!(points.len(), 2);
+        assert_eq!((points[0].time, points[0].detections), (time(15), 2));
+        assert!((points[0].latitude - 51.0).abs() < 1e-9);
+        assert_eq!((points[1].time, points[1].detections), (time(90), 1));
+        assert!((points[1].latitude - 53.0).abs() < 1e-9);
+
+        // A zero window still puts every detection in a window of its own.
+        let points = trajectory(phone, Duration::zero(), &options);
+        let times: Vec<_> = points.iter().map(|point| (point.time, point.detections)).collect();
+        assert_eq!(times, [(time(0), 1), (time(30), 1), (time(90), 1)]);
+
+        assert!(trajectory(&[], Duration::seconds(60), &options).is_empty());
+    }
+
+    #[test]
+    fn trajectory_skips_windows_without_an_estimate() {
+        // Without a half-life the recency weights are not numbers, so no window gets a position.
+        let options = EstimatorOptions { recency_half_life_secs: 0.0, ..EstimatorOptions::default() };
+        assert!(trajectory(&detections()[..3], Duration::seconds(60), &options).is_empty());
+
+        let mut out = Vec::new();
+        write_gpx(&mut out, &detections()[..3], Duration::seconds(60), &options).unwrap();
+        let gpx = String::from_utf8(out).unwrap();
+        assert_eq!(gpx.matches("<trk>").count(), 1);
+        assert!(!gpx.contains("<trkpt"));
+    }
+}
@@ -0,0 +1,114 @@
+use btleplug::api::{BDAddr, ScanFilter};
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use std::collections::HashSet;
+use std::fs;
+use uuid::Uuid;
+
+use crate::db::DeviceScanData;
+use crate::error::{BluetrackerError, Result};
+
+/// Decides which advertisements a scan keeps. An empty filter keeps everything.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceFilter {
+    /// Handed to the adapter's scan filter, and also checked against each
+    /// result since stacks may still report devices they already knew.
+    pub services: Vec<Uuid>,
+    /// Regular expression the advertised name has to match.
+    pub name_pattern: Option<Regex>,
+    /// Devices heard weaker than this (or without an RSSI) are dropped.
+    pub min_rssi: Option<i32>,
+    /// Keep only devices advertising manufacturer data from one of these companies.
+    pub manufacturer_ids: Vec<u16>,
+    /// When set, only these addresses are kept.
+    pub allow: Option<HashSet<BDAddr>>,
+    pub deny: HashSet<BDAddr>,
+}
+
+impl DeviceFilter {
+    /// The part of the filter the Bluetooth stack can apply itself.
+    pub fn scan_filter(&self) -> ScanFilter {
+        ScanFilter { services: self.services.clone() }
+    }
+
+    /// The filters in effect as a JSON object, or `None` when nothing is filtered.
+    pub fn to_json(&self) -> Option<String> {
+        let mut filters = Map::new();
+        if !self.services.is_empty() {
+            filters.insert("services".into(), json!(self.services));
+        }
+        if let Some(pattern) = &self.name_pattern {
+            filters.insert("name".into(), json!(pattern.as_str()));
+        }
+        if let Some(min_rssi) = self.min_rssi {
+            filters.insert("min_rssi".into(), json!(min_rssi));
+        }
+        if !self.manufacturer_ids.is_empty() {
+            filters.insert("manufacturer_ids".into(), json!(self.manufacturer_ids));
+        }
+        if let Some(allow) = &self.allow {
+            filters.insert("allow".into(), address_list(allow));
+        }
+        if !self.deny.is_empty() {
+            filters.insert("deny".into(), address_list(&self.deny));
+        }
+        (!filters.is_empty()).then(|| Value::Object(filters).to_string())
+    }
+
+    pub fn matches(&self, data: &DeviceScanData) -> bool {
+        if let Ok(address) = data.address.parse::<BDAddr>() {
+            if self.deny.contains(&address) {
+                return false;
+            }
+            if self.allow.as_ref().is_some_and(|allow| !allow.contains(&address)) {
+                return false;
+            }
+        } else if self.allow.is_some() {
+            return false;
+        }
+
+        if !self.services.is_empty() && !data.services.iter().any(|uuid| self.services.contains(uuid)) {
+            return false;
+        }
+        if let Some(pattern) = &self.name_pattern {
+            if !data.name.as_deref().is_some_and(|name| pattern.is_match(name)) {
+                return false;
+            }
+        }
+        if let Some(min_rssi) = self.min_rssi {
+            if data.rssi.is_none_or(|rssi| rssi < min_rssi) {
+                return false;
+            }
+        }
+        if !self.manufacturer_ids.is_empty()
+            && !data.manufacturer_data.keys().any(|id| self.manufacturer_ids.contains(id))
+        {
+            return false;
+        }
+        true
+    }
+}
+
+fn address_list(addresses: &HashSet<BDAddr>) -> Value {
+    let mut addresses: Vec<String> = addresses.iter().map(|address| address.to_string()).collect();
+    addresses.sort();
+    json!(addresses)
+}
+
+/// Loads an address list: one address per line, with blank lines and `#`
+/// comments ignored.
+pub fn load_address_list(path: &str) -> Result<HashSet<BDAddr>> {
+    let content = fs::read_to_string(path)?;
+    let mut addresses = HashSet::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let address = line.parse::<BDAddr>().map_err(|_| {
+            BluetrackerError::Parse(format!("{}:{}: invalid address '{}'", path, number + 1, line))
+        })?;
+        addresses.insert(address);
+    }
+    Ok(addresses)
+}
@@ -0,0 +1,444 @@
+use btleplug::api::{CharPropFlags, Characteristic, Service, WriteType};
+use chrono::{DateTime, Utc};
+use futures::stream::{Stream, StreamExt};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+
+use crate::backend::BluetoothPeripheral;
+use crate::error::{BluetrackerError, Result};
+
+/// Low 96 bits shared by every UUID assigned by the Bluetooth SIG.
+const BLUETOOTH_BASE_UUID: u128 = 0x0000_0000_0000_1000_8000_0080_5f9b_34fb;
+const SHORT_UUID_MASK: u128 = 0xffff_ffff << 96;
+
+/// Characteristic property names in the order they are printed.
+const PROPERTY_NAMES: &[(CharPropFlags, &str)] = &[
+    (CharPropFlags::BROADCAST, "broadcast"),
+    (CharPropFlags::READ, "read"),
+    (CharPropFlags::WRITE_WITHOUT_RESPONSE, "write_without_response"),
+    (CharPropFlags::WRITE, "write"),
+    (CharPropFlags::NOTIFY, "notify"),
+    (CharPropFlags::INDICATE, "indicate"),
+    (CharPropFlags::AUTHENTICATED_SIGNED_WRITES, "authenticated_signed_writes"),
+    (CharPropFlags::EXTENDED_PROPERTIES, "extended_properties"),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GattService {
+    pub uuid: Uuid,
+    pub name: Option<&'static str>,
+    pub primary: bool,
+    pub characteristics: Vec<GattCharacteristic>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GattCharacteristic {
+    pub uuid: Uuid,
+    pub name: Option<&'static str>,
+    pub properties: Vec<&'static str>,
+    pub descriptors: Vec<GattDescriptor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GattDescriptor {
+    pub uuid: Uuid,
+    pub name: Option<&'static str>,
+}
+
+/// How a characteristic value is shown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ValueFormat {
+    #[default]
+    Hex,
+    Utf8,
+    U8,
+    U16,
+    U32,
+    I8,
+    I16,
+    I32,
+}
+
+impl FromStr for ValueFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "hex" => Ok(ValueFormat::Hex),
+            "utf8" => Ok(ValueFormat::Utf8),
+            "u8" => Ok(ValueFormat::U8),
+            "u16" => Ok(ValueFormat::U16),
+            "u32" => Ok(ValueFormat::U32),
+            "i8" => Ok(ValueFormat::I8),
+            "i16" => Ok(ValueFormat::I16),
+            "i32" => Ok(ValueFormat::I32),
+            _ => Err(format!("invalid value format '{}': expected hex, utf8, u8, u16, u32, i8, i16 or i32", value)),
+        }
+    }
+}
+
+/// A decoded characteristic value; integers stay numbers in JSON output.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum DecodedValue {
+    Integer(i64),
+    Text(String),
+}
+
+impl fmt::Display for DecodedValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodedValue::Integer(value) => write!(f, "{}", value),
+            DecodedValue::Text(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// A value read from or notified by a characteristic.
+#[derive(Debug, Clone, Serialize)]
+pub struct GattValue {
+    pub timestamp: DateTime<Utc>,
+    pub address: String,
+    pub uuid: Uuid,
+    pub hex: String,
+    /// `None` when the bytes do not fit the requested format.
+    pub value: Option<DecodedValue>,
+}
+
+impl GattValue {
+    pub fn new(address: &str, uuid: Uuid, data: &[u8], format: ValueFormat) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            address: address.to_string(),
+            uuid,
+            hex: hex::encode(data),
+            value: decode_value(data, format),
+        }
+    }
+}
+
+/// Decodes a value as hex, UTF-8 or a little-endian integer. Integers need
+/// exactly as many bytes as their width.
+pub fn decode_value(data: &[u8], format: ValueFormat) -> Option<DecodedValue> {
+    let integer = match format {
+        ValueFormat::Hex => return Some(DecodedValue::Text(hex::encode(data))),
+        ValueFormat::Utf8 => return String::from_utf8(data.to_vec()).ok().map(DecodedValue::Text),
+        ValueFormat::U8 => i64::from(u8::from_le_bytes(data.try_into().ok()?)),
+        ValueFormat::U16 => i64::from(u16::from_le_bytes(data.try_into().ok()?)),
+        ValueFormat::U32 => i64::from(u32::from_le_bytes(data.try_into().ok()?)),
+        ValueFormat::I8 => i64::from(i8::from_le_bytes(data.try_into().ok()?)),
+        ValueFormat::I16 => i64::from(i16::from_le_bytes(data.try_into().ok()?)),
+        ValueFormat::I32 => i64::from(i32::from_le_bytes(data.try_into().ok()?)),
+    };
+    Some(DecodedValue::Integer(integer))
+}
+
+/// Parses a value to write, given as hex (`0a1b`, `0x0a1b`, `0a:1b`) or as UTF-8 text.
+pub fn parse_value(input: &str, utf8: bool) -> Result<Vec<u8>> {
+    if utf8 {
+        return Ok(input.as_bytes().to_vec());
+    }
+    let digits: String = input
+        .trim_start_matches("0x")
+        .chars()
+        .filter(|c| !matches!(c, ' ' | ':' | '-'))
+        .collect();
+    hex::decode(&digits).map_err(|e| BluetrackerError::Parse(format!("invalid hex value '{}': {}", input, e)))
+}
+
+/// Finds a characteristic of a connected peripheral, discovering its services
+/// first if that has not happened yet.
+pub async fn find_characteristic(peripheral: &dyn BluetoothPeripheral, uuid: &Uuid) -> Result<Characteristic> {
+    if peripheral.services().is_empty() {
+        peripheral.discover_services().await?;
+    }
+    peripheral
+        .services()
+        .into_iter()
+        .flat_map(|service| service.characteristics)
+        .find(|characteristic| characteristic.uuid == *uuid)
+        .ok_or_else(|| BluetrackerError::CharacteristicNotFound {
+            address: peripheral.address(),
+            uuid: format_uuid(uuid),
+        })
+}
+
+pub async fn read_characteristic(peripheral: &dyn BluetoothPeripheral, uuid: &Uuid) -> Result<Vec<u8>> {
+    let characteristic = find_characteristic(peripheral, uuid).await?;
+    Ok(peripheral.read(&characteristic).await?)
+}
+
+pub async fn write_characteristic(
+    peripheral: &dyn BluetoothPeripheral,
+    uuid: &Uuid,
+    data: &[u8],
+    with_response: bool,
+) -> Result<()> {
+    let characteristic = find_characteristic(peripheral, uuid).await?;
+    let write_type = if with_response { WriteType::WithResponse } else { WriteType::WithoutResponse };
+    Ok(peripheral.write(&characteristic, data, write_type).await?)
+}
+
+/// Subscribes to a characteristic and returns the stream of its values.
+pub async fn subscribe(
+    peripheral: &dyn BluetoothPeripheral,
+    uuid: &Uuid,
+) -> Result<impl Stream<Item = Vec<u8>> + Send> {
+    let characteristic = find_characteristic(peripheral, uuid).await?;
+    // Open the stream first so no value sent right after subscribing is missed.
+    let notifications = peripheral.notifications().await?;
+    peripheral.subscribe(&characteristic).await?;
+    let uuid = *uuid;
+    Ok(notifications.filter_map(move |notification| async move {
+        (notification.uuid == uuid).then_some(notification.value)
+    }))
+}
+
+pub async fn unsubscribe(peripheral: &dyn BluetoothPeripheral, uuid: &Uuid) -> Result<()> {
+    let characteristic = find_characteristic(peripheral, uuid).await?;
+    Ok(peripheral.unsubscribe(&characteristic).await?)
+}
+
+/// Discovers the services of a connected peripheral.
+pub async fn discover(peripheral: &dyn BluetoothPeripheral) -> Result<Vec<GattService>> {
+    peripheral.discover_services().await?;
+    Ok(from_services(&peripheral.services()))
+}
+
+/// Converts btleplug's service tree into the named GATT table.
+pub fn from_services(services: &BTreeSet<Service>) -> Vec<GattService> {
+    services
+        .iter()
+        .map(|service| GattService {
+            uuid: service.uuid,
+            name: uuid_name(&service.uuid),
+            primary: service.primary,
+            characteristics: service
+                .characteristics
+                .iter()
+                .map(|characteristic| GattCharacteristic {
+                    uuid: characteristic.uuid,
+                    name: uuid_name(&characteristic.uuid),
+                    properties: property_names(characteristic.properties),
+                    descriptors: characteristic
+                        .descriptors
+                        .iter()
+                        .map(|descriptor| GattDescriptor {
+                            uuid: descriptor.uuid,
+                            name: uuid_name(&descriptor.uuid),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+pub fn property_names(flags: CharPropFlags) -> Vec<&'static str> {
+    PROPERTY_NAMES
+        .iter()
+        .filter(|(flag, _)| flags.contains(*flag))
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Looks up a property by the name `property_names` gives it.
+pub fn parse_property(name: &str) -> Option<(CharPropFlags, &'static str)> {
+    PROPERTY_NAMES.iter().find(|(_, known)| *known == name).copied()
+}
+
+/// The 16-bit form of a UUID assigned by the Bluetooth SIG.
+pub fn short_uuid(uuid: &Uuid) -> Option<u16> {
+    let value = uuid.as_u128();
+    if value & !SHORT_UUID_MASK != BLUETOOTH_BASE_UUID || value >> 112 != 0 {
+        return None;
+    }
+    Some((value >> 96) as u16)
+}
+
+/// Expands a 16-bit SIG-assigned UUID with the Bluetooth base UUID.
+pub fn from_short_uuid(short: u16) -> Uuid {
+    Uuid::from_u128((u128::from(short) << 96) | BLUETOOTH_BASE_UUID)
+}
+
+/// Prints SIG-assigned UUIDs in their short `0x180f` form.
+pub fn format_uuid(uuid: &Uuid) -> String {
+    match short_uuid(uuid) {
+        Some(short) => format!("0x{:04x}", short),
+        None => uuid.to_string(),
+    }
+}
+
+/// Name of a well-known service, characteristic or descriptor.
+pub fn uuid_name(uuid: &Uuid) -> Option<&'static str> {
+    let name = match short_uuid(uuid)? {
+        // Services
+        0x1800 => "Generic Access",
+        0x1801 => "Generic Attribute",
+        0x1802 => "Immediate Alert",
+        0x1803 => "Link Loss",
+        0x1804 => "Tx Power",
+        0x1805 => "Current Time",
+        0x180a => "Device Information",
+        0x180d => "Heart Rate",
+        0x180f => "Battery",
+        0x1810 => "Blood Pressure",
+        0x1812 => "Human Interface Device",
+        0x1816 => "Cycling Speed and Cadence",
+        0x1818 => "Cycling Power",
+        0x181a => "Environmental Sensing",
+        0x181c => "User Data",
+        0x1826 => "Fitness Machine",
+        0xfe9f => "Google",
+        0xfd6f => "Exposure Notification",
+        // Characteristics
+        0x2a00 => "Device Name",
+        0x2a01 => "Appearance",
+        0x2a04 => "Peripheral Preferred Connection Parameters",
+        0x2a05 => "Service Changed",
+        0x2a06 => "Alert Level",
+        0x2a07 => "Tx Power Level",
+        0x2a19 => "Battery Level",
+        0x2a23 => "System ID",
+        0x2a24 => "Model Number String",
+        0x2a25 => "Serial Number String",
+        0x2a26 => "Firmware Revision String",
+        0x2a27 => "Hardware Revision String",
+        0x2a28 => "Software Revision String",
+        0x2a29 => "Manufacturer Name String",
+        0x2a2b => "Current Time",
+        0x2a37 => "Heart Rate Measurement",
+        0x2a38 => "Body Sensor Location",
+        0x2a39 => "Heart Rate Control Point",
+        0x2a4d => "Report",
+        0x2a50 => "PnP ID",
+        0x2a6e => "Temperature",
+        0x2a6f => "Humidity",
+        0x2aa6 => "Central Address Resolution",
+        0x2b29 => "Client Supported Features",
+        0x2b2a => "Database Hash",
+        // Descriptors
+        0x2900 => "Characteristic Extended Properties",
+        0x2901 => "Characteristic User Description",
+        0x2902 => "Client Characteristic Configuration",
+        0x2903 => "Server Characteristic Configuration",
+        0x2904 => "Characteristic Presentation Format",
+        0x2908 => "Report Reference",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Lists what changed between two GATT tables of the same device, one line per
+/// added (`+`), removed (`-`) or changed (`~`) service, characteristic or descriptor.
+pub fn compare(old: &[GattService], new: &[GattService]) -> Vec<String> {
+    let mut changes = Vec::new();
+
+    for service in new {
+        let Some(previous) = old.iter().find(|s| s.uuid == service.uuid) else {
+            changes.push(format!("+ service {}", describe(&service.uuid)));
+            continue;
+        };
+        for characteristic in &service.characteristics {
+            let uuid = describe(&characteristic.uuid);
+            let Some(before) = previous.characteristics.iter().find(|c| c.uuid == characteristic.uuid) else {
+                changes.push(format!("+ characteristic {}", uuid));
+                continue;
+            };
+            if before.properties != characteristic.properties {
+                changes.push(format!(
+                    "~ characteristic {} [{}] -> [{}]",
+                    uuid,
+                    before.properties.join(", "),
+                    characteristic.properties.join(", ")
+                ));
+            }
+            for descriptor in &characteristic.descriptors {
+                if !before.descriptors.iter().any(|d| d.uuid == descriptor.uuid) {
+                    changes.push(format!("+ descriptor {} of {}", describe(&descriptor.uuid), uuid));
+                }
+            }
+            for descriptor in &before.descriptors {
+                if !characteristic.descriptors.iter().any(|d| d.uuid == descriptor.uuid) {
+                    changes.push(format!("- descriptor {} of {}", describe(&descriptor.uuid), uuid));
+                }
+            }
+        }
+        for characteristic in &previous.characteristics {
+            if !service.characteristics.iter().any(|c| c.uuid == characteristic.uuid) {
+                changes.push(format!("- characteristic {}", describe(&characteristic.uuid)));
+            }
+        }
+    }
+
+    for service in old {
+        if !new.iter().any(|s| s.uuid == service.uuid) {
+            changes.push(format!("- service {}", describe(&service.uuid)));
+        }
+    }
+
+    changes
+}
+
+/// A UUID followed by its name when it is a well-known one.
+pub fn describe(uuid: &Uuid) -> String {
+    match uuid_name(uuid) {
+        Some(name) => format!("{} ({})", format_uuid(uuid), name),
+        None => format_uuid(uuid),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn characteristic(short: u16, properties: &[&'static str], descriptors: &[u16]) -> GattCharacteristic {
+        let uuid = from_short_uuid(short);
+        GattCharacteristic {
+            uuid,
+            name: uuid_name(&uuid),
+            properties: properties.to_vec(),
+            descriptors: descriptors
+                .iter()
+                .map(|short| {
+                    let uuid = from_short_uuid(*short);
+                    GattDescriptor { uuid, name: uuid_name(&uuid) }
+                })
+                .collect(),
+        }
+    }
+
+    fn service(short: u16, characteristics: Vec<GattCharacteristic>) -> GattService {
+        let uuid = from_short_uuid(short);
+        GattService { uuid, name: uuid_name(&uuid), primary: true, characteristics }
+    }
+
+    #[test]
+    fn compare_lists_changed_attributes() {
+        let old = vec![
+            service(0x180f, vec![characteristic(0x2a19, &["read"], &[0x2902])]),
+            service(0x180a, vec![characteristic(0x2a29, &["read"], &[])]),
+        ];
+        let new = vec![
+            service(0x180f, vec![characteristic(0x2a19, &["read", "notify"], &[0x2901])]),
+            service(0x180d, vec![characteristic(0x2a37, &["notify"], &[0x2902])]),
+        ];
+
+        let changes = compare(&old, &new);
+        let battery_level = describe(&from_short_uuid(0x2a19));
+        assert_eq!(
+            changes,
+            [
+                format!("~ characteristic {} [read] -> [read, notify]", battery_level),
+                format!("+ descriptor {} of {}", describe(&from_short_uuid(0x2901)), battery_level),
+                format!("- descriptor {} of {}", describe(&from_short_uuid(0x2902)), battery_level),
+                format!("+ service {}", describe(&from_short_uuid(0x180d))),
+                format!("- service {}", describe(&from_short_uuid(0x180a))),
+            ]
+        );
+        assert!(compare(&new, &new).is_empty());
+    }
+}
@@ -0,0 +1,219 @@
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde::Serialize;
+
+use crate::error::{BluetrackerError, Result};
+
+/// One timestamped point of a GPX track.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct TrackPoint {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub elevation: Option<f64>,
+    pub time: DateTime<Utc>,
+}
+
+/// The trackpoints of a GPX file, all tracks and segments merged and ordered by time.
+#[derive(Debug, Clone, Default)]
+pub struct Track {
+    points: Vec<TrackPoint>,
+}
+
+impl Track {
+    /// Reads the `<trkpt>` elements of a GPX file. Points without a time are
+    /// skipped since they cannot be matched to detections.
+    pub fn load(path: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Self::parse(&content).map_err(|e| BluetrackerError::Parse(format!("{}: {}", path, e)))
+    }
+
+    pub fn parse(content: &str) -> std::result::Result<Self, String> {
+        let mut reader = Reader::from_str(content);
+        reader.trim_text(true);
+
+        let mut points = Vec::new();
+        let mut point: Option<PartialPoint> = None;
+        let mut field: Option<Field> = None;
+        loop {
+            let event = reader
+                .read_event()
+                .map_err(|e| format!("at byte {}: {}", reader.buffer_position(), e))?;
+            match event {
+                Event::Start(element) => match element.local_name().as_ref() {
+                    b"trkpt" => point = Some(PartialPoint::from_element(&element)?),
+                    b"ele" if point.is_some() => field = Some(Field::Elevation),
+                    b"time" if point.is_some() => field = Some(Field::Time),
+                    _ => field = None,
+                },
+                // A self-closing trkpt has no time and is skipped.
+                Event::Empty(_) => {}
+                Event::Text(text) => {
+                    let (Some(current), Some(field)) = (point.as_mut(), field) else {
+                        continue;
+                    };
+                    let text = text.unescape().map_err(|e| e.to_string())?;
+                    match field {
+                        Field::Elevation => {
+                            current.elevation = Some(text.trim().parse().map_err(|_| format!("invalid elevation '{}'", text))?)
+                        }
+                        Field::Time => current.time = Some(parse_time(&text)?),
+                    }
+                }
+                Event::End(element) => {
+                    field = None;
+                    if element.local_name().as_ref() == b"trkpt" {
+                        if let Some(point) = point.take().and_then(PartialPoint::finish) {
+                            points.push(point);
+                        }
+                    }
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        points.sort_by_key(|point| point.time);
+        Ok(Self { points })
+    }
+
+    pub fn points(&self) -> &[TrackPoint] {
+        &self.points
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Where the track was at `time`, interpolated linearly between the two
+    /// surrounding trackpoints. `None` when the time is before or after the
+    /// track, or falls between points more than `max_gap` apart.
+    pub fn position_at(&self, time: DateTime<Utc>, max_gap: Duration) -> Option<TrackPoint> {
+        let after = self.points.partition_point(|point| point.time < time);
+        let next = self.points.get(after)?;
+        if next.time == time {
+            return Some(*next);
+        }
+        let previous = self.points.get(after.checked_sub(1)?)?;
+        let gap = next.time - previous.time;
+        if gap > max_gap {
+            return None;
+        }
+
+        let fraction = (time - previous.time).num_milliseconds() as f64 / gap.num_milliseconds() as f64;
+        let lerp = |from: f64, to: f64| from + (to - from) * fraction;
+        Some(TrackPoint {
+            latitude: lerp(previous.latitude, next.latitude),
+            longitude: lerp(previous.longitude, next.longitude),
+            elevation: previous.elevation.zip(next.elevation).map(|(from, to)| lerp(from, to)),
+            time,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    Elevation,
+    Time,
+}
+
+struct PartialPoint {
+    latitude: f64,
+    longitude: f64,
+    elevation: Option<f64>,
+    time: Option<DateTime<Utc>>,
+}
+
+impl PartialPoint {
+    fn from_element(element: &BytesStart) -> std::result::Result<Self, String> {
+        let coordinate = |name: &str| -> std::result::Result<f64, String> {
+            let value = element
+                .try_get_attribute(name)
+                .map_err(|e| e.to_string())?
+                .ok_or_else(|| format!("trkpt without {}", name))?
+                .unescape_value()
+                .map_err(|e| e.to_string())?;
+            value.trim().parse().map_err(|_| format!("invalid {} '{}'", name, value))
+        };
+        Ok(Self {
+            latitude: coordinate("lat")?,
+            longitude: coordinate("lon")?,
+            elevation: None,
+            time: None,
+        })
+    }
+
+    fn finish(self) -> Option<TrackPoint> {
+        Some(TrackPoint {
+            latitude: self.latitude,
+            longitude: self.longitude,
+            elevation: self.elevation,
+            time: self.time?,
+        })
+    }
+}
+
+/// GPX times are ISO 8601, normally in UTC; a missing offset is taken as UTC.
+fn parse_time(value: &str) -> std::result::Result<DateTime<Utc>, String> {
+    let value = value.trim();
+    DateTime::parse_from_rfc3339(value)
+        .map(|time| time.with_timezone(&Utc))
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f").map(|time| time.and_utc()))
+        .map_err(|_| format!("invalid time '{}'", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const GPX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="test" xmlns="http://www.topografix.com/GPX/1/1">
+  <trk><trkseg>
+    <trkpt lat="52.0" lon="13.0"><ele>30</ele><time>2024-05-01T10:00:10Z</time></trkpt>
+    <trkpt lat="51.0" lon="12.0"><ele>10</ele><time>2024-05-01T10:00:00Z</time></trkpt>
+    <trkpt lat="50.0" lon="11.0"/>
+    <trkpt lat="53.0" lon="14.0"><time>2024-05-01T10:30:00</time></trkpt>
+  </trkseg></trk>
+</gpx>"#;
+
+    fn at(minute: u32, second: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, 10, minute, second).unwrap()
+    }
+
+    #[test]
+    fn parses_and_orders_timed_points() {
+        let track = Track::parse(GPX).unwrap();
+        let times: Vec<_> = track.points().iter().map(|point| point.time).collect();
+        assert_eq!(times, [at(0, 0), at(0, 10), at(30, 0)]);
+        assert_eq!(track.points()[0].elevation, Some(10.0));
+        assert_eq!(track.points()[2].elevation, None);
+    }
+
+    #[test]
+    fn rejects_bad_coordinates() {
+        let error = Track::parse(r#"<gpx><trkpt lat="north" lon="1"><time>2024-05-01T10:00:00Z</time></trkpt></gpx>"#)
+            .unwrap_err();
+        assert!(error.contains("invalid lat"), "{}", error);
+        assert!(Track::parse(r#"<gpx><trkpt lon="1"></trkpt></gpx>"#).is_err());
+    }
+
+    #[test]
+    fn interpolates_between_points() {
+        let track = Track::parse(GPX).unwrap();
+        let max_gap = Duration::minutes(1);
+
+        let point = track.position_at(at(0, 5), max_gap).unwrap();
+        assert!((point.latitude - 51.5).abs() < 1e-9);
+        assert!((point.longitude - 12.5).abs() < 1e-9);
+        assert_eq!(point.elevation, Some(20.0));
+        assert_eq!(point.time, at(0, 5));
+
+        assert_eq!(track.position_at(at(0, 10), max_gap).map(|point| point.latitude), Some(52.0));
+        // Before and after the track, and across a gap longer than allowed.
+        assert!(track.position_at(Utc.with_ymd_and_hms(2024, 5, 1, 9, 59, 59).unwrap(), max_gap).is_none());
+        assert!(track.position_at(at(31, 0), max_gap).is_none());
+        assert!(track.position_at(at(15, 0), max_gap).is_none());
+        assert!(track.position_at(at(15, 0), Duration::hours(1)).is_some());
+    }
+}
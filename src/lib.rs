@@ -0,0 +1,55 @@
+//! Bluetooth scanning, connection and tracking library behind the `blet` CLI.
+//!
+//! - [`backend`]: adapter abstraction over btleplug, plus the scripted [`simulator`]
+//! - [`scan`]: one-shot and continuous scans producing [`db::DeviceScanData`]
+//! - [`filter`]: which advertisements a scan keeps
+//! - [`schedule`]: cron-style schedules for repeated scans
+//! - [`config`]: settings from `~/.bluetracker/config.yaml`
+//! - [`connect`]: connecting to a device by address
+//! - [`gatt`]: GATT service discovery, well-known UUID names and characteristic IO
+//! - [`profiles`]: decoders for the Battery, Device Information and Heart Rate services
+//! - [`db`]: the SQLite store ([`db::BluetoothTracker`]) and its queries
+//! - [`location`]: live GPS position providers such as gpsd
+//! - [`gpx`]: GPX tracks for geotagging detections after the fact
+//! - [`estimate`]: RSSI-weighted location estimation
+//! - [`export`]: GeoJSON, KML and GPX exports of detections
+//! - [`utils`]: manufacturer lookups and formatting helpers
+//! - [`output`]: JSON, NDJSON and CSV output of query results
+//! - [`commands`]: the commands of the CLI, which only parses their arguments
+//!
+//! ```no_run
+//! use bluetracker::backend::BtleplugBackend;
+//! use bluetracker::db::{get_db_path, BluetoothTracker};
+//! use bluetracker::scan::{scan_devices, ScanOptions};
+//!
+//! # async fn run() -> bluetracker::Result<()> {
+//! let backend = BtleplugBackend::new().await?;
+//! let devices = scan_devices(&backend, ScanOptions::default()).await?;
+//!
+//! let mut tracker = BluetoothTracker::new(&get_db_path(None))?;
+//! tracker.store_scan_data_batch(&devices)?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod backend;
+pub mod commands;
+pub mod config;
+pub mod connect;
+pub mod db;
+pub mod error;
+pub mod estimate;
+pub mod export;
+pub mod filter;
+pub mod gatt;
+pub mod gpx;
+pub mod location;
+pub mod migrations;
+pub mod output;
+pub mod profiles;
+pub mod scan;
+pub mod schedule;
+pub mod simulator;
+pub mod utils;
+
+pub use error::{BluetrackerError, Result};
@@ -0,0 +1,503 @@
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Instant};
+
+/// Default address of gpsd.
+pub const DEFAULT_GPSD_ADDRESS: &str = "localhost:2947";
+/// Fixes older than this are not used, so a lost GPS does not keep tagging
+/// detections with a stale position.
+pub const DEFAULT_MAX_FIX_AGE: Duration = Duration::from_secs(10);
+/// Delay before reconnecting after the connection to a GPS source is lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Longest pause between two fixes when replaying a recorded NMEA file.
+const MAX_REPLAY_GAP: Duration = Duration::from_secs(10);
+
+/// A position reported by a GPS.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Fix {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Meters above mean sea level; `None` for 2D fixes.
+    pub altitude: Option<f64>,
+    /// Estimated horizontal error in meters.
+    pub accuracy: Option<f64>,
+    /// Horizontal dilution of precision; below 2 is good, above 5 poor.
+    pub hdop: Option<f64>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Source of the current position, consulted whenever a detection is recorded.
+pub trait LocationProvider: Send + Sync + fmt::Debug {
+    /// The latest fix, or `None` when there is no recent one.
+    fn current_fix(&self) -> Option<Fix>;
+}
+
+/// The latest fix received by a background reader, with when it arrived.
+#[derive(Debug, Default)]
+struct FixCell(Mutex<Option<(Fix, Instant)>>);
+
+impl FixCell {
+    fn set(&self, fix: Option<Fix>) {
+        *self.0.lock().unwrap() = fix.map(|fix| (fix, Instant::now()));
+    }
+
+    fn get(&self, max_age: Duration) -> Option<Fix> {
+        self.0.lock().unwrap().filter(|(_, received)| received.elapsed() <= max_age).map(|(fix, _)| fix)
+    }
+}
+
+/// Uses the first of several providers that has a fix.
+#[derive(Debug)]
+pub struct FirstFix(pub Vec<Box<dyn LocationProvider>>);
+
+impl LocationProvider for FirstFix {
+    fn current_fix(&self) -> Option<Fix> {
+        self.0.iter().find_map(|provider| provider.current_fix())
+    }
+}
+
+/// Follows gpsd's JSON reports over TCP, reconnecting when the connection drops.
+pub struct GpsdProvider {
+    address: String,
+    max_age: Duration,
+    fix: Arc<FixCell>,
+    task: JoinHandle<()>,
+}
+
+impl GpsdProvider {
+    /// Starts watching gpsd at `address` (host:port) in the background.
+    pub fn start(address: &str, max_age: Duration) -> Self {
+        let fix = Arc::new(FixCell::default());
+        let task = tokio::spawn(watch_gpsd(address.to_string(), fix.clone()));
+        Self { address: address.to_string(), max_age, fix, task }
+    }
+}
+
+impl LocationProvider for GpsdProvider {
+    fn current_fix(&self) -> Option<Fix> {
+        self.fix.get(self.max_age)
+    }
+}
+
+impl fmt::Debug for GpsdProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GpsdProvider").field("address", &self.address).finish()
+    }
+}
+
+impl Drop for GpsdProvider {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn watch_gpsd(address: String, fix: Arc<FixCell>) {
+    loop {
+        match read_gpsd(&address, &fix).await {
+            Ok(()) => eprintln!("gpsd at {} closed the connection", address),
+            Err(e) => eprintln!("gpsd at {}: {}", address, e),
+        }
+        fix.set(None);
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn read_gpsd(address: &str, fix: &FixCell) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect(address).await?;
+    stream.write_all(b"?WATCH={\"enable\":true,\"json\":true};\n").await?;
+
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next_line().await? {
+        if let Some(update) = parse_gpsd_report(&line) {
+            fix.set(update);
+        }
+    }
+    Ok(())
+}
+
+/// A gpsd time-position-velocity report. Other report classes are ignored.
+#[derive(Debug, Deserialize)]
+struct GpsdReport {
+    class: String,
+    /// 0 or 1: no fix, 2: 2D fix, 3: 3D fix.
+    #[serde(default)]
+    mode: u8,
+    time: Option<DateTime<Utc>>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    #[serde(rename = "altMSL")]
+    alt_msl: Option<f64>,
+    /// Reported by gpsd before 3.20, replaced by `altMSL`/`altHAE`.
+    alt: Option<f64>,
+    eph: Option<f64>,
+    epx: Option<f64>,
+    epy: Option<f64>,
+}
+
+/// Turns a gpsd JSON line into a fix update: `Some(None)` for a TPV report
+/// without a fix, `None` for lines that say nothing about the position.
+pub fn parse_gpsd_report(line: &str) -> Option<Option<Fix>> {
+    let report: GpsdReport = serde_json::from_str(line).ok()?;
+    if report.class != "TPV" {
+        return None;
+    }
+    let (Some(latitude), Some(longitude)) = (report.lat, report.lon) else {
+        return Some(None);
+    };
+    if report.mode < 2 {
+        return Some(None);
+    }
+
+    let accuracy = report.eph.or(match (report.epx, report.epy) {
+        (Some(epx), Some(epy)) => Some(epx.max(epy)),
+        _ => None,
+    });
+    Some(Some(Fix {
+        latitude,
+        longitude,
+        altitude: if report.mode >= 3 { report.alt_msl.or(report.alt) } else { None },
+        accuracy,
+        hdop: None,
+        timestamp: report.time.unwrap_or_else(Utc::now),
+    }))
+}
+
+/// Reads NMEA 0183 sentences from a serial GPS device or a recorded `.nmea`
+/// file. A recorded file is replayed at the pace of its timestamps.
+pub struct NmeaProvider {
+    path: String,
+    max_age: Duration,
+    fix: Arc<FixCell>,
+    task: JoinHandle<()>,
+}
+
+impl NmeaProvider {
+    /// Starts reading `path` in the background.
+    pub fn start(path: &str, max_age: Duration) -> Self {
+        let fix = Arc::new(FixCell::default());
+        let task = tokio::spawn(watch_nmea(path.to_string(), fix.clone()));
+        Self { path: path.to_string(), max_age, fix, task }
+    }
+}
+
+impl LocationProvider for NmeaProvider {
+    fn current_fix(&self) -> Option<Fix> {
+        self.fix.get(self.max_age)
+    }
+}
+
+impl fmt::Debug for NmeaProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NmeaProvider").field("path", &self.path).finish()
+    }
+}
+
+impl Drop for NmeaProvider {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn watch_nmea(path: String, fix: Arc<FixCell>) {
+    loop {
+        match read_nmea(&path, &fix).await {
+            Ok(true) => {
+                // A recorded file has been replayed; its last fix ages out on its own.
+                eprintln!("Finished replaying {}", path);
+                return;
+            }
+            Ok(false) => eprintln!("{} closed", path),
+            Err(e) => eprintln!("{}: {}", path, e),
+        }
+        fix.set(None);
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Reads sentences until the end of the input. Returns whether the input was a
+/// regular file, which is replayed once rather than reopened.
+async fn read_nmea(path: &str, fix: &FixCell) -> std::io::Result<bool> {
+    let file = File::open(path).await?;
+    let replay = file.metadata().await?.is_file();
+
+    // A recording is replayed long after it was made, so its fixes need the
+    // date from an RMC sentence rather than today's.
+    let mut parser = NmeaParser { require_date: replay, ..Default::default() };
+    let mut last_timestamp: Option<DateTime<Utc>> = None;
+    let mut lines = BufReader::new(file).lines();
+    while let Some(line) = lines.next_line().await? {
+        let Some(update) = parser.parse_line(&line) else { continue };
+        if replay {
+            if let (Some(previous), Some(current)) = (last_timestamp, update.map(|fix| fix.timestamp)) {
+                let gap = (current - previous).to_std().unwrap_or_default().min(MAX_REPLAY_GAP);
+                sleep(gap).await;
+            }
+            last_timestamp = update.map(|fix| fix.timestamp).or(last_timestamp);
+        }
+        fix.set(update);
+    }
+    Ok(replay)
+}
+
+/// Turns NMEA 0183 sentences into fixes. GGA provides position, altitude and
+/// HDOP; RMC position and date; GSA the fix dimension and HDOP. Sentences from
+/// any talker (GP, GN, GL, ...) are accepted; others and those with a bad
+/// checksum are ignored.
+#[derive(Debug, Default)]
+pub struct NmeaParser {
+    /// Ignore fixes until an RMC sentence has given the date, instead of
+    /// assuming today.
+    pub require_date: bool,
+    date: Option<NaiveDate>,
+    /// 2 or 3 once a GSA sentence reported the fix dimension.
+    dimension: Option<u8>,
+    hdop: Option<f64>,
+    fix: Option<Fix>,
+}
+
+impl NmeaParser {
+    /// Feeds one line. Returns `Some(fix)` when it changed the current fix
+    /// (`Some(None)` when the receiver lost its fix), `None` otherwise.
+    pub fn parse_line(&mut self, line: &str) -> Option<Option<Fix>> {
+        let fields = split_sentence(line)?;
+        let kind = fields[0].get(2..)?;
+        match kind {
+            "GGA" => self.parse_gga(&fields),
+            "RMC" => self.parse_rmc(&fields),
+            "GSA" => self.parse_gsa(&fields),
+            _ => None,
+        }
+    }
+
+    fn parse_gga(&mut self, fields: &[&str]) -> Option<Option<Fix>> {
+        // $GPGGA,time,lat,N,lon,E,quality,satellites,hdop,altitude,M,...
+        if fields.get(6).is_none_or(|quality| *quality == "0" || quality.is_empty()) {
+            self.fix = None;
+            return Some(None);
+        }
+        let (latitude, longitude) = parse_position(fields.get(2..6)?)?;
+        let time = parse_time(fields.get(1)?)?;
+        self.hdop = fields.get(8).and_then(|hdop| hdop.parse().ok()).or(self.hdop);
+        let altitude = if self.dimension == Some(2) { None } else { fields.get(9).and_then(|alt| alt.parse().ok()) };
+        let timestamp = self.timestamp(time)?;
+
+        self.fix = Some(Fix { latitude, longitude, altitude, accuracy: None, hdop: self.hdop, timestamp });
+        Some(self.fix)
+    }
+
+    fn parse_rmc(&mut self, fields: &[&str]) -> Option<Option<Fix>> {
+        // $GPRMC,time,status,lat,N,lon,E,speed,course,date,...
+        if let Some(date) = fields.get(9).and_then(|date| NaiveDate::parse_from_str(date, "%d%m%y").ok()) {
+            self.date = Some(date);
+        }
+        if fields.get(2) != Some(&"A") {
+            self.fix = None;
+            return Some(None);
+        }
+        let (latitude, longitude) = parse_position(fields.get(3..7)?)?;
+        let time = parse_time(fields.get(1)?)?;
+        // Altitude only comes with GGA; keep the one from the same epoch.
+        let timestamp = self.timestamp(time)?;
+        let altitude = self.fix.filter(|fix| fix.timestamp == timestamp).and_then(|fix| fix.altitude);
+
+        self.fix = Some(Fix { latitude, longitude, altitude, accuracy: None, hdop: self.hdop, timestamp });
+        Some(self.fix)
+    }
+
+    fn parse_gsa(&mut self, fields: &[&str]) -> Option<Option<Fix>> {
+        // $GPGSA,mode,dimension,12 satellite PRNs,pdop,hdop,vdop
+        let dimension: u8 = fields.get(2)?.parse().ok()?;
+        if dimension < 2 {
+            self.dimension = None;
+            self.fix = None;
+            return Some(None);
+        }
+        self.dimension = Some(dimension);
+        self.hdop = fields.get(16).and_then(|hdop| hdop.parse().ok()).or(self.hdop);
+
+        let mut fix = self.fix?;
+        fix.hdop = self.hdop;
+        if dimension == 2 {
+            fix.altitude = None;
+        }
+        self.fix = Some(fix);
+        Some(self.fix)
+    }
+
+    /// Combines a time of day with the date from the last RMC sentence, or
+    /// today's unless `require_date` is set.
+    fn timestamp(&self, time: NaiveTime) -> Option<DateTime<Utc>> {
+        let date = match self.date {
+            Some(date) => date,
+            None if self.require_date => return None,
+            None => Utc::now().date_naive(),
+        };
+        Some(Utc.from_utc_datetime(&date.and_time(time)))
+    }
+}
+
+/// Checks the `*hh` checksum and splits the sentence into its fields, the
+/// first being the talker and sentence type such as `GPGGA`.
+fn split_sentence(line: &str) -> Option<Vec<&str>> {
+    let body = line.trim().strip_prefix('$')?;
+    let (body, checksum) = body.split_once('*')?;
+    let expected = u8::from_str_radix(checksum.get(..2)?, 16).ok()?;
+    if body.bytes().fold(0, |sum, byte| sum ^ byte) != expected {
+        return None;
+    }
+    Some(body.split(',').collect())
+}
+
+/// Parses `ddmm.mmmm,N,dddmm.mmmm,E` into signed decimal degrees.
+fn parse_position(fields: &[&str]) -> Option<(f64, f64)> {
+    let latitude = parse_coordinate(fields[0], 2, fields[1], "S")?;
+    let longitude = parse_coordinate(fields[2], 3, fields[3], "W")?;
+    Some((latitude, longitude))
+}
+
+fn parse_coordinate(value: &str, degree_digits: usize, hemisphere: &str, negative: &str) -> Option<f64> {
+    let degrees: f64 = value.get(..degree_digits)?.parse().ok()?;
+    let minutes: f64 = value.get(degree_digits..)?.parse().ok()?;
+    let coordinate = degrees + minutes / 60.0;
+    Some(if hemisphere == negative { -coordinate } else { coordinate })
+}
+
+fn parse_time(value: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(value, "%H%M%S%.f").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Wraps a sentence body in `$` and its checksum.
+    fn sentence(body: &str) -> String {
+        format!("${}*{:02X}", body, body.bytes().fold(0, |sum, byte| sum ^ byte))
+    }
+
+    #[test]
+    fn rmc_and_gga_make_a_dated_3d_fix() {
+        let mut parser = NmeaParser::default();
+        let rmc = sentence("GPRMC,123519,A,4807.038,N,01131.000,W,022.4,084.4,230394,003.1,W");
+        let fix = parser.parse_line(&rmc).unwrap().unwrap();
+        assert!((fix.latitude - 48.1173).abs() < 1e-9);
+        assert!((fix.longitude + 11.516_666_666).abs() < 1e-6);
+        assert_eq!(fix.timestamp, Utc.with_ymd_and_hms(1994, 3, 23, 12, 35, 19).unwrap());
+
+        let gga = sentence("GNGGA,123520,4807.038,S,01131.000,E,1,08,0.9,545.4,M,46.9,M,,");
+        let fix = parser.parse_line(&gga).unwrap().unwrap();
+        assert!(fix.latitude < 0.0 && fix.longitude > 0.0);
+        assert_eq!(fix.altitude, Some(545.4));
+        assert_eq!(fix.hdop, Some(0.9));
+        assert_eq!(fix.timestamp, Utc.with_ymd_and_hms(1994, 3, 23, 12, 35, 20).unwrap());
+    }
+
+    #[test]
+    fn lost_fix_and_2d_fix() {
+        let mut parser = NmeaParser::default();
+        parser.parse_line(&sentence("GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W"));
+        parser.parse_line(&sentence("GPGGA,123520,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,"));
+
+        let gsa = sentence("GPGSA,A,2,04,05,,09,12,,,24,,,,,2.5,1.3,2.1");
+        let fix = parser.parse_line(&gsa).unwrap().unwrap();
+        assert_eq!(fix.altitude, None);
+        assert_eq!(fix.hdop, Some(1.3));
+
+        let no_fix = sentence("GPGGA,123521,,,,,0,00,,,M,,M,,");
+        assert_eq!(parser.parse_line(&no_fix), Some(None));
+        let void = sentence("GPRMC,123522,V,,,,,,,230394,,");
+        assert_eq!(parser.parse_line(&void), Some(None));
+    }
+
+    #[test]
+    fn undated_fixes_wait_for_rmc_when_required() {
+        let gga = sentence("GPGGA,235959,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,");
+        let mut live = NmeaParser::default();
+        assert_eq!(live.parse_line(&gga).unwrap().unwrap().timestamp.date_naive(), Utc::now().date_naive());
+
+        let mut replay = NmeaParser { require_date: true, ..Default::default() };
+        assert_eq!(replay.parse_line(&gga), None);
+        replay.parse_line(&sentence("GPRMC,000000,A,4807.038,N,01131.000,E,022.4,084.4,010524,003.1,W"));
+        let gga = sentence("GPGGA,000001,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,");
+        let fix = replay.parse_line(&gga).unwrap().unwrap();
+        assert_eq!(fix.timestamp, Utc.with_ymd_and_hms(2024, 5, 1, 0, 0, 1).unwrap());
+    }
+
+    #[test]
+    fn ignores_bad_checksums_and_other_sentences() {
+        let mut parser = NmeaParser::default();
+        assert_eq!(parser.parse_line("$GPGGA,123520,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*00"), None);
+        assert_eq!(parser.parse_line("GPGGA,123520,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,"), None);
+        assert_eq!(parser.parse_line(&sentence("GPVTG,054.7,T,034.4,M,005.5,N,010.2,K")), None);
+    }
+
+    #[test]
+    fn gpsd_reports() {
+        let fix = parse_gpsd_report(
+            r#"{"class":"TPV","mode":3,"time":"2024-05-01T10:00:00.000Z","lat":51.5,"lon":-0.12,"altMSL":35.2,"epx":4.0,"epy":6.5}"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!((fix.latitude, fix.longitude), (51.5, -0.12));
+        assert_eq!(fix.altitude, Some(35.2));
+        assert_eq!(fix.accuracy, Some(6.5));
+        assert_eq!(fix.timestamp, Utc.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap());
+
+        let fix = parse_gpsd_report(r#"{"class":"TPV","mode":2,"lat":51.5,"lon":-0.12,"alt":35.2,"eph":3.0}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(fix.altitude, None);
+        assert_eq!(fix.accuracy, Some(3.0));
+
+        assert_eq!(parse_gpsd_report(r#"{"class":"TPV","mode":1}"#), Some(None));
+        assert_eq!(parse_gpsd_report(r#"{"class":"SKY","satellites":[]}"#), None);
+        assert_eq!(parse_gpsd_report("not json"), None);
+    }
+
+    /// Polls `provider` until its fix satisfies `check`, for up to `limit`.
+    async fn wait_for(provider: &GpsdProvider, limit: Duration, check: impl Fn(Option<Fix>) -> bool) -> bool {
+        let deadline = Instant::now() + limit;
+        while Instant::now() < deadline {
+            if check(provider.current_fix()) {
+                return true;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn gpsd_provider_follows_a_fake_gpsd() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let provider = GpsdProvider::start(&listener.local_addr().unwrap().to_string(), DEFAULT_MAX_FIX_AGE);
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), r#"?WATCH={"enable":true,"json":true};"#);
+        assert_eq!(provider.current_fix(), None);
+
+        writer.write_all(b"{\"class\":\"VERSION\",\"release\":\"3.25\"}\n").await.unwrap();
+        writer.write_all(b"{\"class\":\"TPV\",\"mode\":3,\"lat\":51.5,\"lon\":-0.12,\"altMSL\":35.2}\n").await.unwrap();
+        assert!(wait_for(&provider, Duration::from_secs(5), |fix| fix.is_some_and(|fix| fix.latitude == 51.5)).await);
+        assert_eq!(provider.current_fix().unwrap().altitude, Some(35.2));
+
+        writer.write_all(b"{\"class\":\"TPV\",\"mode\":2,\"lat\":51.6,\"lon\":-0.13}\n").await.unwrap();
+        assert!(wait_for(&provider, Duration::from_secs(5), |fix| fix.is_some_and(|fix| fix.latitude == 51.6)).await);
+        assert_eq!(provider.current_fix().unwrap().altitude, None);
+
+        // A closed connection drops the fix, and the provider reconnects.
+        drop((lines, writer));
+        assert!(wait_for(&provider, Duration::from_secs(5), |fix| fix.is_none()).await);
+        let (stream, _) = tokio::time::timeout(RECONNECT_DELAY * 2, listener.accept()).await.unwrap().unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        assert!(lines.next_line().await.unwrap().unwrap().starts_with("?WATCH="));
+    }
+}
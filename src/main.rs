@@ -1,11 +1,13 @@
-use std::error::Error;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use chrono::{DateTime, Utc};
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::time::Duration;
+use uuid::Uuid;
 
-mod db;
-mod scan;
-mod connect;
-mod utils;
+use bluetracker::commands::{self, BackendKind, Context, ExportTable, GattOperation};
+use bluetracker::output::OutputFormat;
+use bluetracker::{backend, config, connect, db, filter, gatt, schedule, utils, BluetrackerError};
 
 #[derive(Parser, Debug)]
 #[command(version = "1.0", about = "Bluetooth CLI tool to scan, connect and track devices plus location")]
@@ -13,8 +15,50 @@ struct Args {
     /// Command to perform (scan, connect, location, nearby, history)
     #[command(subcommand)]
     command: Command,
+
+    /// Path to the SQLite database (default: ~/.bluetracker/bluetooth_devices.db)
+    #[arg(long, global = true)]
+    db: Option<String>,
+
+    /// Bluetooth backend used by scan and connect
+    #[arg(long, value_enum, default_value_t = BackendKind::Btleplug, global = true)]
+    backend: BackendKind,
+
+    /// YAML script describing the simulated devices (required with --backend sim)
+    #[arg(long, global = true)]
+    sim_script: Option<String>,
+
+    /// Path to the YAML config file (default: ~/.bluetracker/config.yaml)
+    #[arg(long, global = true)]
+    config: Option<String>,
+
+    /// Output format of query commands
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, global = true)]
+    format: OutputFormat,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum SortKey {
+    /// Most recently seen first
+    LastSeen,
+    /// Most detections first
+    Count,
+    /// Strongest RSSI first
+    Rssi,
 }
 
+impl From<SortKey> for db::DeviceSort {
+    fn from(key: SortKey) -> Self {
+        match key {
+            SortKey::LastSeen => db::DeviceSort::LastSeen,
+            SortKey::Count => db::DeviceSort::Count,
+            SortKey::Rssi => db::DeviceSort::Rssi,
+        }
+    }
+}
+
+// Parsed once per run, so the size of the scan variant does not matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Parser, Debug)]
 enum Command {
     /// Scan for Bluetooth devices
@@ -34,30 +78,152 @@ enum Command {
         /// Longitude coordinate (optional)
         #[arg(long)]
         longitude: Option<f64>,
+
+        /// Keep scanning and record detections as they arrive until Ctrl-C
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        continuous: bool,
+
+        /// Seconds between writes of pending detections in continuous mode
+        #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+        flush_interval: u64,
+
+        /// Minimum seconds between recorded detections of the same device in continuous mode
+        #[arg(long, default_value_t = 5)]
+        debounce: u64,
+
+        /// Adapter to scan on, by index or name (see `adapters list`)
+        #[arg(long, conflicts_with = "all_adapters")]
+        adapter: Option<backend::AdapterSelector>,
+
+        /// Scan on every adapter at once, recording which one heard each device
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        all_adapters: bool,
+
+        #[command(flatten)]
+        filter: ScanFilterFlags,
+
+        /// Seconds each scan listens (default: 8, or scan.duration from the config); stops a continuous scan
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        duration: Option<u64>,
+
+        /// Number of scans to run
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..), conflicts_with = "continuous")]
+        repeat: Option<u32>,
+
+        /// Seconds between the starts of repeated scans (repeats until Ctrl-C unless --repeat is given)
+        #[arg(long, conflicts_with = "continuous")]
+        interval: Option<u64>,
+
+        /// Scan whenever a cron expression such as "*/5 9-17 * * mon-fri" fires (default: scan.schedule from the config)
+        #[arg(long, conflicts_with_all = ["continuous", "repeat", "interval"])]
+        schedule: Option<Option<schedule::Schedule>>,
+
+        /// Free-text label stored with the scan session (e.g. site or operator)
+        #[arg(long)]
+        label: Option<String>,
+
+        /// Take the position of each detection from gpsd (default: location.gpsd from the config, or localhost:2947)
+        #[arg(long)]
+        gpsd: Option<Option<String>>,
+
+        /// Take the position of each detection from NMEA sentences in a serial GPS device or recorded .nmea file
+        #[arg(long)]
+        nmea: Option<String>,
     },
 
     /// Connect to a Bluetooth device by address
     Connect {
         /// Bluetooth address of the device to connect to
         address: String,
+
+        /// List the device's GATT services, characteristics and descriptors
+        #[arg(long)]
+        discover: bool,
+
+        /// Store the discovered GATT table and compare it with the last stored one
+        #[arg(long, requires = "discover")]
+        save: bool,
+
+        /// Read battery level, device information and heart rate, and record them
+        #[arg(long)]
+        info: bool,
+
+        #[command(flatten)]
+        connection: ConnectFlags,
     },
 
-    /// Get the last known location of a device
+    /// Estimate the location of a device from its detections
     Location {
         /// Bluetooth address of the device
         address: String,
+
+        /// Only use detections since this time (RFC3339 or a duration like 24h)
+        #[arg(long, value_parser = utils::parse_since)]
+        since: Option<DateTime<Utc>>,
+
+        /// Refine the estimate by trilaterating RSSI path-loss distances
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        trilaterate: bool,
     },
 
     /// Find nearby devices within a given radius
     Nearby {
         /// Latitude coordinate
+        #[arg(allow_hyphen_values = true)]
         latitude: f64,
 
         /// Longitude coordinate
+        #[arg(allow_hyphen_values = true)]
         longitude: f64,
 
         /// Radius in kilometers
         radius: f64,
+
+        /// Maximum number of devices to list
+        #[arg(short, long)]
+        limit: Option<usize>,
+
+        /// Only consider detections since this time (RFC3339 or a duration like 24h)
+        #[arg(long, value_parser = utils::parse_since)]
+        since: Option<DateTime<Utc>>,
+    },
+
+    /// Fill in the position of detections recorded without one from a GPX track
+    Geotag {
+        /// GPX file with the track logged while scanning
+        #[arg(long)]
+        gpx: String,
+
+        /// Longest gap between trackpoints to interpolate across (e.g. 60s, 5m)
+        #[arg(long, default_value = "60s", value_parser = utils::parse_duration_arg)]
+        max_gap: chrono::Duration,
+    },
+
+    /// Export geotagged detections for mapping, as given by --format geojson, kml or gpx
+    Export {
+        /// File to write (default: standard output)
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// Only export this device (repeatable)
+        #[arg(long = "address")]
+        addresses: Vec<String>,
+
+        /// Regular expression the device name has to match
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Only export detections at or after this time (RFC3339 or a duration like 24h)
+        #[arg(short, long, value_parser = utils::parse_since)]
+        start_time: Option<DateTime<Utc>>,
+
+        /// Only export detections at or before this time (RFC3339 or a duration like 24h)
+        #[arg(short, long, value_parser = utils::parse_since)]
+        end_time: Option<DateTime<Utc>>,
+
+        /// Time span of detections combined into each GPX trackpoint (e.g. 60s, 5m)
+        #[arg(long, default_value = "5m", value_parser = utils::parse_duration_arg)]
+        window: chrono::Duration,
     },
 
     /// Get the detection history of a device
@@ -65,35 +231,67 @@ enum Command {
         /// Bluetooth address of the device
         address: String,
         
-        /// Start timestamp (RFC3339 format)
-        #[arg(short, long)]
-        start_time: Option<String>,
+        /// Only show detections at or after this time (RFC3339 or a duration like 24h)
+        #[arg(short, long, value_parser = utils::parse_since)]
+        start_time: Option<DateTime<Utc>>,
 
-        /// End timestamp (RFC3339 format)
-        #[arg(short, long)]
-        end_time: Option<String>,
+        /// Only show detections at or before this time (RFC3339 or a duration like 24h)
+        #[arg(short, long, value_parser = utils::parse_since)]
+        end_time: Option<DateTime<Utc>>,
 
         /// Limit the number of results
         #[arg(short, long)]
         limit: Option<usize>,
     },
 
-     /// Get the detection history of a device
+     /// List stored devices matching search criteria
      Devices {
         /// Manufacturer ID for bluetooth device
         manufacturer_id: Option<u16>,
 
-        /// Start timestamp (RFC3339 format)
-        #[arg(short, long)]
-        start_time: Option<String>,
+        /// Only consider detections at or after this time (RFC3339 or a duration like 24h)
+        #[arg(short, long, value_parser = utils::parse_since)]
+        start_time: Option<DateTime<Utc>>,
 
-        /// End timestamp (RFC3339 format)
-        #[arg(short, long)]
-        end_time: Option<String>,
+        /// Only consider detections at or before this time (RFC3339 or a duration like 24h)
+        #[arg(short, long, value_parser = utils::parse_since)]
+        end_time: Option<DateTime<Utc>>,
+
+        /// Regular expression the device name has to match
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Minimum number of detections
+        #[arg(long)]
+        min_count: Option<u32>,
+
+        /// Minimum strongest RSSI in dBm
+        #[arg(long, allow_hyphen_values = true)]
+        min_rssi: Option<i32>,
+
+        /// Maximum strongest RSSI in dBm
+        #[arg(long, allow_hyphen_values = true)]
+        max_rssi: Option<i32>,
+
+        /// Advertised service UUID (full or short form such as 180f)
+        #[arg(long, value_parser = utils::parse_service_uuid)]
+        service: Option<Uuid>,
+
+        /// Minimum number of distinct locations the device was seen at
+        #[arg(long)]
+        min_locations: Option<u32>,
+
+        /// Sort order
+        #[arg(long, value_enum, default_value_t = SortKey::LastSeen)]
+        sort: SortKey,
 
         /// Limit the number of results
         #[arg(short, long)]
         limit: Option<usize>,
+
+        /// Skip this many results
+        #[arg(long)]
+        offset: Option<usize>,
     },
 
        /// Find the manufacturer name
@@ -101,13 +299,324 @@ enum Command {
         /// Manufacture id
         id: u16,
     },
+
+    /// List and inspect stored scan sessions
+    Sessions {
+        #[command(subcommand)]
+        command: SessionsCommand,
+    },
+
+    /// Inspect the available Bluetooth adapters
+    Adapters {
+        #[command(subcommand)]
+        command: AdaptersCommand,
+    },
+
+    /// Read, write and subscribe to GATT characteristics
+    Gatt {
+        #[command(subcommand)]
+        command: GattCommand,
+    },
+
+    /// Database maintenance
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+}
+
+/// Which advertisements `scan` keeps.
+#[derive(clap::Args, Debug)]
+struct ScanFilterFlags {
+    /// Only scan for devices advertising this service UUID (repeatable)
+    #[arg(long = "service", value_parser = utils::parse_service_uuid)]
+    services: Vec<Uuid>,
+
+    /// Regular expression the device name has to match
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Drop devices heard weaker than this RSSI in dBm
+    #[arg(long, allow_hyphen_values = true)]
+    min_rssi: Option<i32>,
+
+    /// Only keep devices with manufacturer data from this company ID (repeatable)
+    #[arg(long = "manufacturer-id")]
+    manufacturer_ids: Vec<u16>,
+
+    /// File of addresses to keep, one per line; all others are dropped
+    #[arg(long)]
+    allow_file: Option<String>,
+
+    /// File of addresses to drop, one per line
+    #[arg(long)]
+    deny_file: Option<String>,
+}
+
+impl ScanFilterFlags {
+    fn filter(&self) -> bluetracker::Result<filter::DeviceFilter> {
+        let name_pattern = self
+            .name
+            .as_deref()
+            .map(regex::Regex::new)
+            .transpose()
+            .map_err(|e| BluetrackerError::Parse(format!("invalid name pattern: {}", e)))?;
+        Ok(filter::DeviceFilter {
+            services: self.services.clone(),
+            name_pattern,
+            min_rssi: self.min_rssi,
+            manufacturer_ids: self.manufacturer_ids.clone(),
+            allow: self.allow_file.as_deref().map(filter::load_address_list).transpose()?,
+            deny: self.deny_file.as_deref().map(filter::load_address_list).transpose()?.unwrap_or_default(),
+        })
+    }
+}
+
+/// How `connect` and `gatt` find and connect to a device.
+#[derive(clap::Args, Debug)]
+struct ConnectFlags {
+    /// Adapter to connect through, by index or name (see `adapters list`)
+    #[arg(long)]
+    adapter: Option<backend::AdapterSelector>,
+
+    /// Seconds to scan for the device before giving up
+    #[arg(long, default_value_t = 10)]
+    scan_timeout: u64,
+
+    /// Overall time limit in seconds for scanning and connecting
+    #[arg(long)]
+    timeout: Option<u64>,
+
+    /// Connection attempts before giving up
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    attempts: u32,
+
+    /// Delay in milliseconds before the first retry, doubled after every failure
+    #[arg(long, default_value_t = 500)]
+    backoff: u64,
+
+    /// Longest delay in milliseconds between retries
+    #[arg(long, default_value_t = 8000)]
+    max_backoff: u64,
+
+    /// Fraction by which retry delays are randomly varied (0-1)
+    #[arg(long, default_value_t = 0.2, value_parser = connect::parse_jitter)]
+    jitter: f64,
+}
+
+impl ConnectFlags {
+    fn options(&self) -> connect::ConnectOptions {
+        connect::ConnectOptions {
+            adapter: self.adapter.clone(),
+            scan_timeout: Duration::from_secs(self.scan_timeout),
+            max_attempts: self.attempts,
+            initial_backoff: Duration::from_millis(self.backoff),
+            max_backoff: Duration::from_millis(self.max_backoff),
+            jitter: self.jitter,
+            timeout: self.timeout.map(Duration::from_secs),
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+enum GattCommand {
+    /// Read the value of a characteristic
+    Read {
+        /// Bluetooth address of the device
+        address: String,
+
+        /// Characteristic UUID (full or short form such as 2a19)
+        #[arg(value_parser = utils::parse_service_uuid)]
+        uuid: Uuid,
+
+        /// Decode the value as hex, utf8, u8, u16, u32, i8, i16 or i32 (little-endian)
+        #[arg(long = "as", value_parser = gatt::ValueFormat::from_str, default_value = "hex")]
+        value_format: gatt::ValueFormat,
+
+        #[command(flatten)]
+        connection: ConnectFlags,
+    },
+
+    /// Write a value to a characteristic
+    Write {
+        /// Bluetooth address of the device
+        address: String,
+
+        /// Characteristic UUID (full or short form such as 2a06)
+        #[arg(value_parser = utils::parse_service_uuid)]
+        uuid: Uuid,
+
+        /// Value to write, as hex (e.g. 0a1b) unless --utf8 is given
+        value: String,
+
+        /// Write the value as UTF-8 text
+        #[arg(long)]
+        utf8: bool,
+
+        /// Use a write command, which the device does not acknowledge
+        #[arg(long)]
+        without_response: bool,
+
+        #[command(flatten)]
+        connection: ConnectFlags,
+    },
+
+    /// Stream the notifications of a characteristic as NDJSON
+    Notify {
+        /// Bluetooth address of the device
+        address: String,
+
+        /// Characteristic UUID (full or short form such as 2a37)
+        #[arg(value_parser = utils::parse_service_uuid)]
+        uuid: Uuid,
+
+        /// Stop after this many seconds (default: until Ctrl-C)
+        #[arg(long)]
+        duration: Option<u64>,
+
+        /// Decode the values as hex, utf8, u8, u16, u32, i8, i16 or i32 (little-endian)
+        #[arg(long = "as", value_parser = gatt::ValueFormat::from_str, default_value = "hex")]
+        value_format: gatt::ValueFormat,
+
+        #[command(flatten)]
+        connection: ConnectFlags,
+    },
+}
+
+#[derive(Parser, Debug)]
+enum SessionsCommand {
+    /// List scan sessions, most recent first
+    List {
+        /// Maximum number of sessions to list
+        #[arg(short, long, default_value_t = 20)]
+        limit: usize,
+    },
+
+    /// Show one scan session and the strongest devices it heard
+    Show {
+        /// Session ID as shown by `sessions list`
+        id: i64,
+
+        /// Number of devices to list
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+}
+
+#[derive(Parser, Debug)]
+enum AdaptersCommand {
+    /// List every adapter with its index, name and info
+    List,
+}
+
+#[derive(Parser, Debug)]
+enum DbCommand {
+    /// Apply pending schema migrations
+    Migrate {
+        /// Only list the pending migrations
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        dry_run: bool,
+    },
+
+    /// Export the devices or detections table, as CSV or with --format ndjson
+    Export {
+        /// Table to export
+        #[arg(value_enum)]
+        table: ExportTable,
+
+        /// File to write (default: standard output)
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+
+    /// Remove old detections, optionally keeping hourly aggregates of them, and reclaim the space
+    Prune {
+        /// Remove detections older than this (e.g. 90d; default: retention.older_than from the config)
+        #[arg(long, value_parser = utils::parse_duration_arg)]
+        older_than: Option<chrono::Duration>,
+
+        /// Keep only each device's N most recent detections (default: retention.keep_per_device from the config)
+        #[arg(long)]
+        keep_per_device: Option<u32>,
+
+        /// Fold removed detections into hourly per-device aggregates (count, RSSI range and mean, centroid)
+        #[arg(long)]
+        downsample: bool,
+
+        /// Only report what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Rewrite the database with a full VACUUM, switching it to incremental auto-vacuum so that prunes reclaim space
+    Vacuum,
+
+    /// Merge the devices, detections, sessions and snapshots of another bluetracker database
+    #[command(alias = "import")]
+    Merge {
+        /// Database to merge into this one
+        other: String,
+    },
+}
+
+
+impl GattCommand {
+    fn address(&self) -> &str {
+        match self {
+            GattCommand::Read { address, .. }
+            | GattCommand::Write { address, .. }
+            | GattCommand::Notify { address, .. } => address,
+        }
+    }
+
+    fn connection(&self) -> &ConnectFlags {
+        match self {
+            GattCommand::Read { connection, .. }
+            | GattCommand::Write { connection, .. }
+            | GattCommand::Notify { connection, .. } => connection,
+        }
+    }
+
+    fn operation(&self) -> GattOperation {
+        match self {
+            GattCommand::Read { uuid, value_format, .. } => GattOperation::Read {
+                uuid: *uuid,
+                value_format: *value_format,
+            },
+            GattCommand::Write { uuid, value, utf8, without_response, .. } => GattOperation::Write {
+                uuid: *uuid,
+                value: value.clone(),
+                utf8: *utf8,
+                without_response: *without_response,
+            },
+            GattCommand::Notify { uuid, duration, value_format, .. } => GattOperation::Notify {
+                uuid: *uuid,
+                duration: duration.map(Duration::from_secs),
+                value_format: *value_format,
+            },
+        }
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
-    let db_path = db::get_db_path(None);
-    let mut db = db::BluetoothTracker::new(&db_path)?;
+async fn main() -> ExitCode {
+    match run(Args::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(args: Args) -> bluetracker::Result<()> {
+    let context = Context {
+        db_path: db::get_db_path(args.db),
+        config_path: config::get_config_path(args.config),
+        backend: args.backend,
+        sim_script: args.sim_script,
+        format: args.format,
+    };
 
     match args.command {
         Command::Scan {
@@ -115,131 +624,127 @@ async fn main() -> Result<(), Box<dyn Error>> {
             use_db,
             latitude,
             longitude,
+            continuous,
+            flush_interval,
+            debounce,
+            adapter,
+            all_adapters,
+            filter,
+            duration,
+            repeat,
+            interval,
+            schedule,
+            label,
+            gpsd,
+            nmea,
         } => {
-            let scan_options = scan::ScanOptions {
-                outpath: output,
+            let request = commands::ScanRequest {
+                output,
                 use_db,
                 latitude,
                 longitude,
+                continuous,
+                flush_interval: Duration::from_secs(flush_interval),
+                debounce: Duration::from_secs(debounce),
+                adapter,
+                all_adapters,
+                filter: filter.filter()?,
+                duration: duration.map(Duration::from_secs),
+                repeat,
+                interval: interval.map(Duration::from_secs),
+                schedule,
+                label,
+                gpsd,
+                nmea,
             };
-
-            match scan::scan_devices(scan_options).await {
-                Ok(devices) => {
-                    println!("Scan completed. Found {} devices.", devices.len());
-                }
-                Err(e) => {
-                    eprintln!("Error during scan: {}", e);
-                }
-            }
+            commands::scan(&context, request).await
         }
 
-        Command::Connect { address } => {
-            match connect::connect_to_device(&address).await {
-                Ok(_) => println!("Successfully connected to device: {}", address),
-                Err(e) => eprintln!("Error connecting to device {}: {}", address, e),
-            }
+        Command::Connect { address, discover, save, info, connection } => {
+            commands::connect(&context, &address, &connection.options(), discover, save, info).await
         }
 
-        Command::Location { address } => {
-           
-            match db.estimate_device_location(&address)? {
-                Some((lat, lon)) => println!("Last known location: ({}, {})", lat, lon),
-                None => println!("No location data found for device."),
-            }
+        Command::Location { address, since, trilaterate } => {
+            commands::location(&context, &address, since, trilaterate)
         }
 
-        Command::Nearby { latitude, longitude, radius } => {
-            let devices = db.find_devices_near(latitude, longitude, radius)?;
-            if devices.is_empty() {
-                println!("No devices found within {} km.", radius);
-            } else {
-                println!("Devices found within {} km:", radius);
-                for device in devices {
-                    println!("- {}", device);
-                }
-            }
+        Command::Nearby { latitude, longitude, radius, limit, since } => {
+            commands::nearby(&context, latitude, longitude, radius, since, limit)
         }
 
-        Command::History {
-            address,
-            start_time,
-            end_time,
-            limit,
-        } => {
-            let filters = db::FilterOptions {
-                start_time: start_time
-                    .as_deref()
-                    .map(|s| DateTime::parse_from_rfc3339(s).ok().map(|dt| dt.with_timezone(&Utc)))
-                    .flatten(),
-                end_time: end_time
-                    .as_deref()
-                    .map(|s| DateTime::parse_from_rfc3339(s).ok().map(|dt| dt.with_timezone(&Utc)))
-                    .flatten(),
-                limit,
-            };
+        Command::Geotag { gpx, max_gap } => commands::geotag(&context, &gpx, max_gap),
 
-            let history = db.get_device_history(&address, filters)?;
-            if history.is_empty() {
-                println!("No history found for device.");
-            } else {
-                println!("Detection history for {}:", address);
-                for detection in history {
-                    println!("- Time: {}, Location: ({:?}, {:?}), RSSI: {}, Tx Power: {}, Manufacturer Data: {}",
-                        detection.timestamp,
-                        detection.latitude,
-                        detection.longitude,
-                        detection.rssi,
-                        detection.tx_power,
-                        detection.manufacturer_data
-                    );
-                }
-            }
+        Command::Export { output, addresses, name, start_time, end_time, window } => {
+            let query = db::DetectionQuery {
+                addresses,
+                name_pattern: name,
+                start_time,
+                end_time,
+            };
+            commands::export(&context, &query, output.as_deref(), window)
         }
 
+        Command::History { address, start_time, end_time, limit } => {
+            let filters = db::FilterOptions { start_time, end_time, limit };
+            commands::history(&context, &address, filters)
+        }
 
         Command::Devices {
             manufacturer_id,
             start_time,
             end_time,
+            name,
+            min_count,
+            min_rssi,
+            max_rssi,
+            service,
+            min_locations,
+            sort,
             limit,
+            offset,
         } => {
-            let filters = db::FilterOptions {
-                start_time: start_time
-                    .as_deref()
-                    .map(|s| DateTime::parse_from_rfc3339(s).ok().map(|dt| dt.with_timezone(&Utc)))
-                    .flatten(),
-                end_time: end_time
-                    .as_deref()
-                    .map(|s| DateTime::parse_from_rfc3339(s).ok().map(|dt| dt.with_timezone(&Utc)))
-                    .flatten(),
+            let query = db::DeviceQuery {
+                start_time,
+                end_time,
+                manufacturer_id,
+                name_pattern: name,
+                min_detections: min_count,
+                min_rssi,
+                max_rssi,
+                service,
+                min_locations,
+                sort: sort.into(),
                 limit,
+                offset,
             };
-
-            let devices = db.get_devices(filters, manufacturer_id)?;
-            if devices.is_empty() {
-                println!("No devices found.");
-            } else {
-                println!("Stored devices for {}:",  devices.len());
-                for device in devices {
-                    println!("- Address: {}, Name: {}, Manufacturer Id: {:?}", 
-                        device.address,
-                        device.name,
-                        device.manufacturer_id
-                    );
-                }
-            }
+            commands::devices(&context, &query)
         }
 
-        Command::Brand { id } => {
-            let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
-            let path = format!("{}/.bluetracker/assets/company_identifiers.yaml", home);
-            let manufacturer_map = utils::load_manufacturer_map_from_yaml(&path)?;
-            match utils::get_manufacturer_name(&id, &manufacturer_map) {
-                Some(name) => println!("Manufacturer Name: {}", name),
-                None => println!("Manufacturer Name not found."),
-            }        
+        Command::Brand { id } => commands::brand(&context, id),
+
+        Command::Sessions { command } => match command {
+            SessionsCommand::List { limit } => commands::list_sessions(&context, limit),
+            SessionsCommand::Show { id, top } => commands::show_session(&context, id, top),
+        },
+
+        Command::Adapters { command } => match command {
+            AdaptersCommand::List => commands::list_adapters(&context).await,
+        },
+
+        Command::Gatt { command } => {
+            let options = command.connection().options();
+            commands::gatt(&context, command.address(), &options, &command.operation()).await
         }
-    }
 
-    Ok(())
+        Command::Db { command } => match command {
+            DbCommand::Migrate { dry_run } => commands::migrate(&context, dry_run),
+            DbCommand::Export { table, output } => commands::export_table(&context, table, output.as_deref()),
+            DbCommand::Prune { older_than, keep_per_device, downsample, dry_run } => {
+                let request = commands::PruneRequest { older_than, keep_per_device, downsample, dry_run };
+                commands::prune(&context, &request)
+            }
+            DbCommand::Vacuum => commands::vacuum(&context),
+            DbCommand::Merge { other } => commands::merge(&context, &other),
+        },
+    }
 }
@@ -0,0 +1,501 @@
+use chrono::Local;
+use rusqlite::{params, Connection, Result, Transaction};
+
+use crate::error::Result as BluetrackerResult;
+use crate::db::insert_manufacturer_data;
+use crate::utils::parse_manufacturer_data_debug;
+
+/// A single schema step. Steps are applied in order, each in its own
+/// transaction, and the database's `PRAGMA user_version` records the last one applied.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    /// Destructive steps rewrite existing rows, so the database file is backed up first.
+    pub destructive: bool,
+    up: fn(&Transaction) -> Result<()>,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create devices and detections tables",
+        destructive: false,
+        up: create_base_tables,
+    },
+    Migration {
+        version: 2,
+        description: "Move manufacturer data debug strings into the manufacturer_data table",
+        destructive: true,
+        up: create_manufacturer_data,
+    },
+    Migration {
+        version: 3,
+        description: "Add address type, class, advertised services and service data",
+        destructive: false,
+        up: add_advertisement_fields,
+    },
+    Migration {
+        version: 4,
+        description: "Track first/last seen, detection count and name history per device",
+        destructive: false,
+        up: add_device_tracking,
+    },
+    Migration {
+        version: 5,
+        description: "Index detection coordinates with an R*Tree",
+        destructive: false,
+        up: create_spatial_index,
+    },
+    Migration {
+        version: 6,
+        description: "Index detections by device and time, and advertised services by UUID",
+        destructive: false,
+        up: create_query_indexes,
+    },
+    Migration {
+        version: 7,
+        description: "Store discovered GATT tables per device",
+        destructive: false,
+        up: create_gatt_tables,
+    },
+    Migration {
+        version: 8,
+        description: "Record battery level and firmware snapshots per device",
+        destructive: false,
+        up: create_device_info_snapshots,
+    },
+    Migration {
+        version: 9,
+        description: "Record which adapter heard each detection",
+        destructive: false,
+        up: add_detection_adapter,
+    },
+    Migration {
+        version: 10,
+        description: "Group detections into scan sessions",
+        destructive: false,
+        up: create_scan_sessions,
+    },
+    Migration {
+        version: 11,
+        description: "Describe scan sessions: adapter, duration, filters, location, label and host",
+        destructive: false,
+        up: add_scan_session_details,
+    },
+    Migration {
+        version: 12,
+        description: "Record GPS altitude and fix accuracy with each detection",
+        destructive: false,
+        up: add_detection_fix_quality,
+    },
+    Migration {
+        version: 13,
+        description: "Record the GPS HDOP of each detection",
+        destructive: false,
+        up: add_detection_hdop,
+    },
+    Migration {
+        version: 14,
+        description: "Keep hourly per-device and per-session aggregates of downsampled detections",
+        destructive: false,
+        up: create_detection_aggregates,
+    },
+];
+
+pub struct MigrationReport {
+    pub applied: Vec<&'static Migration>,
+    pub backup: Option<String>,
+}
+
+/// The schema version a fully migrated database is at.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+pub fn current_version(conn: &Connection) -> BluetrackerResult<u32> {
+    Ok(conn.pragma_query_value(None, "user_version", |row| row.get(0))?)
+}
+
+pub fn pending(conn: &Connection) -> BluetrackerResult<Vec<&'static Migration>> {
+    let version = current_version(conn)?;
+    Ok(MIGRATIONS.iter().filter(|m| m.version > version).collect())
+}
+
+/// Applies every pending migration. Before the first destructive step runs on a
+/// database that already holds data, a copy is written next to `db_path`.
+pub fn migrate(conn: &mut Connection, db_path: &str) -> BluetrackerResult<MigrationReport> {
+    let mut report = MigrationReport { applied: Vec::new(), backup: None };
+
+    for migration in pending(conn)? {
+        if migration.destructive && report.backup.is_none() && has_data(conn)? {
+            report.backup = Some(backup(conn, db_path)?);
+        }
+
+        let transaction = conn.transaction()?;
+        (migration.up)(&transaction)?;
+        transaction.pragma_update(None, "user_version", migration.version)?;
+        transaction.commit()?;
+
+        report.applied.push(migration);
+    }
+
+    Ok(report)
+}
+
+/// Tables holding collected data; the rest only index or detail their rows.
+const DATA_TABLES: &[&str] = &[
+    "devices",
+    "detections",
+    "detection_aggregates",
+    "scan_sessions",
+    "device_name_history",
+    "gatt_snapshots",
+    "device_info_snapshots",
+];
+
+fn has_data(conn: &Connection) -> Result<bool> {
+    for table in DATA_TABLES {
+        let exists: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?)",
+            params![table],
+            |row| row.get(0),
+        )?;
+        if exists && conn.query_row(&format!("SELECT EXISTS(SELECT 1 FROM {})", table), [], |row| row.get(0))? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn backup(conn: &Connection, db_path: &str) -> BluetrackerResult<String> {
+    let version = current_version(conn)?;
+    let backup_path = format!("{}.v{}-{}.bak", db_path, version, Local::now().format("%Y%m%d_%H%M%S"));
+    conn.execute("VACUUM INTO ?", params![backup_path])?;
+    Ok(backup_path)
+}
+
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>>>()?
+        .iter()
+        .any(|name| name == column);
+    if !exists {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+    Ok(())
+}
+
+fn create_base_tables(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS devices (
+            address TEXT PRIMARY KEY,
+            name TEXT,
+            manufacturer_id TEXT
+        )",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS detections (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            device_address TEXT,
+            timestamp TEXT,
+            latitude REAL,
+            longitude REAL,
+            rssi INTEGER,
+            tx_power INTEGER,
+            manufacturer_data TEXT,
+            FOREIGN KEY(device_address) REFERENCES devices(address)
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+fn create_manufacturer_data(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS manufacturer_data (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            detection_id INTEGER NOT NULL,
+            company_id INTEGER NOT NULL,
+            payload BLOB NOT NULL,
+            FOREIGN KEY(detection_id) REFERENCES detections(id)
+        )",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS idx_manufacturer_data_detection ON manufacturer_data(detection_id)",
+        [],
+    )?;
+
+    // Earlier versions stored the `{:?}` output of the manufacturer data map on
+    // the detection. Rows that cannot be parsed are left untouched.
+    let legacy: Vec<(i64, String)> = tx
+        .prepare("SELECT id, manufacturer_data FROM detections WHERE manufacturer_data IS NOT NULL")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_>>()?;
+
+    for (detection_id, debug_string) in legacy {
+        let Some(manufacturer_data) = parse_manufacturer_data_debug(&debug_string) else {
+            continue;
+        };
+        insert_manufacturer_data(tx, detection_id, &manufacturer_data)?;
+        tx.execute(
+            "UPDATE detections SET manufacturer_data = NULL WHERE id = ?",
+            params![detection_id],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn add_advertisement_fields(tx: &Transaction) -> Result<()> {
+    add_column_if_missing(tx, "detections", "address_type", "TEXT")?;
+    add_column_if_missing(tx, "detections", "class", "INTEGER")?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS detection_services (
+            detection_id INTEGER NOT NULL,
+            uuid TEXT NOT NULL,
+            FOREIGN KEY(detection_id) REFERENCES detections(id)
+        )",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS idx_detection_services_detection ON detection_services(detection_id)",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS service_data (
+            detection_id INTEGER NOT NULL,
+            uuid TEXT NOT NULL,
+            payload BLOB NOT NULL,
+            FOREIGN KEY(detection_id) REFERENCES detections(id)
+        )",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS idx_service_data_detection ON service_data(detection_id)",
+        [],
+    )?;
+
+    Ok(())
+}
+
+fn add_device_tracking(tx: &Transaction) -> Result<()> {
+    add_column_if_missing(tx, "devices", "first_seen", "TEXT")?;
+    add_column_if_missing(tx, "devices", "last_seen", "TEXT")?;
+    add_column_if_missing(tx, "devices", "detection_count", "INTEGER NOT NULL DEFAULT 0")?;
+
+    tx.execute(
+        "UPDATE devices SET
+            first_seen = (SELECT MIN(timestamp) FROM detections WHERE device_address = devices.address),
+            last_seen = (SELECT MAX(timestamp) FROM detections WHERE device_address = devices.address),
+            detection_count = (SELECT COUNT(*) FROM detections WHERE device_address = devices.address)",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS device_name_history (
+            address TEXT NOT NULL,
+            name TEXT NOT NULL,
+            first_seen TEXT NOT NULL,
+            last_seen TEXT NOT NULL,
+            UNIQUE(address, name),
+            FOREIGN KEY(address) REFERENCES devices(address)
+        )",
+        [],
+    )?;
+
+    // Only the latest name survived in earlier versions; seed the history with it.
+    tx.execute(
+        "INSERT OR IGNORE INTO device_name_history (address, name, first_seen, last_seen)
+         SELECT address, name, first_seen, last_seen FROM devices
+         WHERE name IS NOT NULL AND name != 'Unknown' AND first_seen IS NOT NULL",
+        [],
+    )?;
+
+    Ok(())
+}
+
+fn create_spatial_index(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS detections_rtree USING rtree(
+            id, min_lat, max_lat, min_lon, max_lon
+        )",
+        [],
+    )?;
+
+    tx.execute(
+        "INSERT OR REPLACE INTO detections_rtree (id, min_lat, max_lat, min_lon, max_lon)
+         SELECT id, latitude, latitude, longitude, longitude FROM detections
+         WHERE latitude IS NOT NULL AND longitude IS NOT NULL",
+        [],
+    )?;
+
+    // Keep the index in step with detections as they are added, geotagged or pruned.
+    tx.execute_batch(
+        "CREATE TRIGGER IF NOT EXISTS detections_rtree_insert AFTER INSERT ON detections
+         WHEN NEW.latitude IS NOT NULL AND NEW.longitude IS NOT NULL
+         BEGIN
+            INSERT INTO detections_rtree (id, min_lat, max_lat, min_lon, max_lon)
+            VALUES (NEW.id, NEW.latitude, NEW.latitude, NEW.longitude, NEW.longitude);
+         END;
+
+         CREATE TRIGGER IF NOT EXISTS detections_rtree_update AFTER UPDATE OF latitude, longitude ON detections
+         BEGIN
+            DELETE FROM detections_rtree WHERE id = OLD.id;
+            INSERT INTO detections_rtree (id, min_lat, max_lat, min_lon, max_lon)
+            SELECT NEW.id, NEW.latitude, NEW.latitude, NEW.longitude, NEW.longitude
+            WHERE NEW.latitude IS NOT NULL AND NEW.longitude IS NOT NULL;
+         END;
+
+         CREATE TRIGGER IF NOT EXISTS detections_rtree_delete AFTER DELETE ON detections
+         BEGIN
+            DELETE FROM detections_rtree WHERE id = OLD.id;
+         END;",
+    )?;
+
+    Ok(())
+}
+
+fn create_query_indexes(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE INDEX IF NOT EXISTS idx_detections_device_time ON detections(device_address, timestamp);
+         CREATE INDEX IF NOT EXISTS idx_detection_services_uuid ON detection_services(uuid);",
+    )?;
+
+    Ok(())
+}
+
+fn create_gatt_tables(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS gatt_snapshots (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            device_address TEXT NOT NULL,
+            discovered_at TEXT NOT NULL
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_gatt_snapshots_device ON gatt_snapshots(device_address, discovered_at);
+
+        CREATE TABLE IF NOT EXISTS gatt_services (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            snapshot_id INTEGER NOT NULL,
+            uuid TEXT NOT NULL,
+            is_primary INTEGER NOT NULL,
+            FOREIGN KEY(snapshot_id) REFERENCES gatt_snapshots(id)
+        );
+
+        CREATE TABLE IF NOT EXISTS gatt_characteristics (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            service_id INTEGER NOT NULL,
+            uuid TEXT NOT NULL,
+            properties TEXT NOT NULL,
+            FOREIGN KEY(service_id) REFERENCES gatt_services(id)
+        );
+
+        CREATE TABLE IF NOT EXISTS gatt_descriptors (
+            characteristic_id INTEGER NOT NULL,
+            uuid TEXT NOT NULL,
+            FOREIGN KEY(characteristic_id) REFERENCES gatt_characteristics(id)
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_gatt_services_snapshot ON gatt_services(snapshot_id);
+        CREATE INDEX IF NOT EXISTS idx_gatt_characteristics_service ON gatt_characteristics(service_id);
+        CREATE INDEX IF NOT EXISTS idx_gatt_descriptors_characteristic ON gatt_descriptors(characteristic_id);",
+    )?;
+
+    Ok(())
+}
+
+fn create_device_info_snapshots(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS device_info_snapshots (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            device_address TEXT NOT NULL,
+            recorded_at TEXT NOT NULL,
+            battery_level INTEGER,
+            manufacturer_name TEXT,
+            model_number TEXT,
+            serial_number TEXT,
+            hardware_revision TEXT,
+            firmware_revision TEXT,
+            software_revision TEXT
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_device_info_snapshots_device ON device_info_snapshots(device_address, recorded_at);",
+    )?;
+
+    Ok(())
+}
+
+fn add_detection_adapter(tx: &Transaction) -> Result<()> {
+    add_column_if_missing(tx, "detections", "adapter", "TEXT")?;
+    Ok(())
+}
+
+fn create_scan_sessions(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS scan_sessions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            started_at TEXT NOT NULL,
+            ended_at TEXT
+        );",
+    )?;
+    add_column_if_missing(tx, "detections", "session_id", "INTEGER REFERENCES scan_sessions(id)")?;
+    tx.execute("CREATE INDEX IF NOT EXISTS idx_detections_session ON detections(session_id)", [])?;
+    Ok(())
+}
+
+fn add_scan_session_details(tx: &Transaction) -> Result<()> {
+    add_column_if_missing(tx, "scan_sessions", "adapter", "TEXT")?;
+    add_column_if_missing(tx, "scan_sessions", "duration_secs", "REAL")?;
+    add_column_if_missing(tx, "scan_sessions", "filters", "TEXT")?;
+    add_column_if_missing(tx, "scan_sessions", "latitude", "REAL")?;
+    add_column_if_missing(tx, "scan_sessions", "longitude", "REAL")?;
+    add_column_if_missing(tx, "scan_sessions", "label", "TEXT")?;
+    add_column_if_missing(tx, "scan_sessions", "host", "TEXT")?;
+    Ok(())
+}
+
+fn add_detection_fix_quality(tx: &Transaction) -> Result<()> {
+    add_column_if_missing(tx, "detections", "altitude", "REAL")?;
+    add_column_if_missing(tx, "detections", "accuracy", "REAL")?;
+    Ok(())
+}
+
+fn add_detection_hdop(tx: &Transaction) -> Result<()> {
+    add_column_if_missing(tx, "detections", "hdop", "REAL")?;
+    Ok(())
+}
+
+/// Aggregates of the same hour from different scan sessions are kept apart, so
+/// merging databases that downsampled the same detections does not count them
+/// twice. Rows without a session use session 0, as NULL cannot be part of the key.
+fn create_detection_aggregates(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS detection_aggregates (
+            device_address TEXT NOT NULL,
+            hour TEXT NOT NULL,
+            session_id INTEGER NOT NULL DEFAULT 0,
+            detection_count INTEGER NOT NULL,
+            rssi_count INTEGER NOT NULL,
+            min_rssi INTEGER,
+            max_rssi INTEGER,
+            mean_rssi REAL,
+            located_count INTEGER NOT NULL,
+            latitude REAL,
+            longitude REAL,
+            PRIMARY KEY(device_address, hour, session_id),
+            FOREIGN KEY(device_address) REFERENCES devices(address)
+        );",
+    )?;
+    Ok(())
+}
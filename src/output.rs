@@ -0,0 +1,104 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::Value;
+use std::io::{self, Write};
+
+use crate::error::{BluetrackerError, Result};
+
+/// How query commands print their results. The map formats are only written
+/// by `export`; the other commands reject them.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human readable text
+    #[default]
+    Text,
+    /// A single JSON document
+    Json,
+    /// One JSON object per line
+    Ndjson,
+    /// Comma separated values with a header row; nested fields are JSON encoded
+    Csv,
+    /// GeoJSON FeatureCollection of detection points (export only)
+    Geojson,
+    /// KML with a folder of timestamped placemarks per device (export only)
+    Kml,
+    /// GPX track of each device's estimated trajectory (export only)
+    Gpx,
+}
+
+/// Prints a list of records in a machine-readable format. Text output is left
+/// to the caller, which knows how to describe its own records.
+pub fn print_records<T: Serialize>(format: OutputFormat, records: &[T]) -> Result<()> {
+    write_records(io::stdout().lock(), format, records)
+}
+
+/// Writes a list of records in a machine-readable format; text writes nothing.
+pub fn write_records<T: Serialize, W: Write>(
+    mut out: W,
+    format: OutputFormat,
+    records: &[T],
+) -> Result<()> {
+    match format {
+        OutputFormat::Text => {}
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, records)?;
+            writeln!(out)?;
+        }
+        OutputFormat::Ndjson => {
+            for record in records {
+                serde_json::to_writer(&mut out, record)?;
+                writeln!(out)?;
+            }
+        }
+        OutputFormat::Csv => write_csv(&mut out, records)?,
+        OutputFormat::Geojson | OutputFormat::Kml | OutputFormat::Gpx => {
+            let name = format.to_possible_value().map(|value| value.get_name().to_string()).unwrap_or_default();
+            return Err(BluetrackerError::Usage(format!("--format {} is only supported by export", name)));
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Prints a single, possibly missing, record. JSON prints the object or `null`.
+pub fn print_record<T: Serialize>(format: OutputFormat, record: Option<&T>) -> Result<()> {
+    match format {
+        OutputFormat::Json => {
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            serde_json::to_writer_pretty(&mut out, &record)?;
+            writeln!(out)?;
+            Ok(())
+        }
+        _ => print_records(format, record.as_slice()),
+    }
+}
+
+fn write_csv<T: Serialize, W: Write>(out: W, records: &[T]) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    let mut header: Option<Vec<String>> = None;
+
+    for record in records {
+        let Value::Object(fields) = serde_json::to_value(record)? else {
+            return Err(BluetrackerError::Usage("--format csv needs records with named fields".into()));
+        };
+        if header.is_none() {
+            let names: Vec<String> = fields.keys().cloned().collect();
+            writer.write_record(&names)?;
+            header = Some(names);
+        }
+        writer.write_record(fields.values().map(csv_cell))?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+fn csv_cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Bool(_) | Value::Number(_) => value.to_string(),
+        Value::Array(_) | Value::Object(_) => value.to_string(),
+    }
+}
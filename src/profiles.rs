@@ -0,0 +1,268 @@
+use futures::StreamExt;
+use serde::Serialize;
+use std::time::Duration;
+
+use crate::backend::BluetoothPeripheral;
+use crate::error::Result;
+use crate::gatt::{self, short_uuid};
+
+const BATTERY_SERVICE: u16 = 0x180f;
+const DEVICE_INFORMATION_SERVICE: u16 = 0x180a;
+const HEART_RATE_SERVICE: u16 = 0x180d;
+
+const BATTERY_LEVEL: u16 = 0x2a19;
+const MANUFACTURER_NAME: u16 = 0x2a29;
+const MODEL_NUMBER: u16 = 0x2a24;
+const SERIAL_NUMBER: u16 = 0x2a25;
+const HARDWARE_REVISION: u16 = 0x2a27;
+const FIRMWARE_REVISION: u16 = 0x2a26;
+const SOFTWARE_REVISION: u16 = 0x2a28;
+const HEART_RATE_MEASUREMENT: u16 = 0x2a37;
+const BODY_SENSOR_LOCATION: u16 = 0x2a38;
+
+/// How long to wait for the first heart rate measurement after subscribing.
+const MEASUREMENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Facts decoded from the standard services a device exposes. Services the
+/// device does not have are left out.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DeviceProfile {
+    /// Battery level in percent.
+    pub battery_level: Option<u8>,
+    pub device_information: Option<DeviceInformation>,
+    pub heart_rate: Option<HeartRate>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DeviceInformation {
+    pub manufacturer_name: Option<String>,
+    pub model_number: Option<String>,
+    pub serial_number: Option<String>,
+    pub hardware_revision: Option<String>,
+    pub firmware_revision: Option<String>,
+    pub software_revision: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HeartRate {
+    pub body_sensor_location: Option<&'static str>,
+    pub measurement: Option<HeartRateMeasurement>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HeartRateMeasurement {
+    pub bpm: u16,
+    /// `None` when the sensor does not report skin contact.
+    pub sensor_contact: Option<bool>,
+    /// Energy expended in kilojoules since the last reset.
+    pub energy_expended_kj: Option<u16>,
+    /// Intervals between beats in seconds.
+    pub rr_intervals: Vec<f64>,
+}
+
+/// Reads the Battery, Device Information and Heart Rate services of a connected
+/// peripheral when present. Characteristics that fail to read are skipped.
+pub async fn read_profile(peripheral: &dyn BluetoothPeripheral) -> Result<DeviceProfile> {
+    if peripheral.services().is_empty() {
+        peripheral.discover_services().await?;
+    }
+    let services: Vec<u16> = peripheral.services().iter().filter_map(|s| short_uuid(&s.uuid)).collect();
+    let mut profile = DeviceProfile::default();
+
+    if services.contains(&BATTERY_SERVICE) {
+        profile.battery_level = read(peripheral, BATTERY_LEVEL).await.and_then(|data| decode_battery_level(&data));
+    }
+
+    if services.contains(&DEVICE_INFORMATION_SERVICE) {
+        let information = DeviceInformation {
+            manufacturer_name: read_string(peripheral, MANUFACTURER_NAME).await,
+            model_number: read_string(peripheral, MODEL_NUMBER).await,
+            serial_number: read_string(peripheral, SERIAL_NUMBER).await,
+            hardware_revision: read_string(peripheral, HARDWARE_REVISION).await,
+            firmware_revision: read_string(peripheral, FIRMWARE_REVISION).await,
+            software_revision: read_string(peripheral, SOFTWARE_REVISION).await,
+        };
+        // Nothing readable is as good as no service, and not worth a snapshot.
+        profile.device_information = (information != DeviceInformation::default()).then_some(information);
+    }
+
+    if services.contains(&HEART_RATE_SERVICE) {
+        profile.heart_rate = Some(HeartRate {
+            body_sensor_location: read_optional(peripheral, BODY_SENSOR_LOCATION)
+                .await
+                .and_then(|data| data.first().map(|location| body_sensor_location(*location))),
+            measurement: next_heart_rate_measurement(peripheral).await,
+        });
+    }
+
+    Ok(profile)
+}
+
+/// Battery level (0x2A19): one byte, 0-100 percent.
+pub fn decode_battery_level(data: &[u8]) -> Option<u8> {
+    data.first().copied().filter(|level| *level <= 100)
+}
+
+/// Device Information strings are UTF-8, sometimes padded with NULs.
+pub fn decode_string(data: &[u8]) -> Option<String> {
+    let value = String::from_utf8_lossy(data).trim_end_matches('\0').trim().to_string();
+    (!value.is_empty()).then_some(value)
+}
+
+/// Heart Rate Measurement (0x2A37): a flags byte followed by the heart rate as
+/// u8 or u16, the optional energy expended (u16) and RR intervals (u16, 1/1024 s).
+pub fn decode_heart_rate_measurement(data: &[u8]) -> Option<HeartRateMeasurement> {
+    let (&flags, mut rest) = data.split_first()?;
+
+    let bpm = if flags & 0x01 != 0 {
+        let (value, tail) = take_u16(rest)?;
+        rest = tail;
+        value
+    } else {
+        let (&value, tail) = rest.split_first()?;
+        rest = tail;
+        u16::from(value)
+    };
+
+    let sensor_contact = (flags & 0x04 != 0).then_some(flags & 0x02 != 0);
+
+    let energy_expended_kj = if flags & 0x08 != 0 {
+        let (value, tail) = take_u16(rest)?;
+        rest = tail;
+        Some(value)
+    } else {
+        None
+    };
+
+    let mut rr_intervals = Vec::new();
+    if flags & 0x10 != 0 {
+        while let Some((value, tail)) = take_u16(rest) {
+            rr_intervals.push(f64::from(value) / 1024.0);
+            rest = tail;
+        }
+    }
+
+    Some(HeartRateMeasurement { bpm, sensor_contact, energy_expended_kj, rr_intervals })
+}
+
+/// Body Sensor Location (0x2A38).
+pub fn body_sensor_location(value: u8) -> &'static str {
+    match value {
+        1 => "Chest",
+        2 => "Wrist",
+        3 => "Finger",
+        4 => "Hand",
+        5 => "Ear Lobe",
+        6 => "Foot",
+        _ => "Other",
+    }
+}
+
+fn take_u16(data: &[u8]) -> Option<(u16, &[u8])> {
+    let bytes = data.get(..2)?;
+    Some((u16::from_le_bytes([bytes[0], bytes[1]]), &data[2..]))
+}
+
+async fn read(peripheral: &dyn BluetoothPeripheral, characteristic: u16) -> Option<Vec<u8>> {
+    let uuid = gatt::from_short_uuid(characteristic);
+    match gatt::read_characteristic(peripheral, &uuid).await {
+        Ok(data) => Some(data),
+        Err(e) => {
+            eprintln!("Could not read {}: {}", gatt::describe(&uuid), e);
+            None
+        }
+    }
+}
+
+/// Reads an optional characteristic; unlike [`read`], one the device lacks is not reported.
+async fn read_optional(peripheral: &dyn BluetoothPeripheral, characteristic: u16) -> Option<Vec<u8>> {
+    gatt::find_characteristic(peripheral, &gatt::from_short_uuid(characteristic)).await.ok()?;
+    read(peripheral, characteristic).await
+}
+
+/// Every Device Information characteristic is optional.
+async fn read_string(peripheral: &dyn BluetoothPeripheral, characteristic: u16) -> Option<String> {
+    read_optional(peripheral, characteristic).await.and_then(|data| decode_string(&data))
+}
+
+/// Heart rate measurements are only notified, so wait for the first one.
+async fn next_heart_rate_measurement(peripheral: &dyn BluetoothPeripheral) -> Option<HeartRateMeasurement> {
+    let uuid = gatt::from_short_uuid(HEART_RATE_MEASUREMENT);
+    let notifications = match gatt::subscribe(peripheral, &uuid).await {
+        Ok(notifications) => notifications,
+        Err(e) => {
+            eprintln!("Could not subscribe to {}: {}", gatt::describe(&uuid), e);
+            return None;
+        }
+    };
+    tokio::pin!(notifications);
+    let data = tokio::time::timeout(MEASUREMENT_TIMEOUT, notifications.next()).await.ok().flatten();
+    if let Err(e) = gatt::unsubscribe(peripheral, &uuid).await {
+        eprintln!("Could not unsubscribe from {}: {}", gatt::describe(&uuid), e);
+    }
+    data.and_then(|data| decode_heart_rate_measurement(&data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_u8_heart_rate() {
+        assert_eq!(
+            decode_heart_rate_measurement(&[0x00, 72]),
+            Some(HeartRateMeasurement { bpm: 72, sensor_contact: None, energy_expended_kj: None, rr_intervals: vec![] })
+        );
+    }
+
+    #[test]
+    fn decodes_all_fields() {
+        // u16 rate 300, contact detected, 1000 kJ, RR intervals of 1 s and 0.5 s.
+        let data = [0x1f, 0x2c, 0x01, 0xe8, 0x03, 0x00, 0x04, 0x00, 0x02];
+        let measurement = decode_heart_rate_measurement(&data).unwrap();
+        assert_eq!(measurement.bpm, 300);
+        assert_eq!(measurement.sensor_contact, Some(true));
+        assert_eq!(measurement.energy_expended_kj, Some(1000));
+        assert_eq!(measurement.rr_intervals, vec![1.0, 0.5]);
+
+        // Contact supported but not detected.
+        assert_eq!(decode_heart_rate_measurement(&[0x04, 60]).unwrap().sensor_contact, Some(false));
+    }
+
+    #[tokio::test]
+    async fn device_information_without_readable_characteristics_is_left_out() {
+        use crate::backend::BluetoothBackend;
+        use crate::simulator::{SimScript, SimulatedBackend};
+
+        let script: SimScript = serde_yaml::from_str(
+            r#"
+devices:
+  - address: "AA:BB:CC:DD:EE:01"
+    gatt:
+      - uuid: "180a"
+        characteristics:
+          - uuid: "2a29"
+            properties: [write]
+"#,
+        )
+        .unwrap();
+        let backend = SimulatedBackend::from_script(script).unwrap();
+        let adapter = backend.adapters().await.unwrap().remove(0);
+        adapter.start_scan(Default::default()).await.unwrap();
+        let device = adapter.peripheral("AA:BB:CC:DD:EE:01").await.unwrap().unwrap();
+        device.connect().await.unwrap();
+
+        let profile = read_profile(device.as_ref()).await.unwrap();
+        assert!(profile.device_information.is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_measurements() {
+        assert_eq!(decode_heart_rate_measurement(&[]), None);
+        assert_eq!(decode_heart_rate_measurement(&[0x00]), None);
+        assert_eq!(decode_heart_rate_measurement(&[0x01, 0x2c]), None);
+        assert_eq!(decode_heart_rate_measurement(&[0x08, 60, 0x01]), None);
+        // A dangling RR byte is ignored.
+        assert_eq!(decode_heart_rate_measurement(&[0x10, 60, 0x00, 0x04, 0x01]).unwrap().rr_intervals, vec![1.0]);
+    }
+}
@@ -1,90 +1,525 @@
-use serde_json;
-
-use btleplug::api::{Central, Peripheral, ScanFilter, Manager};
-use btleplug::platform::Manager as PlatformManager;
-use std::error::Error;
+use btleplug::api::{AddressType, PeripheralProperties};
+use futures::future::join_all;
+use futures::stream::{self, StreamExt};
+use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use chrono::Local;
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{Local, Utc};
+use tokio::sync::watch;
+use tokio::time::{sleep, sleep_until, Instant};
+
+use crate::backend::{adapter_name, select_adapter, AdapterSelector, BluetoothAdapter, BluetoothBackend};
+use crate::db::{BluetoothTracker, DeviceScanData, NewScanSession, RetentionPolicy, get_db_path};
+use crate::error::{BluetrackerError, Result};
+use crate::filter::DeviceFilter;
+use crate::location::{Fix, LocationProvider};
+use crate::schedule::Schedule;
+use crate::utils::hostname;
 
-use crate::db::{BluetoothTracker, DeviceScanData, get_db_path};
+/// How long a snapshot scan listens unless told otherwise.
+pub const DEFAULT_SCAN_DURATION: Duration = Duration::from_secs(8);
 
+#[derive(Debug, Clone, Default)]
 pub struct ScanOptions {
     pub outpath: Option<String>, 
     pub use_db: bool,
+    pub db_path: Option<String>,
     pub latitude: Option<f64>,
     pub longitude: Option<f64>,
+    /// Adapter to scan on; the first one when unset.
+    pub adapter: Option<AdapterSelector>,
+    /// Scan on every adapter at once instead of a single one.
+    pub all_adapters: bool,
+    /// Advertisements not matching the filter are neither printed nor stored.
+    pub filter: DeviceFilter,
+    /// How long a snapshot listens ([`DEFAULT_SCAN_DURATION`] when unset); a
+    /// continuous scan stops after this long instead of waiting for Ctrl-C.
+    pub duration: Option<Duration>,
+    /// Free-text note stored with the scan session, e.g. where or by whom the scan was made.
+    pub label: Option<String>,
+    /// Live position source. `latitude`/`longitude` are used while it has no fix.
+    pub location: Option<Arc<dyn LocationProvider>>,
+    /// Pruning applied to the database after each stored scan.
+    pub retention: Option<RetentionPolicy>,
+}
+
+impl ScanOptions {
+    fn current_fix(&self) -> Option<Fix> {
+        self.location.as_ref().and_then(|location| location.current_fix())
+    }
+}
+
+/// When [`scan_repeatedly`] starts the next snapshot scan.
+#[derive(Debug, Clone)]
+pub enum Repetition {
+    /// `count` scans (unlimited when `None`), each starting `interval` after the previous one started.
+    Every { interval: Duration, count: Option<u32> },
+    /// Whenever the cron schedule fires.
+    Schedule(Schedule),
+}
+
+/// The database a scan writes to, and the session its detections are filed under.
+struct SessionStore {
+    db: BluetoothTracker,
+    session_id: i64,
+    retention: Option<RetentionPolicy>,
 }
 
-pub async fn scan_devices(options: ScanOptions) -> Result<Vec<DeviceScanData>, Box<dyn Error>> {
-    let manager = PlatformManager::new().await?;
-    let adapters = manager.adapters().await?;
-    let central = match adapters.first() {
-        Some(adapter) => adapter.clone(),
-        None => {
-            eprintln!("No Bluetooth adapters found.");
-            return Ok(vec![]);
+impl SessionStore {
+    /// Opens the database and starts a session, unless the scan is not stored.
+    /// `duration` is `None` for scans without a fixed length.
+    fn open(options: &ScanOptions, adapters: &[ScanAdapter], duration: Option<Duration>) -> Result<Option<Self>> {
+        if !options.use_db {
+            return Ok(None);
         }
-    };
+        let db = BluetoothTracker::new(&get_db_path(options.db_path.clone()))?;
+        let adapter_names: Vec<&str> = adapters.iter().map(|adapter| adapter.name.as_str()).collect();
+        let fix = options.current_fix();
+        let session_id = db.start_scan_session(&NewScanSession {
+            started_at: Utc::now(),
+            adapter: Some(adapter_names.join(",")),
+            duration_secs: duration.map(|duration| duration.as_secs_f64()),
+            filters: options.filter.to_json(),
+            latitude: fix.map_or(options.latitude, |fix| Some(fix.latitude)),
+            longitude: fix.map_or(options.longitude, |fix| Some(fix.longitude)),
+            label: options.label.clone(),
+            host: hostname(),
+        })?;
+        Ok(Some(Self { db, session_id, retention: options.retention.clone() }))
+    }
+
+    fn store(&mut self, device_list: &[DeviceScanData]) -> Result<()> {
+        self.db.store_session_scan_data(self.session_id, device_list)
+    }
 
-    println!("Scanning for Bluetooth devices...");
-    central.start_scan(ScanFilter::default()).await?;
-    tokio::time::sleep(std::time::Duration::from_secs(8)).await;
+    /// Ends the session, then prunes the database if a retention policy is set.
+    fn finish(&mut self) -> Result<()> {
+        self.db.finish_scan_session(self.session_id, Utc::now())?;
 
-    let devices = central.peripherals().await?;
-    let mut device_list = Vec::new();
+        let Some(policy) = &self.retention else {
+            return Ok(());
+        };
+        let summary = self.db.prune(policy, false)?;
+        if summary.detections_removed > 0 {
+            let freed = self.db.vacuum()?;
+            println!(
+                "Pruned {} old detections of {} devices ({} KiB freed)",
+                summary.detections_removed,
+                summary.devices_affected,
+                freed / 1024
+            );
+        }
+        Ok(())
+    }
 
-    let db = if options.use_db {
-        Some(BluetoothTracker::new(&get_db_path(None))?)
+    /// Ends the session of a scan that failed. It is removed if nothing was
+    /// stored under it, e.g. when no adapter could scan.
+    fn abort(&mut self) -> Result<()> {
+        if self.db.discard_empty_scan_session(self.session_id)? {
+            return Ok(());
+        }
+        self.finish()
+    }
+
+    /// Ends the session as [`finish`](Self::finish) or [`abort`](Self::abort) depending on how the scan went.
+    fn close<T>(session: &mut Option<Self>, result: &Result<T>) -> Result<()> {
+        match session {
+            Some(session) if result.is_err() => session.abort(),
+            Some(session) => session.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Settings for `scan --continuous`, which streams detections until interrupted.
+pub struct ContinuousOptions {
+    /// How often pending detections are written out.
+    pub flush_interval: Duration,
+    /// Minimum time between two recorded detections of the same device.
+    pub debounce: Duration,
+}
+
+/// An adapter taking part in a scan, with the name its detections are tagged with.
+struct ScanAdapter {
+    name: String,
+    info: String,
+    adapter: Box<dyn BluetoothAdapter>,
+}
+
+async fn scan_adapters(backend: &dyn BluetoothBackend, options: &ScanOptions) -> Result<Vec<ScanAdapter>> {
+    let adapters = if options.all_adapters {
+        backend.adapters().await?
     } else {
-        None
+        vec![select_adapter(backend, options.adapter.as_ref()).await?]
     };
+    if adapters.is_empty() {
+        return Err(BluetrackerError::NoAdapter);
+    }
 
-    if devices.is_empty() {
-        println!("No devices found.");
-    } else {
-        println!("Found {} devices:", devices.len());
-
-        for device in devices {
-            let properties = device.properties().await?;
-            if let Some(props) = properties {
-                let device_data = DeviceScanData {
-                    name: props.local_name.clone(),
-                    address: device.address().to_string(),
-                    rssi: props.rssi.unwrap_or(0) as i32,
-                    tx_power: props.tx_power_level.unwrap_or(0) as i32,
-                    manufacturer_data: if props.manufacturer_data.is_empty() {
-                        "".to_string()
-                    } else {
-                        format!("{:?}", props.manufacturer_data)
-                    },
-                    latitude: options.latitude,
-                    longitude: options.longitude,
-                };
+    let mut scan_adapters = Vec::new();
+    for adapter in adapters {
+        let info = adapter.info().await?;
+        scan_adapters.push(ScanAdapter { name: adapter_name(&info).to_string(), info, adapter });
+    }
+    Ok(scan_adapters)
+}
 
-                println!("{:?}", device_data);
-                device_list.push(device_data.clone());
+/// Pairs each adapter that succeeded with its position and result. The failed
+/// ones are reported, and the scan only fails when every adapter did.
+fn working_adapters<T>(adapters: &[ScanAdapter], results: Vec<Result<T>>) -> Result<Vec<(usize, T)>> {
+    let mut working = Vec::new();
+    let mut last_error = None;
+    for (index, result) in results.into_iter().enumerate() {
+        match result {
+            Ok(value) => working.push((index, value)),
+            Err(e) => {
+                eprintln!("Scan on {} failed: {}", adapters[index].info, e);
+                last_error = Some(e);
             }
         }
+    }
+    match last_error {
+        Some(e) if working.is_empty() => Err(e),
+        _ => Ok(working),
+    }
+}
+
+fn to_scan_data(
+    address: String,
+    props: &PeripheralProperties,
+    options: &ScanOptions,
+    adapter: &str,
+) -> DeviceScanData {
+    let fix = options.current_fix();
+    DeviceScanData {
+        timestamp: Utc::now(),
+        name: props.local_name.clone(),
+        address,
+        address_type: props.address_type.map(|kind| match kind {
+            AddressType::Public => "public".to_string(),
+            AddressType::Random => "random".to_string(),
+        }),
+        rssi: props.rssi.map(i32::from),
+        tx_power: props.tx_power_level.map(i32::from),
+        manufacturer_data: props.manufacturer_data.clone(),
+        services: props.services.clone(),
+        service_data: props.service_data.clone(),
+        class: props.class,
+        latitude: fix.map_or(options.latitude, |fix| Some(fix.latitude)),
+        longitude: fix.map_or(options.longitude, |fix| Some(fix.longitude)),
+        altitude: fix.and_then(|fix| fix.altitude),
+        accuracy: fix.and_then(|fix| fix.accuracy),
+        hdop: fix.and_then(|fix| fix.hdop),
+        adapter: Some(adapter.to_string()),
+    }
+}
+
+pub async fn scan_devices(backend: &dyn BluetoothBackend, options: ScanOptions) -> Result<Vec<DeviceScanData>> {
+    scan_devices_until(backend, options, None).await
+}
+
+/// A snapshot scan that is cut short, but still stored, once `stop` turns true.
+async fn scan_devices_until(
+    backend: &dyn BluetoothBackend,
+    options: ScanOptions,
+    stop: Option<&watch::Receiver<bool>>,
+) -> Result<Vec<DeviceScanData>> {
+    let adapters = scan_adapters(backend, &options).await?;
+    let duration = options.duration.unwrap_or(DEFAULT_SCAN_DURATION);
+    let mut session = SessionStore::open(&options, &adapters, Some(duration))?;
+
+    let result: Result<Vec<DeviceScanData>> = async {
+        let snapshots = join_all(adapters.iter().map(|adapter| snapshot(adapter, &options, stop))).await;
+        let device_list: Vec<DeviceScanData> =
+            working_adapters(&adapters, snapshots)?.into_iter().flat_map(|(_, devices)| devices).collect();
+
+        if device_list.is_empty() {
+            println!("No devices found.");
+        } else {
+            println!("Found {} devices:", device_list.len());
+
+            for device_data in &device_list {
+                println!("{:?}", device_data);
+            }
+
+            if let Some(session) = &mut session {
+                session.store(&device_list)?;
+            }
 
-        if let Some(mut db) = db {
-            db.store_scan_data_batch(&device_list)?;
+            if let Some(outpath) = options.outpath.as_deref() {
+                save_device_list(outpath, &device_list).await?;
+            }
         }
+        Ok(device_list)
+    }
+    .await;
 
-        if let Some(outpath) = options.outpath {
-            save_device_list(&outpath, &device_list).await?;
+    let finished = SessionStore::close(&mut session, &result);
+    let device_list = result?;
+    finished?;
+    Ok(device_list)
+}
+
+/// Scans on one adapter for a fixed time, or until `stop` turns true, and
+/// returns what it heard. Devices whose properties cannot be read are skipped.
+async fn snapshot(
+    scan_adapter: &ScanAdapter,
+    options: &ScanOptions,
+    stop: Option<&watch::Receiver<bool>>,
+) -> Result<Vec<DeviceScanData>> {
+    let central = &scan_adapter.adapter;
+
+    println!("Scanning for Bluetooth devices on {}...", scan_adapter.info);
+    central.start_scan(options.filter.scan_filter()).await?;
+    let mut stop = stop.cloned();
+    tokio::select! {
+        _ = sleep(options.duration.unwrap_or(DEFAULT_SCAN_DURATION)) => {}
+        Some(Ok(_)) = async { Some(stop.as_mut()?.wait_for(|stopped| *stopped).await) } => {
+            println!("Scan on {} cut short.", scan_adapter.info);
         }
     }
+    central.stop_scan().await?;
 
+    let mut device_list = Vec::new();
+    for device in central.peripherals().await? {
+        let props = match device.properties().await {
+            Ok(Some(props)) => props,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("Skipping {} on {}: {}", device.address(), scan_adapter.info, e);
+                continue;
+            }
+        };
+        let device_data = to_scan_data(device.address(), &props, options, &scan_adapter.name);
+        if options.filter.matches(&device_data) {
+            device_list.push(device_data);
+        }
+    }
     Ok(device_list)
 }
 
-pub async fn save_device_list(output: &str, device_list: &[DeviceScanData]) -> std::io::Result<()> {
+/// Runs snapshot scans as the repetition dictates until it is exhausted or
+/// Ctrl-C is pressed. Ctrl-C during a run cuts that scan short and stores what
+/// it heard. A failed run is reported without stopping later ones.
+/// Returns the number of runs started.
+pub async fn scan_repeatedly(
+    backend: &dyn BluetoothBackend,
+    options: ScanOptions,
+    repetition: &Repetition,
+) -> Result<u32> {
+    let (stop_sender, mut stop) = watch::channel(false);
+    let ctrl_c = tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            stop_sender.send_replace(true);
+        }
+    });
+    let mut runs = 0;
+    let mut next_start = Instant::now();
+
+    loop {
+        let start = match repetition {
+            Repetition::Every { count, .. } => {
+                if count.is_some_and(|count| runs >= count) {
+                    break;
+                }
+                next_start
+            }
+            Repetition::Schedule(schedule) => {
+                let now = Local::now();
+                let Some(next) = schedule.next_after(now) else {
+                    println!("Schedule '{}' does not fire within the next year.", schedule);
+                    break;
+                };
+                println!("Next scan at {}", next.format("%Y-%m-%d %H:%M"));
+                Instant::now() + (next - now).to_std().unwrap_or_default()
+            }
+        };
+
+        tokio::select! {
+            Ok(_) = stop.wait_for(|stopped| *stopped) => {}
+            _ = sleep_until(start) => {}
+        }
+        if *stop.borrow() {
+            println!("Stopping scans...");
+            break;
+        }
+
+        if let Repetition::Every { interval, .. } = repetition {
+            next_start = Instant::now() + *interval;
+        }
+        runs += 1;
+        match scan_devices_until(backend, options.clone(), Some(&stop)).await {
+            Ok(devices) => println!("Scan run {} found {} devices.", runs, devices.len()),
+            Err(e) => eprintln!("Scan run {} failed: {}", runs, e),
+        }
+    }
+
+    ctrl_c.abort();
+    Ok(runs)
+}
+
+/// Scans until Ctrl-C (or the configured duration), recording detections as
+/// advertisements arrive instead of taking a single snapshot. Returns the
+/// number of detections recorded.
+pub async fn scan_continuous(
+    backend: &dyn BluetoothBackend,
+    options: ScanOptions,
+    continuous: ContinuousOptions,
+) -> Result<usize> {
+    let adapters = scan_adapters(backend, &options).await?;
+    let mut session = SessionStore::open(&options, &adapters, options.duration)?;
+    let mut pending: Vec<DeviceScanData> = Vec::new();
+    // One file per session, which every flush appends to.
+    let output = options.outpath.as_deref().map(timestamped_path);
+
+    let result = record_events(&adapters, &options, &continuous, &mut session, output.as_deref(), &mut pending).await;
+
+    // However the scan ended, stop the adapters, keep what was heard and close the session.
+    for scan_adapter in &adapters {
+        if let Err(e) = scan_adapter.adapter.stop_scan().await {
+            eprintln!("Failed to stop scanning on {}: {}", scan_adapter.info, e);
+        }
+    }
+    let flushed = flush_pending(&mut session, output.as_deref(), &mut pending);
+    let finished = SessionStore::close(&mut session, &result);
+    if let Some(path) = output.as_ref().filter(|path| path.exists()) {
+        println!("Detections saved to {}", path.display());
+    }
+
+    let recorded = result? + flushed?;
+    finished?;
+    Ok(recorded)
+}
+
+/// Listens for advertisements until Ctrl-C, the deadline or the end of the
+/// event streams, flushing detections periodically. Devices that cannot be
+/// read are reported and skipped. Returns the number of detections flushed.
+async fn record_events(
+    adapters: &[ScanAdapter],
+    options: &ScanOptions,
+    continuous: &ContinuousOptions,
+    session: &mut Option<SessionStore>,
+    output: Option<&Path>,
+    pending: &mut Vec<DeviceScanData>,
+) -> Result<usize> {
+    let mut started = Vec::new();
+    for scan_adapter in adapters {
+        started.push(
+            async {
+                let events = scan_adapter.adapter.events().await?;
+                scan_adapter.adapter.start_scan(options.filter.scan_filter()).await?;
+                println!("Continuously scanning on {} (Ctrl-C to stop)...", scan_adapter.info);
+                Ok(events)
+            }
+            .await,
+        );
+    }
+    // Events from every adapter are merged, each tagged with the adapter's position.
+    let streams = working_adapters(adapters, started)?
+        .into_iter()
+        .map(|(index, events)| events.map(move |event| (index, event)));
+    let mut events = stream::select_all(streams);
+
+    let mut flush = tokio::time::interval(continuous.flush_interval);
+    flush.tick().await;
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
+    let deadline = async {
+        match options.duration {
+            Some(duration) => sleep(duration).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(deadline);
+
+    // Debounced per adapter, so every adapter's view of a device is kept.
+    let mut last_recorded: HashMap<(usize, String), Instant> = HashMap::new();
+    let mut recorded = 0;
+
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => {
+                println!("Stopping scan...");
+                break;
+            }
+            _ = &mut deadline => break,
+            _ = flush.tick() => {
+                recorded += flush_pending(session, output, pending)?;
+            }
+            event = events.next() => {
+                let Some((index, event)) = event else { break };
+                let scan_adapter = &adapters[index];
+                let key = (index, event.address().to_string());
+
+                let debounced = last_recorded
+                    .get(&key)
+                    .is_some_and(|last| last.elapsed() < continuous.debounce);
+                if debounced {
+                    continue;
+                }
+
+                let (address, props) = match read_properties(scan_adapter, &key.1).await {
+                    Ok(Some(found)) => found,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        eprintln!("Skipping {} on {}: {}", key.1, scan_adapter.info, e);
+                        continue;
+                    }
+                };
+                let device_data = to_scan_data(address, &props, options, &scan_adapter.name);
+                if !options.filter.matches(&device_data) {
+                    continue;
+                }
+
+                println!("{:?}", device_data);
+                last_recorded.insert(key, Instant::now());
+                pending.push(device_data);
+            }
+        }
+    }
+    Ok(recorded)
+}
+
+/// The address and advertised properties of one device, `None` when the
+/// adapter no longer knows it.
+async fn read_properties(scan_adapter: &ScanAdapter, address: &str) -> Result<Option<(String, PeripheralProperties)>> {
+    let Some(device) = scan_adapter.adapter.peripheral(address).await? else {
+        return Ok(None);
+    };
+    Ok(device.properties().await?.map(|props| (device.address(), props)))
+}
+
+/// Stores the pending detections and appends them to `output` as NDJSON. Once
+/// the database has them they are cleared, even if the file cannot be written,
+/// so the next flush does not store them again.
+fn flush_pending(
+    session: &mut Option<SessionStore>,
+    output: Option<&Path>,
+    pending: &mut Vec<DeviceScanData>,
+) -> Result<usize> {
+    if pending.is_empty() {
+        return Ok(0);
+    }
+
+    if let Some(session) = session {
+        session.store(pending)?;
+    }
+    let flushed = std::mem::take(pending);
+    if let Some(path) = output {
+        append_device_list(path, &flushed)?;
+    }
+    Ok(flushed.len())
+}
+
+/// `output` with the local time inserted before its extension.
+fn timestamped_path(output: &str) -> PathBuf {
     let timestamp = Local::now().format("%Y%m%d_%H%M%S").to_string();
 
     let path = Path::new(output);
-    let new_path: PathBuf = if let Some(file_stem) = path.file_stem().and_then(|s| s.to_str()) {
+    if let Some(file_stem) = path.file_stem().and_then(|s| s.to_str()) {
         if let Some(ext) = path.extension().and_then(|s| s.to_str()) {
             path.with_file_name(format!("{}_{}.{}", file_stem, timestamp, ext))
         } else {
@@ -92,7 +527,24 @@ pub async fn save_device_list(output: &str, device_list: &[DeviceScanData]) -> s
         }
     } else {
         PathBuf::from(format!("{}_{}", output, timestamp))
-    };
+    }
+}
+
+/// Appends one JSON object per detection to the file at `path`, creating it if needed.
+fn append_device_list(path: &Path, device_list: &[DeviceScanData]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = std::io::BufWriter::new(fs::OpenOptions::new().create(true).append(true).open(path)?);
+    for device in device_list {
+        serde_json::to_writer(&mut file, device)?;
+        file.write_all(b"\n")?;
+    }
+    file.flush()
+}
+
+pub async fn save_device_list(output: &str, device_list: &[DeviceScanData]) -> std::io::Result<()> {
+    let new_path = timestamped_path(output);
 
     if let Some(parent) = new_path.parent() {
         fs::create_dir_all(parent)?; 
@@ -0,0 +1,182 @@
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDateTime, TimeZone, Timelike};
+use std::fmt;
+use std::str::FromStr;
+
+/// How far ahead `next_after` looks before deciding a schedule never fires.
+const SEARCH_LIMIT_MINUTES: i64 = 366 * 24 * 60;
+
+const MONTH_NAMES: &[&str] = &["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+const DAY_NAMES: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// A five-field cron expression in local time: minute, hour, day of month,
+/// month and day of week. Fields accept `*`, lists, ranges and steps, e.g.
+/// `*/5 9-17 * * mon-fri` fires every five minutes during business hours.
+/// As in cron, a time matches when either day field does if both are restricted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl Schedule {
+    /// The first time strictly after `after` (at minute resolution) the schedule fires.
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = after.naive_local().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        (0..SEARCH_LIMIT_MINUTES)
+            .map(|offset| start + Duration::minutes(offset))
+            .filter(|time| self.matches(time))
+            // Skip times that do not exist locally because of a DST change.
+            .find_map(|time| Local.from_local_datetime(&time).earliest())
+    }
+
+    fn matches(&self, time: &NaiveDateTime) -> bool {
+        let day_of_month = has(self.days_of_month, time.day());
+        let day_of_week = has(self.days_of_week, time.weekday().num_days_from_sunday());
+        let day = match (self.any_day_of_month, self.any_day_of_week) {
+            (true, true) => true,
+            (true, false) => day_of_week,
+            (false, true) => day_of_month,
+            (false, false) => day_of_month || day_of_week,
+        };
+        day && has(self.minutes, time.minute()) && has(self.hours, time.hour()) && has(self.months, time.month())
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minute, hour, day_of_month, month, day_of_week] = fields[..] else {
+            return Err(format!("invalid schedule '{}': expected five fields (minute hour day month weekday)", s));
+        };
+
+        let mut days_of_week = parse_field(day_of_week, 0, 7, DAY_NAMES)?;
+        // Both 0 and 7 mean Sunday.
+        if has(days_of_week, 7) {
+            days_of_week |= 1;
+        }
+
+        Ok(Schedule {
+            expression: fields.join(" "),
+            minutes: parse_field(minute, 0, 59, &[])?,
+            hours: parse_field(hour, 0, 23, &[])?,
+            days_of_month: parse_field(day_of_month, 1, 31, &[])?,
+            months: parse_field(month, 1, 12, MONTH_NAMES)?,
+            days_of_week,
+            any_day_of_month: day_of_month == "*",
+            any_day_of_week: day_of_week == "*",
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+fn has(mask: u64, value: u32) -> bool {
+    mask & (1 << value) != 0
+}
+
+/// Parses one field into a bit mask of the values it allows.
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step.parse().map_err(|_| format!("invalid step in '{}'", part))?;
+                if step == 0 {
+                    return Err(format!("invalid step in '{}'", part));
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (parse_value(start, min, max, names)?, parse_value(end, min, max, names)?)
+        } else {
+            let start = parse_value(range, min, max, names)?;
+            // `5/10` means every tenth value starting at 5.
+            (start, if step > 1 { max } else { start })
+        };
+        if start > end {
+            return Err(format!("invalid range '{}'", range));
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+fn parse_value(value: &str, min: u32, max: u32, names: &[&str]) -> Result<u32, String> {
+    let lower = value.to_ascii_lowercase();
+    let parsed = match names.iter().position(|name| *name == lower) {
+        // Month names count from 1, day names from 0.
+        Some(index) => index as u32 + min,
+        None => value.parse().map_err(|_| format!("invalid value '{}'", value))?,
+    };
+    if parsed < min || parsed > max {
+        return Err(format!("value {} out of range {}-{}", parsed, min, max));
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        // January 2026, away from any DST change; the 5th is a Monday.
+        Local.with_ymd_and_hms(2026, 1, day, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn parses_fields() {
+        let schedule: Schedule = "*/15 9-17 * jan,feb mon-fri".parse().unwrap();
+        assert_eq!(schedule.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(schedule.hours, (9..=17).fold(0, |mask, hour| mask | 1 << hour));
+        assert_eq!(schedule.months, 1 << 1 | 1 << 2);
+        assert_eq!(schedule.days_of_week, (1..=5).fold(0, |mask, day| mask | 1 << day));
+        assert_eq!(schedule.to_string(), "*/15 9-17 * jan,feb mon-fri");
+
+        let sunday: Schedule = "0 0 * * 7".parse().unwrap();
+        assert!(has(sunday.days_of_week, 0));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for expression in ["* * * *", "60 * * * *", "*/0 * * * *", "5-1 * * * *", "* * * * funday"] {
+            assert!(expression.parse::<Schedule>().is_err(), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn finds_next_run() {
+        let schedule: Schedule = "*/5 9-17 * * mon-fri".parse().unwrap();
+        assert_eq!(schedule.next_after(local(5, 9, 2)), Some(local(5, 9, 5)));
+        assert_eq!(schedule.next_after(local(5, 9, 5)), Some(local(5, 9, 10)));
+        // Friday evening rolls over to Monday morning.
+        assert_eq!(schedule.next_after(local(9, 17, 58)), Some(local(12, 9, 0)));
+
+        // With both day fields restricted either one matches.
+        let schedule: Schedule = "0 12 15 * sun".parse().unwrap();
+        assert_eq!(schedule.next_after(local(5, 13, 0)), Some(local(11, 12, 0)));
+        assert_eq!(schedule.next_after(local(11, 13, 0)), Some(local(15, 12, 0)));
+
+        let never: Schedule = "0 0 31 2 *".parse().unwrap();
+        assert_eq!(never.next_after(local(5, 0, 0)), None);
+    }
+}
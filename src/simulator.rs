@@ -0,0 +1,606 @@
+use async_trait::async_trait;
+use btleplug::api::{
+    AddressType, BDAddr, CharPropFlags, Characteristic, Descriptor, PeripheralProperties, ScanFilter, Service,
+    ValueNotification, WriteType,
+};
+use btleplug::{Error as BtleError, Result};
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, watch};
+use uuid::Uuid;
+use tokio::time::{sleep, sleep_until, Instant};
+
+use crate::backend::{
+    AdapterEvent, BluetoothAdapter, BluetoothBackend, BluetoothPeripheral, EventStream, NotificationStream,
+};
+use crate::error::BluetrackerError;
+use crate::gatt;
+use crate::utils::parse_service_uuid;
+
+/// Scripted description of the simulated radio environment, loaded from YAML.
+///
+/// ```yaml
+/// adapters:
+///   - name: sim0
+///     rssi_offset: -6
+/// devices:
+///   - address: "AA:BB:CC:DD:EE:01"
+///     name: Sim Phone
+///     rssi: [-60, -64, -71]
+///     tx_power: -4
+///     manufacturer_data: { 76: [2, 21] }
+///     services: ["0000180f-0000-1000-8000-00805f9b34fb"]
+///     address_type: random
+///     connect_failures: 1
+///     connect_delay_ms: 200
+///     gatt:
+///       - uuid: "180f"
+///         characteristics:
+///           - uuid: "2a19"
+///             properties: [read, notify]
+///             descriptors: ["2902"]
+///             value: [100]
+///             notifications: [[99], [98]]
+///     appear_after_ms: 500
+///     update_interval_ms: 1000
+///     leave_after_ms: 10000
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct SimScript {
+    #[serde(default = "default_adapters")]
+    pub adapters: Vec<SimAdapterScript>,
+    #[serde(default)]
+    pub devices: Vec<SimDeviceScript>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SimAdapterScript {
+    pub name: String,
+    /// When set, `start_scan` fails with this message.
+    #[serde(default)]
+    pub scan_error: Option<String>,
+    /// Added to every RSSI this adapter reports, to mimic antenna placement.
+    #[serde(default)]
+    pub rssi_offset: i16,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SimDeviceScript {
+    pub address: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// RSSI walk: every properties read advances one step, holding the last value.
+    #[serde(default)]
+    pub rssi: Vec<i16>,
+    #[serde(default)]
+    pub tx_power: Option<i16>,
+    #[serde(default)]
+    pub manufacturer_data: HashMap<u16, Vec<u8>>,
+    #[serde(default)]
+    pub services: Vec<Uuid>,
+    #[serde(default)]
+    pub service_data: HashMap<Uuid, Vec<u8>>,
+    /// `public` or `random`.
+    #[serde(default)]
+    pub address_type: Option<String>,
+    #[serde(default)]
+    pub class: Option<u32>,
+    /// Number of connection attempts that fail before one succeeds.
+    #[serde(default)]
+    pub connect_failures: u32,
+    /// How long a successful connection takes to be confirmed. The link is up
+    /// from the start, as with a real stack that completes a connection the
+    /// caller has stopped waiting for.
+    #[serde(default)]
+    pub connect_delay_ms: u64,
+    /// GATT table returned by service discovery once connected.
+    #[serde(default)]
+    pub gatt: Vec<SimServiceScript>,
+    /// When set, reading the device properties fails with this message.
+    #[serde(default)]
+    pub properties_error: Option<String>,
+    /// Delay after the scan starts before the device is discovered.
+    #[serde(default)]
+    pub appear_after_ms: u64,
+    /// Interval between update events while scanning; only a discovery is emitted when unset.
+    #[serde(default)]
+    pub update_interval_ms: Option<u64>,
+    /// Time after which the device stops advertising.
+    #[serde(default)]
+    pub leave_after_ms: Option<u64>,
+}
+
+/// A GATT service. UUIDs may be given in their 16-bit short form.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SimServiceScript {
+    #[serde(deserialize_with = "deserialize_uuid")]
+    pub uuid: Uuid,
+    #[serde(default = "default_primary")]
+    pub primary: bool,
+    #[serde(default)]
+    pub characteristics: Vec<SimCharacteristicScript>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SimCharacteristicScript {
+    #[serde(deserialize_with = "deserialize_uuid")]
+    pub uuid: Uuid,
+    /// Property names such as `read`, `write`, `write_without_response`, `notify` and `indicate`.
+    #[serde(default)]
+    pub properties: Vec<String>,
+    #[serde(default, deserialize_with = "deserialize_uuids")]
+    pub descriptors: Vec<Uuid>,
+    /// Value returned by reads until it is overwritten by a write.
+    #[serde(default)]
+    pub value: Vec<u8>,
+    /// Values sent in turn, over and over, while subscribed.
+    #[serde(default)]
+    pub notifications: Vec<Vec<u8>>,
+    #[serde(default = "default_notify_interval")]
+    pub notify_interval_ms: u64,
+}
+
+fn default_primary() -> bool {
+    true
+}
+
+fn default_notify_interval() -> u64 {
+    1000
+}
+
+fn deserialize_uuid<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Uuid, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_service_uuid(&value).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_uuids<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<Uuid>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|value| parse_service_uuid(value).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn build_services(device: &SimDeviceScript) -> crate::error::Result<BTreeSet<Service>> {
+    let mut services = BTreeSet::new();
+    for service in &device.gatt {
+        let mut characteristics = BTreeSet::new();
+        for characteristic in &service.characteristics {
+            let mut properties = CharPropFlags::empty();
+            for name in &characteristic.properties {
+                let (flag, _) = gatt::parse_property(name).ok_or_else(|| {
+                    BluetrackerError::Parse(format!("invalid characteristic property '{}' for {}", name, device.address))
+                })?;
+                properties |= flag;
+            }
+            characteristics.insert(Characteristic {
+                uuid: characteristic.uuid,
+                service_uuid: service.uuid,
+                properties,
+                descriptors: characteristic
+                    .descriptors
+                    .iter()
+                    .map(|uuid| Descriptor {
+                        uuid: *uuid,
+                        service_uuid: service.uuid,
+                        characteristic_uuid: characteristic.uuid,
+                    })
+                    .collect(),
+            });
+        }
+        services.insert(Service { uuid: service.uuid, primary: service.primary, characteristics });
+    }
+    Ok(services)
+}
+
+fn default_adapters() -> Vec<SimAdapterScript> {
+    vec![SimAdapterScript { name: "sim0".to_string(), scan_error: None, rssi_offset: 0 }]
+}
+
+/// Backend that replays a `SimScript` instead of talking to a radio.
+pub struct SimulatedBackend {
+    adapters: Vec<Arc<SimAdapter>>,
+}
+
+impl SimulatedBackend {
+    pub fn from_file(path: &str) -> crate::error::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let script: SimScript = serde_yaml::from_str(&content)?;
+        Self::from_script(script)
+    }
+
+    pub fn from_script(script: SimScript) -> crate::error::Result<Self> {
+        let mut devices = Vec::new();
+        for device in script.devices {
+            let address = BDAddr::from_str(&device.address)
+                .map_err(|e| BluetrackerError::Parse(format!("invalid address '{}': {}", device.address, e)))?;
+            let address_type = match device.address_type.as_deref() {
+                None => None,
+                Some(kind) => Some(
+                    AddressType::from_str(kind)
+                        .ok_or_else(|| {
+                            BluetrackerError::Parse(format!("invalid address_type '{}' for {}", kind, device.address))
+                        })?,
+                ),
+            };
+            devices.push(Arc::new(SimDevice {
+                address,
+                address_type,
+                services: build_services(&device)?,
+                script: device,
+                rssi_step: AtomicUsize::new(0),
+                connect_attempts: AtomicU32::new(0),
+                connected: AtomicBool::new(false),
+                discovered: AtomicBool::new(false),
+                values: Mutex::new(HashMap::new()),
+                subscriptions: Mutex::new(HashSet::new()),
+                notify_tx: broadcast::channel(64).0,
+            }));
+        }
+
+        let adapters = script
+            .adapters
+            .into_iter()
+            .map(|script| {
+                Arc::new(SimAdapter {
+                    script,
+                    devices: devices.clone(),
+                    scan: watch::channel(ScanState::default()).0,
+                })
+            })
+            .collect();
+
+        Ok(Self { adapters })
+    }
+}
+
+#[async_trait]
+impl BluetoothBackend for SimulatedBackend {
+    async fn adapters(&self) -> Result<Vec<Box<dyn BluetoothAdapter>>> {
+        Ok(self
+            .adapters
+            .iter()
+            .map(|adapter| Box::new(SimAdapterHandle(adapter.clone())) as Box<dyn BluetoothAdapter>)
+            .collect())
+    }
+}
+
+struct SimAdapter {
+    script: SimAdapterScript,
+    devices: Vec<Arc<SimDevice>>,
+    /// The last scan, which both `peripherals` and the event streams follow.
+    scan: watch::Sender<ScanState>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ScanState {
+    /// When the last scan started; the device timelines run from here.
+    started: Option<Instant>,
+    /// Whether it is still running; like BlueZ, starting a second one fails.
+    running: bool,
+    /// Services from its filter; when set, only devices advertising one are reported.
+    services: Vec<Uuid>,
+}
+
+impl ScanState {
+    fn matches(&self, device: &SimDeviceScript) -> bool {
+        self.services.is_empty() || device.services.iter().any(|service| self.services.contains(service))
+    }
+}
+
+struct SimAdapterHandle(Arc<SimAdapter>);
+
+#[async_trait]
+impl BluetoothAdapter for SimAdapterHandle {
+    async fn info(&self) -> Result<String> {
+        Ok(format!("{} (simulated)", self.0.script.name))
+    }
+
+    async fn start_scan(&self, filter: ScanFilter) -> Result<()> {
+        if let Some(message) = &self.0.script.scan_error {
+            return Err(BtleError::RuntimeError(message.clone()));
+        }
+        let started = self.0.scan.send_if_modified(|state| {
+            if state.running {
+                return false;
+            }
+            *state = ScanState { started: Some(Instant::now()), running: true, services: filter.services };
+            true
+        });
+        if !started {
+            return Err(BtleError::RuntimeError("a scan is already in progress".to_string()));
+        }
+        Ok(())
+    }
+
+    async fn stop_scan(&self) -> Result<()> {
+        self.0.scan.send_if_modified(|state| std::mem::replace(&mut state.running, false));
+        Ok(())
+    }
+
+    async fn peripherals(&self) -> Result<Vec<Box<dyn BluetoothPeripheral>>> {
+        // Like a real stack, nothing is known about nearby devices until a scan has run.
+        let scan = self.0.scan.borrow().clone();
+        let Some(scan_started) = scan.started else {
+            return Ok(vec![]);
+        };
+        let elapsed = scan_started.elapsed();
+        Ok(self
+            .0
+            .devices
+            .iter()
+            .filter(|device| device.advertising_at(elapsed))
+            .filter(|device| scan.matches(&device.script))
+            .map(|device| {
+                Box::new(SimPeripheral(device.clone(), self.0.script.rssi_offset)) as Box<dyn BluetoothPeripheral>
+            })
+            .collect())
+    }
+
+    async fn events(&self) -> Result<EventStream> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        for device in &self.0.devices {
+            tokio::spawn(replay_device(device.clone(), self.0.scan.subscribe(), tx.clone()));
+        }
+        let events = futures::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|event| (event, rx))
+        });
+        Ok(Box::pin(events))
+    }
+}
+
+/// Emits the advertisement timeline of one scripted device during every scan
+/// that would report it, from the scan's start like `peripherals`. Stops when
+/// the event stream is dropped.
+async fn replay_device(
+    device: Arc<SimDevice>,
+    mut scan: watch::Receiver<ScanState>,
+    tx: mpsc::UnboundedSender<AdapterEvent>,
+) {
+    loop {
+        let state = scan.borrow_and_update().clone();
+        if let Some(started) = state.started.filter(|_| state.running && state.matches(&device.script)) {
+            tokio::select! {
+                open = replay_timeline(&device, started, &tx) => {
+                    if !open {
+                        return;
+                    }
+                }
+                changed = scan.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            }
+        }
+        tokio::select! {
+            changed = scan.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+            _ = tx.closed() => return,
+        }
+    }
+}
+
+/// Sends the events of one scan started at `started`. Returns false once the
+/// receiver is gone.
+async fn replay_timeline(device: &SimDevice, started: Instant, tx: &mpsc::UnboundedSender<AdapterEvent>) -> bool {
+    let script = &device.script;
+    let address = device.address.to_string();
+    let left = |at: Instant| script.leave_after_ms.is_some_and(|ms| at >= started + Duration::from_millis(ms));
+
+    let mut at = started + Duration::from_millis(script.appear_after_ms);
+    if left(at) {
+        return true;
+    }
+    sleep_until(at).await;
+    if tx.send(AdapterEvent::DeviceDiscovered(address.clone())).is_err() {
+        return false;
+    }
+    if !script.manufacturer_data.is_empty()
+        && tx.send(AdapterEvent::ManufacturerDataAdvertisement(address.clone())).is_err()
+    {
+        return false;
+    }
+
+    let Some(interval) = script.update_interval_ms else {
+        return true;
+    };
+    loop {
+        at += Duration::from_millis(interval);
+        if left(at) {
+            return true;
+        }
+        sleep_until(at).await;
+        if tx.send(AdapterEvent::DeviceUpdated(address.clone())).is_err() {
+            return false;
+        }
+    }
+}
+
+struct SimDevice {
+    address: BDAddr,
+    address_type: Option<AddressType>,
+    script: SimDeviceScript,
+    services: BTreeSet<Service>,
+    rssi_step: AtomicUsize,
+    connect_attempts: AtomicU32,
+    connected: AtomicBool,
+    discovered: AtomicBool,
+    /// Values written to characteristics, shadowing the scripted ones.
+    values: Mutex<HashMap<Uuid, Vec<u8>>>,
+    subscriptions: Mutex<HashSet<Uuid>>,
+    notify_tx: broadcast::Sender<ValueNotification>,
+}
+
+impl SimDevice {
+    fn characteristic_script(&self, uuid: &Uuid) -> Option<&SimCharacteristicScript> {
+        self.script
+            .gatt
+            .iter()
+            .flat_map(|service| &service.characteristics)
+            .find(|characteristic| characteristic.uuid == *uuid)
+    }
+
+    /// Checks the device is connected and the characteristic supports one of `flags`.
+    fn check_access(&self, characteristic: &Characteristic, flags: CharPropFlags) -> Result<&SimCharacteristicScript> {
+        if !self.connected.load(Ordering::SeqCst) {
+            return Err(BtleError::NotConnected);
+        }
+        let script = self.characteristic_script(&characteristic.uuid).ok_or(BtleError::NoSuchCharacteristic)?;
+        if !characteristic.properties.intersects(flags) {
+            return Err(BtleError::NotSupported(format!("{:?} on {}", flags, characteristic.uuid)));
+        }
+        Ok(script)
+    }
+
+    /// Whether the device advertises `elapsed` into its timeline.
+    fn advertising_at(&self, elapsed: Duration) -> bool {
+        elapsed >= Duration::from_millis(self.script.appear_after_ms)
+            && self.script.leave_after_ms.is_none_or(|ms| elapsed < Duration::from_millis(ms))
+    }
+
+    fn next_rssi(&self) -> Option<i16> {
+        let walk = &self.script.rssi;
+        if walk.is_empty() {
+            return None;
+        }
+        let step = self.rssi_step.fetch_add(1, Ordering::SeqCst);
+        Some(walk[step.min(walk.len() - 1)])
+    }
+}
+
+/// A scripted device as seen through one adapter, with that adapter's RSSI offset.
+struct SimPeripheral(Arc<SimDevice>, i16);
+
+#[async_trait]
+impl BluetoothPeripheral for SimPeripheral {
+    fn address(&self) -> String {
+        self.0.address.to_string()
+    }
+
+    async fn properties(&self) -> Result<Option<PeripheralProperties>> {
+        let device = &self.0;
+        if let Some(message) = &device.script.properties_error {
+            return Err(BtleError::RuntimeError(message.clone()));
+        }
+        Ok(Some(PeripheralProperties {
+            address: device.address,
+            address_type: device.address_type,
+            local_name: device.script.name.clone(),
+            tx_power_level: device.script.tx_power,
+            rssi: device.next_rssi().map(|rssi| rssi.saturating_add(self.1)),
+            manufacturer_data: device.script.manufacturer_data.clone(),
+            service_data: device.script.service_data.clone(),
+            services: device.script.services.clone(),
+            class: device.script.class,
+        }))
+    }
+
+    async fn connect(&self) -> Result<()> {
+        let device = &self.0;
+        let attempt = device.connect_attempts.fetch_add(1, Ordering::SeqCst);
+        if attempt < device.script.connect_failures {
+            return Err(BtleError::RuntimeError(format!(
+                "simulated connection failure {} of {}",
+                attempt + 1,
+                device.script.connect_failures
+            )));
+        }
+        device.connected.store(true, Ordering::SeqCst);
+        sleep(Duration::from_millis(device.script.connect_delay_ms)).await;
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        let device = &self.0;
+        if !device.connected.swap(false, Ordering::SeqCst) {
+            return Err(BtleError::NotConnected);
+        }
+        device.discovered.store(false, Ordering::SeqCst);
+        device.subscriptions.lock().unwrap().clear();
+        Ok(())
+    }
+
+    async fn discover_services(&self) -> Result<()> {
+        if !self.0.connected.load(Ordering::SeqCst) {
+            return Err(BtleError::NotConnected);
+        }
+        self.0.discovered.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn services(&self) -> BTreeSet<Service> {
+        if self.0.discovered.load(Ordering::SeqCst) {
+            self.0.services.clone()
+        } else {
+            BTreeSet::new()
+        }
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>> {
+        let script = self.0.check_access(characteristic, CharPropFlags::READ)?;
+        let values = self.0.values.lock().unwrap();
+        Ok(values.get(&characteristic.uuid).unwrap_or(&script.value).clone())
+    }
+
+    async fn write(&self, characteristic: &Characteristic, data: &[u8], write_type: WriteType) -> Result<()> {
+        let flag = match write_type {
+            WriteType::WithResponse => CharPropFlags::WRITE,
+            WriteType::WithoutResponse => CharPropFlags::WRITE_WITHOUT_RESPONSE,
+        };
+        self.0.check_access(characteristic, flag)?;
+        self.0.values.lock().unwrap().insert(characteristic.uuid, data.to_vec());
+        Ok(())
+    }
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> Result<()> {
+        let script = self.0.check_access(characteristic, CharPropFlags::NOTIFY | CharPropFlags::INDICATE)?;
+        if self.0.subscriptions.lock().unwrap().insert(characteristic.uuid) && !script.notifications.is_empty() {
+            tokio::spawn(replay_notifications(self.0.clone(), characteristic.uuid));
+        }
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, characteristic: &Characteristic) -> Result<()> {
+        self.0.subscriptions.lock().unwrap().remove(&characteristic.uuid);
+        Ok(())
+    }
+
+    async fn notifications(&self) -> Result<NotificationStream> {
+        let rx = self.0.notify_tx.subscribe();
+        let notifications = futures::stream::unfold(rx, |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(notification) => return Some((notification, rx)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        });
+        Ok(Box::pin(notifications))
+    }
+}
+
+/// Sends the scripted notifications of a characteristic until it is unsubscribed.
+async fn replay_notifications(device: Arc<SimDevice>, uuid: Uuid) {
+    let Some(script) = device.characteristic_script(&uuid) else {
+        return;
+    };
+    let interval = Duration::from_millis(script.notify_interval_ms);
+    for value in script.notifications.iter().cycle() {
+        sleep(interval).await;
+        if !device.subscriptions.lock().unwrap().contains(&uuid) {
+            return;
+        }
+        // Nobody listening yet is not an error; the value is simply missed.
+        let _ = device.notify_tx.send(ValueNotification { uuid, value: value.clone() });
+    }
+}
@@ -1,13 +1,15 @@
+use chrono::{DateTime, Duration, Utc};
 use std::collections::HashMap;
-use std::error::Error;
 use csv::ReaderBuilder;
 use regex::Regex;
 use sha2::{Sha256, Digest};
-use hex;
 
 use serde::{Deserialize, Serialize};
-use serde_yaml;
+use uuid::Uuid;
 use std::fs;
+use std::sync::LazyLock;
+
+use crate::error::{BluetrackerError, Result};
 
 #[derive(Debug, Deserialize, Serialize)]
 struct CompanyIdentifier {
@@ -20,7 +22,7 @@ struct YamlRoot {
     company_identifiers: Vec<CompanyIdentifier>,
 }
 
-pub fn load_manufacturer_map_from_yaml(yaml_path: &str) -> Result<HashMap<String, String>, Box<dyn Error>> {
+pub fn load_manufacturer_map_from_yaml(yaml_path: &str) -> Result<HashMap<String, String>> {
     let yaml_content = fs::read_to_string(yaml_path)?;
     let parsed: YamlRoot = serde_yaml::from_str(&yaml_content)?;
     
@@ -33,14 +35,14 @@ pub fn load_manufacturer_map_from_yaml(yaml_path: &str) -> Result<HashMap<String
     Ok(manufacturer_map)
 }
 
-pub fn load_manufacturer_map_from_csv(csv_path: &str) -> Result<HashMap<String, String>, Box<dyn Error>> {
+pub fn load_manufacturer_map_from_csv(csv_path: &str) -> Result<HashMap<String, String>> {
     let mut rdr = ReaderBuilder::new().has_headers(true).from_path(csv_path)?;
     let mut manufacturer_map = HashMap::new();
 
     for result in rdr.records() {
         let record = result?;
-        let manufacturer_id = record.get(0).ok_or("Missing manufacturer ID")?.to_string(); // First column is ID
-        let manufacturer_name = record.get(1).ok_or("Missing manufacturer name")?.to_string(); // Second column is name
+        let manufacturer_id = record.get(0).ok_or_else(|| BluetrackerError::Parse("Missing manufacturer ID".into()))?.to_string(); // First column is ID
+        let manufacturer_name = record.get(1).ok_or_else(|| BluetrackerError::Parse("Missing manufacturer name".into()))?.to_string(); // Second column is name
         manufacturer_map.insert(manufacturer_id, manufacturer_name);
     }
 
@@ -49,22 +51,61 @@ pub fn load_manufacturer_map_from_csv(csv_path: &str) -> Result<HashMap<String,
 
 
 
-pub fn get_manufacturer_id(manufacturer_data: &str) -> Option<u16> {
-    if manufacturer_data.is_empty() {
-        return None;
-    }
+/// Company identifier used to classify a device. Adverts can carry several
+/// vendors' data, so the lowest ID is picked to keep the choice stable.
+pub fn get_manufacturer_id(manufacturer_data: &HashMap<u16, Vec<u8>>) -> Option<u16> {
+    manufacturer_data.keys().min().copied()
+}
 
-    // Regular expression to match the key-value pair structure: "{key: [value1, value2, ...]}"
-    let re = Regex::new(r"\{(\d+): \[(.*?)\]\}").unwrap();
+/// Parses manufacturer data previously stored as a `{:?}` formatted
+/// `HashMap<u16, Vec<u8>>`, e.g. `{76: [2, 21], 6: [1]}`.
+pub fn parse_manufacturer_data_debug(manufacturer_data: &str) -> Option<HashMap<u16, Vec<u8>>> {
+    let trimmed = manufacturer_data.trim();
+    if trimmed.is_empty() {
+        return Some(HashMap::new());
+    }
+    let inner = trimmed.strip_prefix('{')?.strip_suffix('}')?;
 
-    if let Some(captures) = re.captures(manufacturer_data) {
-        if let Some(manufacturer_id_str) = captures.get(1) {
-            if let Ok(manufacturer_id) = manufacturer_id_str.as_str().parse::<u16>() {
-                return Some(manufacturer_id);
-            }
+    // Migrations call this once per legacy row, so the pattern is compiled once.
+    static ENTRY: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(\d+):\s*\[([^\]]*)\]").unwrap());
+    let mut parsed = HashMap::new();
+    let mut end = 0;
+    for captures in ENTRY.captures_iter(inner) {
+        // Entries have to follow each other with only a comma in between, so
+        // nothing outside them is silently dropped.
+        let span = captures.get(0)?;
+        let separator = inner[end..span.start()].trim();
+        if separator != if end == 0 { "" } else { "," } {
+            return None;
         }
+        end = span.end();
+
+        let company_id = captures[1].parse::<u16>().ok()?;
+        let payload = captures[2]
+            .split(',')
+            .map(str::trim)
+            .filter(|byte| !byte.is_empty())
+            .map(|byte| byte.parse::<u8>().ok())
+            .collect::<Option<Vec<u8>>>()?;
+        parsed.insert(company_id, payload);
     }
-    None
+
+    // Anything left over means the string was not in the expected shape.
+    if !inner[end..].trim().is_empty() {
+        return None;
+    }
+    Some(parsed)
+}
+
+/// Formats manufacturer data as `0x004C: 0215..` entries ordered by company ID.
+pub fn format_manufacturer_data(manufacturer_data: &HashMap<u16, Vec<u8>>) -> String {
+    let mut entries: Vec<_> = manufacturer_data.iter().collect();
+    entries.sort_by_key(|(company_id, _)| **company_id);
+    entries
+        .into_iter()
+        .map(|(company_id, payload)| format!("{}: {}", u16_to_hex(*company_id), hex::encode(payload)))
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 pub fn get_manufacturer_name(id: &u16, manufacturer_map: &HashMap<String, String>) -> Option<String> {
@@ -72,6 +113,22 @@ pub fn get_manufacturer_name(id: &u16, manufacturer_map: &HashMap<String, String
     manufacturer_map.get(&manufacturer_id_hex).cloned()  // Return the name if found, otherwise None
 }
 
+/// Formats service data as `<uuid>: <hex payload>` entries ordered by UUID.
+pub fn format_service_data(service_data: &HashMap<Uuid, Vec<u8>>) -> String {
+    let mut entries: Vec<_> = service_data.iter().collect();
+    entries.sort_by_key(|(uuid, _)| **uuid);
+    entries
+        .into_iter()
+        .map(|(uuid, payload)| format!("{}: {}", uuid, hex::encode(payload)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Formats an optional reading, showing `n/a` when the device did not report it.
+pub fn format_optional<T: std::fmt::Display>(value: Option<T>) -> String {
+    value.map_or_else(|| "n/a".to_string(), |value| value.to_string())
+}
+
 fn u16_to_hex(value: u16) -> String {
     format!("0x{:04X}", value)
 }
@@ -94,9 +151,123 @@ pub fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
 
 
 
+/// Parses a duration such as `90s`, `15m`, `12h`, `30d` or `2w`. `None` when
+/// the value is malformed or too large to represent.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit())?;
+    let amount: i64 = value[..split].parse().ok()?;
+    match &value[split..] {
+        "s" => Duration::try_seconds(amount),
+        "m" => Duration::try_minutes(amount),
+        "h" => Duration::try_hours(amount),
+        "d" => Duration::try_days(amount),
+        "w" => Duration::try_weeks(amount),
+        _ => None,
+    }
+}
+
+/// [`parse_duration`] for command-line arguments.
+pub fn parse_duration_arg(value: &str) -> std::result::Result<Duration, String> {
+    parse_duration(value).ok_or_else(|| format!("invalid duration '{}': expected e.g. 60s, 15m or 2h", value))
+}
+
+/// Parses a point in time given either as an RFC3339 timestamp or as a
+/// duration back from now (e.g. `24h`).
+pub fn parse_since(value: &str) -> std::result::Result<DateTime<Utc>, String> {
+    if let Some(duration) = parse_duration(value) {
+        return Ok(Utc::now().checked_sub_signed(duration).unwrap_or(DateTime::<Utc>::MIN_UTC));
+    }
+    DateTime::parse_from_rfc3339(value)
+        .map(|dt| dt.with_timezone(&Utc))
+        .map_err(|_| format!("invalid time '{}': expected RFC3339 or a duration like 24h", value))
+}
+
+/// Parses a service UUID given in full or as a 16- or 32-bit short form such
+/// as `180f`, which is expanded with the Bluetooth base UUID.
+pub fn parse_service_uuid(value: &str) -> std::result::Result<Uuid, String> {
+    let value = value.trim().trim_start_matches("0x");
+    if (value.len() == 4 || value.len() == 8) && value.chars().all(|c| c.is_ascii_hexdigit()) {
+        let short = u32::from_str_radix(value, 16).map_err(|e| e.to_string())?;
+        return Ok(Uuid::from_u128(((short as u128) << 96) | 0x0000_0000_0000_1000_8000_0080_5f9b_34fb));
+    }
+    Uuid::parse_str(value).map_err(|_| format!("invalid service UUID '{}'", value))
+}
+
+/// Name of the machine, as recorded with each scan session.
+pub fn hostname() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
 /// Hashes a string (e.g., device address) using SHA-256.
 pub fn hash_data(data: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(data);
     hex::encode(hasher.finalize()) // Convert to hex string
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_manufacturer_data_debug_strings() {
+        let parsed = parse_manufacturer_data_debug("{76: [2, 21, 255], 6: [1]}").unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[&76], vec![2, 21, 255]);
+        assert_eq!(parsed[&6], vec![1]);
+        assert_eq!(parse_manufacturer_data_debug("{89: []}").unwrap()[&89], Vec::<u8>::new());
+        assert!(parse_manufacturer_data_debug("{}").unwrap().is_empty());
+        assert!(parse_manufacturer_data_debug("  ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_manufacturer_data() {
+        assert_eq!(parse_manufacturer_data_debug("76: [2, 21]"), None);
+        assert_eq!(parse_manufacturer_data_debug("{76: [2, 256]}"), None);
+        assert_eq!(parse_manufacturer_data_debug("{70000: [1]}"), None);
+        assert_eq!(parse_manufacturer_data_debug("{garbage}"), None);
+        assert_eq!(parse_manufacturer_data_debug("{76: [2, 21], garbage}"), None);
+        assert_eq!(parse_manufacturer_data_debug("{garbage, 76: [2, 21]}"), None);
+        assert_eq!(parse_manufacturer_data_debug("{76: [2, 21] 6: [1]}"), None);
+        assert_eq!(parse_manufacturer_data_debug("{76: [2, 21],, 6: [1]}"), None);
+        assert_eq!(parse_manufacturer_data_debug("{-76: [2, 21]}"), None);
+    }
+
+    #[test]
+    fn parses_service_uuids() {
+        let battery = Uuid::parse_str("0000180f-0000-1000-8000-00805f9b34fb").unwrap();
+        assert_eq!(parse_service_uuid("180f"), Ok(battery));
+        assert_eq!(parse_service_uuid("0x180F"), Ok(battery));
+        assert_eq!(parse_service_uuid("0000180f"), Ok(battery));
+        assert_eq!(parse_service_uuid(" 0000180f-0000-1000-8000-00805f9b34fb "), Ok(battery));
+        assert_eq!(
+            parse_service_uuid("12345678"),
+            Ok(Uuid::parse_str("12345678-0000-1000-8000-00805f9b34fb").unwrap())
+        );
+        assert!(parse_service_uuid("180").is_err());
+        assert!(parse_service_uuid("zzzz").is_err());
+    }
+
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(parse_duration("90s"), Some(Duration::seconds(90)));
+        assert_eq!(parse_duration(" 15m "), Some(Duration::minutes(15)));
+        assert_eq!(parse_duration("2w"), Some(Duration::days(14)));
+        assert_eq!(parse_duration("15"), None);
+        assert_eq!(parse_duration("3y"), None);
+    }
+
+    #[test]
+    fn parse_duration_rejects_overflow() {
+        assert_eq!(parse_duration("99999999999999w"), None);
+        assert_eq!(parse_duration("99999999999999999999s"), None);
+        assert!(parse_duration_arg("99999999999999w").is_err());
+        assert!(parse_since("9999999999w").is_ok());
+    }
+}
@@ -0,0 +1,25 @@
+//! Fixtures shared by the integration tests.
+
+use std::path::PathBuf;
+
+/// A database file in the temp directory, removed when dropped.
+pub struct TempDb(PathBuf);
+
+impl TempDb {
+    /// A fresh path unique to `name` and this process, so parallel tests do not collide.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("bluetracker-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+
+    pub fn path(&self) -> String {
+        self.0.to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use bluetracker::backend::BluetoothBackend;
+use bluetracker::connect::{connect_to_device, ConnectOptions};
+use bluetracker::simulator::{SimScript, SimulatedBackend};
+use bluetracker::BluetrackerError;
+use btleplug::api::ScanFilter;
+
+const SCRIPT: &str = r#"
+devices:
+  - address: "AA:BB:CC:DD:EE:01"
+    name: Slow Phone
+    connect_delay_ms: 60000
+  - address: "AA:BB:CC:DD:EE:02"
+    name: Far Away
+    appear_after_ms: 60000
+"#;
+
+fn options() -> ConnectOptions {
+    ConnectOptions { timeout: Some(Duration::from_millis(200)), ..Default::default() }
+}
+
+#[tokio::test]
+async fn timed_out_connection_is_cleaned_up() {
+    let script: SimScript = serde_yaml::from_str(SCRIPT).unwrap();
+    let backend = SimulatedBackend::from_script(script).unwrap();
+
+    // The device connects but the confirmation never arrives in time.
+    let result = connect_to_device(&backend, "AA:BB:CC:DD:EE:01", &options()).await;
+    assert!(matches!(result, Err(BluetrackerError::TimedOut { .. })));
+    let adapter = backend.adapters().await.unwrap().remove(0);
+    let device = adapter.peripheral("AA:BB:CC:DD:EE:01").await.unwrap().unwrap();
+    assert!(device.disconnect().await.is_err(), "the timed out connection was left up");
+
+    // The device is already known, so a scan someone else started is left alone.
+    adapter.start_scan(ScanFilter::default()).await.unwrap();
+    let result = connect_to_device(&backend, "AA:BB:CC:DD:EE:01", &options()).await;
+    assert!(matches!(result, Err(BluetrackerError::TimedOut { .. })));
+    assert!(adapter.start_scan(ScanFilter::default()).await.is_err(), "a scan it did not start was stopped");
+    adapter.stop_scan().await.unwrap();
+
+    // The device is never seen, so the scan for it is stopped.
+    let result = connect_to_device(&backend, "AA:BB:CC:DD:EE:02", &options()).await;
+    assert!(matches!(result, Err(BluetrackerError::TimedOut { .. })));
+    adapter.start_scan(ScanFilter::default()).await.expect("the scan was left running");
+}
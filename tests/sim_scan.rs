@@ -0,0 +1,159 @@
+use std::time::Duration;
+
+use bluetracker::backend::{AdapterEvent, BluetoothBackend, EventStream};
+use bluetracker::db::{BluetoothTracker, DeviceQuery, FilterOptions};
+use bluetracker::scan::{scan_devices, ScanOptions};
+use bluetracker::simulator::{SimScript, SimulatedBackend};
+use btleplug::api::ScanFilter;
+use futures::StreamExt;
+
+mod common;
+
+use common::TempDb;
+
+const SCRIPT: &str = r#"
+devices:
+  - address: "AA:BB:CC:DD:EE:01"
+    name: Sim Phone
+    rssi: [-60]
+    manufacturer_data: { 76: [2, 21] }
+    services: ["0000180f-0000-1000-8000-00805f9b34fb"]
+  - address: "AA:BB:CC:DD:EE:02"
+    name: Tracker Tag
+    rssi: [-85]
+  - address: "AA:BB:CC:DD:EE:03"
+    name: Late Arrival
+    appear_after_ms: 60000
+  - address: "AA:BB:CC:DD:EE:04"
+    name: Early Leaver
+    leave_after_ms: 0
+  - address: "AA:BB:CC:DD:EE:05"
+    name: Broken
+    properties_error: radio glitch
+"#;
+
+#[tokio::test]
+async fn simulated_scan_is_stored_and_queried_back() {
+    let db = TempDb::new("sim-scan");
+    let script: SimScript = serde_yaml::from_str(SCRIPT).unwrap();
+    let backend = SimulatedBackend::from_script(script).unwrap();
+    let options = ScanOptions {
+        use_db: true,
+        db_path: Some(db.path()),
+        latitude: Some(51.5),
+        longitude: Some(-0.12),
+        duration: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+
+    let found = scan_devices(&backend, options).await.unwrap();
+    let mut addresses: Vec<&str> = found.iter().map(|device| device.address.as_str()).collect();
+    addresses.sort();
+    // Not yet appeared, already gone and unreadable devices are left out.
+    assert_eq!(addresses, ["AA:BB:CC:DD:EE:01", "AA:BB:CC:DD:EE:02"]);
+
+    let mut tracker = BluetoothTracker::new(&db.path()).unwrap();
+    let devices = tracker.get_devices(&DeviceQuery::default()).unwrap();
+    assert_eq!(devices.len(), 2);
+    let phone = devices.iter().find(|device| device.address == "AA:BB:CC:DD:EE:01").unwrap();
+    assert_eq!(phone.name, "Sim Phone");
+    assert_eq!(phone.detection_count, 1);
+    assert_eq!(phone.strongest_rssi, Some(-60));
+
+    let history = tracker.get_device_history("AA:BB:CC:DD:EE:02", FilterOptions { start_time: None, end_time: None, limit: None }).unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].rssi, Some(-85));
+    assert_eq!(history[0].latitude, Some(51.5));
+}
+
+#[tokio::test]
+async fn failing_adapter_does_not_stop_the_others() {
+    let script = |second_error: &str| -> SimScript {
+        serde_yaml::from_str(&format!(
+            r#"
+adapters:
+  - name: sim0
+    scan_error: adapter unplugged
+  - name: sim1
+    {}
+devices:
+  - address: "AA:BB:CC:DD:EE:01"
+    rssi: [-60]
+"#,
+            second_error
+        ))
+        .unwrap()
+    };
+    let db = TempDb::new("sim-failing-adapter");
+    let options = ScanOptions {
+        all_adapters: true,
+        use_db: true,
+        db_path: Some(db.path()),
+        duration: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+
+    let backend = SimulatedBackend::from_script(script("")).unwrap();
+    let found = scan_devices(&backend, options.clone()).await.unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].adapter.as_deref(), Some("sim1"));
+
+    let backend = SimulatedBackend::from_script(script("scan_error: adapter unplugged too")).unwrap();
+    assert!(scan_devices(&backend, options).await.is_err());
+    // Only the scan that worked leaves a session behind.
+    let sessions = BluetoothTracker::new(&db.path()).unwrap().get_scan_sessions(None).unwrap();
+    assert_eq!(sessions.len(), 1);
+}
+
+/// The next event within 100 ms.
+async fn next(events: &mut EventStream) -> Option<AdapterEvent> {
+    tokio::time::timeout(Duration::from_millis(100), events.next()).await.ok().flatten()
+}
+
+#[tokio::test]
+async fn event_stream_follows_the_scan_like_polling() {
+
+    let script: SimScript = serde_yaml::from_str(
+        r#"
+adapters:
+  - name: sim0
+    rssi_offset: -8
+  - name: sim1
+    scan_error: adapter unplugged
+devices:
+  - address: "AA:BB:CC:DD:EE:01"
+    rssi: [-60]
+    services: ["0000180f-0000-1000-8000-00805f9b34fb"]
+    update_interval_ms: 20
+  - address: "AA:BB:CC:DD:EE:02"
+    rssi: [-70]
+"#,
+    )
+    .unwrap();
+    let backend = SimulatedBackend::from_script(script).unwrap();
+    let mut adapters = backend.adapters().await.unwrap();
+
+    // Nothing is heard before the scan starts, nor on an adapter that cannot scan.
+    let failing = adapters.remove(1);
+    let mut events = failing.events().await.unwrap();
+    assert!(failing.start_scan(ScanFilter::default()).await.is_err());
+    assert_eq!(next(&mut events).await, None);
+
+    let adapter = adapters.remove(0);
+    let mut events = adapter.events().await.unwrap();
+    assert_eq!(next(&mut events).await, None);
+
+    // Only the device with the filtered service is reported, with the adapter's offset.
+    let filter = ScanFilter { services: vec!["0000180f-0000-1000-8000-00805f9b34fb".parse().unwrap()] };
+    adapter.start_scan(filter).await.unwrap();
+    assert_eq!(next(&mut events).await, Some(AdapterEvent::DeviceDiscovered("AA:BB:CC:DD:EE:01".to_string())));
+    assert_eq!(next(&mut events).await, Some(AdapterEvent::DeviceUpdated("AA:BB:CC:DD:EE:01".to_string())));
+    let device = adapter.peripheral("AA:BB:CC:DD:EE:01").await.unwrap().unwrap();
+    assert_eq!(device.properties().await.unwrap().unwrap().rssi, Some(-68));
+
+    // And nothing once it stops.
+    adapter.stop_scan().await.unwrap();
+    while next(&mut events).await.is_some() {}
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(next(&mut events).await, None);
+}